@@ -0,0 +1,194 @@
+; Verify 65C02 decimal mode ADC/SBC against every operand pair and both carry values
+; Based on the test by Bruce Clark (6502.org, public domain)
+; On the 65C02 N and Z are valid in decimal mode, see bcd.asm for the NMOS version
+;
+; Result in $00 : 0 if the test passed, 1 if it failed
+; The first pixel of the screen turns green on success and red on failure
+;
+; Zero page variables
+;   $00 ERROR   $10 N1    $11 N1H   $12 N1L   $13 N2    $14 N2L
+;   $15 N2H (2 bytes : N2 & $F0 and (N2 & $F0) + $0F)
+;   $17 DA      $18 DNVZC (actual decimal mode result and flags)
+;   $19 HA      $1a HNVZC (result and flags using binary arithmetic)
+;   $1b AR      $1c NF    $1d VF    $1e ZF    $1f CF (predicted results)
+
+      .cpu 65c02
+      jsr test
+      lda #$05      ; green
+      ldx $00
+      beq show
+      lda #$02      ; red
+show: sta $0200
+      brk
+
+test: ldy #$01      ; Y loops through both carry values
+      sty $00       ; ERROR = 1 until the test passes
+      lda #$00
+      sta $10       ; N1 = 0
+      sta $13       ; N2 = 0
+loop1:
+      lda $13       ; N2L = N2 & $0F
+      and #$0f
+      sta $14
+      lda $13       ; N2H = N2 & $F0
+      and #$f0
+      sta $15
+      ora #$0f      ; N2H+1 = (N2 & $F0) + $0F
+      sta $16
+loop2:
+      lda $10       ; N1L = N1 & $0F
+      and #$0f
+      sta $12
+      lda $10       ; N1H = N1 & $F0
+      and #$f0
+      sta $11
+      jsr add
+      jsr a65c02
+      jsr compare
+      bne done
+      jsr sub
+      jsr s65c02
+      jsr compare
+      bne done
+      inc $10       ; all 256 values of N1
+      bne loop2
+      inc $13       ; all 256 values of N2
+      bne loop1
+      dey           ; both values of the carry flag
+      bpl loop1
+      lda #$00      ; passed
+      sta $00
+done: rts
+
+; Actual decimal mode and binary results of N1 + N2, then the predicted
+; accumulator, carry and V flag
+add:  sed
+      cpy #$01      ; carry = Y
+      lda $10
+      adc $13
+      sta $17       ; DA
+      php
+      pla
+      sta $18       ; DNVZC
+      cld
+      cpy #$01
+      lda $10
+      adc $13
+      sta $19       ; HA
+      php
+      pla
+      sta $1a       ; HNVZC
+      cpy #$01
+      lda $12
+      adc $14
+      cmp #$0a
+      ldx #$00
+      bcc add_low
+      inx
+      adc #$05      ; add 6 (carry is set)
+      and #$0f
+      sec
+add_low:
+      ora $11
+      adc $15,x     ; add N2 & $F0, or (N2 & $F0) + $10 if the low digit carried
+      php
+      bcs add_adjust
+      cmp #$a0
+      bcc add_done
+add_adjust:
+      adc #$5f      ; add $60 (carry is set)
+      sec
+add_done:
+      sta $1b       ; AR
+      php
+      pla
+      sta $1f       ; CF
+      pla
+      sta $1d       ; VF, all 8 bits of P
+      rts
+
+; Actual decimal mode and binary results of N1 - N2
+sub:  sed
+      cpy #$01
+      lda $10
+      sbc $13
+      sta $17       ; DA
+      php
+      pla
+      sta $18       ; DNVZC
+      cld
+      cpy #$01
+      lda $10
+      sbc $13
+      sta $19       ; HA
+      php
+      pla
+      sta $1a       ; HNVZC
+      rts
+
+; Predicted SBC accumulator result, the 65C02 adjusts the high digit first
+sub2: cpy #$01
+      lda $12
+      sbc $14
+      ldx #$00
+      bcs sub2_low
+      inx
+      and #$0f
+      clc
+sub2_low:
+      ora $11
+      sbc $15,x     ; subtract N2 & $F0, or (N2 & $F0) + $10 if the low digit borrowed
+      bcs sub2_high
+      sbc #$5f      ; subtract $60 (carry is clear)
+sub2_high:
+      cpx #$00
+      beq sub2_done
+      sbc #$06      ; subtract 6 if the low digit borrowed
+sub2_done:
+      sta $1b       ; AR
+      rts
+
+; Z = 1 if the actual results match the predicted ones
+compare:
+      lda $17
+      cmp $1b
+      bne compare_done
+      lda $18
+      eor $1c
+      and #$80      ; N
+      bne compare_done
+      lda $18
+      eor $1d
+      and #$40      ; V
+      bne compare_done
+      lda $18
+      eor $1e
+      and #$02      ; Z
+      bne compare_done
+      lda $18
+      eor $1f
+      and #$01      ; C
+compare_done:
+      rts
+
+; 65C02 predictions, N and Z come from the accumulator result
+a65c02:
+      lda $1b
+      php
+      pla
+      sta $1c       ; NF
+      sta $1e       ; ZF
+      rts
+
+; 65C02 predictions, V and C are the same as in binary mode
+s65c02:
+      jsr sub2
+      lda $1b
+      php
+      pla
+      sta $1c       ; NF
+      sta $1e       ; ZF
+      lda $1a
+      sta $1d       ; VF
+      sta $1f       ; CF
+      rts
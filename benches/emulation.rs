@@ -0,0 +1,137 @@
+// Emulated clock speed of the sample programs, run with `cargo bench`
+//
+// Each program in asm-examples is assembled, loaded at its origin and run until BRK or
+// for CYCLES cycles, with the interpreter (Cpu::step) and the decoded core (Cpu::run),
+// on 64 KiB of RAM and on a MemoryMap like the one of e6502
+
+use e6502::bus::{Bus, MemoryMap, Ram};
+use e6502::cpu::{Cpu, CpuError, StepResult};
+use e6502::lexer;
+use std::time::{Duration, Instant};
+
+/// Programs that never end (games waiting for input) are stopped after this many cycles
+const CYCLES: u64 = 100_000_000;
+
+/// Each measure is repeated and the fastest run is kept
+const RUNS: usize = 3;
+
+/// Execution core being measured
+#[derive(Clone, Copy)]
+enum Core
+{
+    Step,
+    Run,
+}
+
+/// Run the program from its first byte, returns the number of cycles, the time taken and the error that stopped it
+fn measure<B: Bus>(mut cpu: Cpu<B>, core: Core) -> (u64, Duration, Option<CpuError>)
+{
+    cpu.sp = 0xff;
+
+    let start = Instant::now();
+    let result = match core
+    {
+        Core::Step =>
+        {
+            let mut result = Ok(StepResult::Executed { cycles: 0 });
+            while cpu.cycles < CYCLES && matches!(result, Ok(StepResult::Executed { .. }))
+            {
+                result = cpu.step();
+            }
+            result
+        },
+        Core::Run => cpu.run(CYCLES),
+    };
+
+    (cpu.cycles, start.elapsed(), result.err())
+}
+
+/// Best speed of `RUNS` runs in MHz
+fn speed<B: Bus>(new_cpu: impl Fn() -> Cpu<B>, core: Core) -> Result<f64, CpuError>
+{
+    let mut best = 0.0f64;
+
+    for _ in 0..RUNS
+    {
+        let (cycles, elapsed, error) = measure(new_cpu(), core);
+        if let Some(e) = error
+        {
+            return Err(e);
+        }
+
+        best = best.max(cycles as f64 / elapsed.as_secs_f64() / 1e6);
+    }
+
+    Ok(best)
+}
+
+fn report<B: Bus>(name: &str, bus: &str, new_cpu: impl Fn() -> Cpu<B>)
+{
+    let text = |result: Result<f64, CpuError>| match result
+    {
+        Ok(mhz) => format!("{:>8.1} MHz", mhz),
+        Err(e) => e.to_string(),
+    };
+
+    let step = text(speed(&new_cpu, Core::Step));
+    let run = text(speed(&new_cpu, Core::Run));
+
+    println!("{:<16} {:<6} {:>14} {:>14}", name, bus, step, run);
+}
+
+fn main()
+{
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/asm-examples");
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .expect("asm-examples not found")
+        .map(|entry| entry.expect("Unreadable directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "asm"))
+        .collect();
+    paths.sort();
+
+    println!("{:<16} {:<6} {:>14} {:>14}", "program", "bus", "step", "run");
+
+    for path in paths
+    {
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let source = std::fs::read_to_string(&path).expect("Unreadable program");
+
+        let mut lexer = lexer::Lexer { file: name.clone(), ..Default::default() };
+        lexer.tokenize(source);
+        let code = match lexer.parse()
+        {
+            Ok(code) => code,
+            Err(diagnostics) =>
+            {
+                println!("{:<16} {} error(s), {}", name, diagnostics.len(), diagnostics[0]);
+                continue;
+            },
+        };
+        let variant = lexer.cpu;
+        let origin = lexer.origin;
+        let start = usize::from(origin);
+
+        report(&name, "ram", ||
+        {
+            let mut ram = Ram::default();
+            ram.mem[start..start + code.len()].copy_from_slice(&code);
+
+            let mut cpu = Cpu::new(ram);
+            cpu.variant = variant;
+            cpu.pc = origin;
+            cpu
+        });
+
+        report(&name, "map", ||
+        {
+            let mut map = MemoryMap::machine(origin);
+            map.ram(origin, (start + code.len() - 1) as u16);
+            map.load(origin, &code);
+
+            let mut cpu = Cpu::new(map);
+            cpu.variant = variant;
+            cpu.pc = origin;
+            cpu
+        });
+    }
+}
@@ -0,0 +1,572 @@
+use crate::state::{Reader, StateError};
+
+/// Memory bus seen by the cpu
+///
+/// Every memory access of the cpu goes through a Bus, so memory-mapped devices
+/// (random number ports, keyboard latches, timers...) can react to reads and writes
+pub trait Bus
+{
+    /// Read the byte at `address`, devices may change state on reads (e.g clear a latch)
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Write `value` to `address`
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Read the byte at `address` without side effects, used to display memory
+    fn peek(&self, address: u16) -> u8;
+
+    /// Write `value` to `address` without side effects, used to undo writes when rewinding
+    fn poke(&mut self, address: u16, value: u8);
+
+    /// Whether the byte at `address` only changes when the cpu writes to `address`, so
+    /// an instruction stored there can be decoded once and kept until it is overwritten
+    ///
+    /// Devices, mirrors and bank switched windows must return false
+    fn cacheable(&self, _address: u16) -> bool
+    {
+        true
+    }
+
+    /// Whether a write to `address` changes the state of a device (a bank register, a latch...),
+    /// the history then keeps the state of the devices to undo the write
+    fn is_device_register(&self, _address: u16) -> bool
+    {
+        false
+    }
+
+    /// Append the state of the devices without the contents of memory
+    fn save_devices(&self, _out: &mut Vec<u8>) {}
+
+    /// Restore what `save_devices` appended
+    fn load_devices(&mut self, _input: &mut Reader) -> Result<(), StateError>
+    {
+        Ok(())
+    }
+
+    /// Append the contents of memory and the state of the devices to a save state
+    fn save_state(&self, out: &mut Vec<u8>);
+
+    /// Read what `save_state` appended and check that it fits this bus, without changing anything
+    fn check_state(&self, input: &mut Reader) -> Result<(), StateError>;
+
+    /// Restore what `save_state` appended, nothing is changed when it returns an error
+    fn load_state(&mut self, input: &mut Reader) -> Result<(), StateError>;
+}
+
+/// 64 KiB of RAM and no devices
+pub struct Ram
+{
+    pub mem: [u8;65536],
+}
+
+impl Default for Ram
+{
+    fn default() -> Ram
+    {
+        Ram
+        {
+            mem: [0;1<<16],
+        }
+    }
+}
+
+impl Bus for Ram
+{
+    #[inline(always)]
+    fn read(&mut self, address: u16) -> u8
+    {
+        self.mem[usize::from(address)]
+    }
+
+    #[inline(always)]
+    fn write(&mut self, address: u16, value: u8)
+    {
+        self.mem[usize::from(address)] = value;
+    }
+
+    #[inline(always)]
+    fn peek(&self, address: u16) -> u8
+    {
+        self.mem[usize::from(address)]
+    }
+
+    fn poke(&mut self, address: u16, value: u8)
+    {
+        self.mem[usize::from(address)] = value;
+    }
+
+    fn save_state(&self, out: &mut Vec<u8>)
+    {
+        out.extend_from_slice(&self.mem);
+    }
+
+    fn check_state(&self, input: &mut Reader) -> Result<(), StateError>
+    {
+        input.bytes(self.mem.len())?;
+
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut Reader) -> Result<(), StateError>
+    {
+        let mem = input.bytes(self.mem.len())?;
+        self.mem.copy_from_slice(mem);
+
+        Ok(())
+    }
+}
+
+/// What answers accesses to a region of a MemoryMap
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RegionKind
+{
+    /// Readable and writable
+    Ram,
+    /// Read only, writes are ignored
+    Rom,
+    /// Accesses are redirected to `base + (address - start) % size`
+    Mirror { base: u16, size: u16 },
+    /// Nothing answers, reads return the open bus value
+    Unmapped,
+}
+
+/// Inclusive address range `start..=end` of a MemoryMap
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Region
+{
+    pub start: u16,
+    pub end: u16,
+    pub kind: RegionKind,
+}
+
+/// Memory described as a list of regions
+///
+/// Regions added later take precedence over earlier ones, so a map can start
+/// with RAM everywhere and place ROM and holes on top of it. Addresses not
+/// covered by any region are unmapped
+///
+/// ```ignore
+/// let mut map = MemoryMap::default();
+/// map.ram(0x0000, 0x07ff);
+/// map.mirror(0x0800, 0x1fff, 0x0000, 0x0800);
+/// map.rom(0xc000, &kernal);
+/// map.on_warning(|message| eprintln!("{}", message));
+/// ```
+pub struct MemoryMap
+{
+    /// Backing storage of RAM and ROM regions
+    pub mem: [u8;65536],
+    regions: Vec<Region>,
+    /// Last value seen on the data bus, returned by unmapped reads
+    open_bus: u8,
+    /// Called on writes to ROM and accesses to unmapped space, see on_warning
+    warn: Option<Box<dyn FnMut(String)>>,
+}
+
+impl Default for MemoryMap
+{
+    fn default() -> MemoryMap
+    {
+        MemoryMap
+        {
+            mem: [0;1<<16],
+            regions: Vec::new(),
+            open_bus: 0,
+            warn: None,
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl MemoryMap
+{
+    /// Start of the space left to cartridges, see `machine`
+    pub const CARTRIDGE_START: u16 = 0x8000;
+
+    /// Memory of the e6502 machine before a program is loaded
+    ///
+    /// 32 KiB of RAM at $0000-$7fff (zero page, stack, the screen at $0200 and programs
+    /// at $0600), nothing at $8000-$fff9 where cartridges are mapped and the vectors
+    /// in ROM, the reset vector points at `entry` and the NMI and IRQ vectors at $0000
+    pub fn machine(entry: u16) -> MemoryMap
+    {
+        let mut map = MemoryMap::default();
+        map.ram(0x0000, MemoryMap::CARTRIDGE_START - 1);
+        map.unmapped(MemoryMap::CARTRIDGE_START, 0xffff);
+
+        let mut vectors = [0; 6];
+        vectors[2..4].copy_from_slice(&entry.to_le_bytes());
+        map.rom(0xfffa, &vectors);
+
+        map
+    }
+
+    /// Report writes to ROM and accesses to unmapped space to `warn`, nothing is reported by default
+    pub fn on_warning(&mut self, warn: impl FnMut(String) + 'static)
+    {
+        self.warn = Some(Box::new(warn));
+    }
+
+    /// Pass the message built by `message` to the warning callback, if there is one
+    fn warn(&mut self, message: impl FnOnce() -> String)
+    {
+        if let Some(warn) = &mut self.warn
+        {
+            warn(message());
+        }
+    }
+
+    /// Add a region, it hides any region it overlaps
+    pub fn map(&mut self, start: u16, end: u16, kind: RegionKind)
+    {
+        assert!(start <= end, "Region ${:04x}-${:04x} ends before it starts", start, end);
+        if let RegionKind::Mirror { size, .. } = kind
+        {
+            assert!(size > 0, "Mirror ${:04x}-${:04x} has a size of 0", start, end);
+        }
+
+        self.regions.push(Region { start, end, kind });
+    }
+
+    pub fn ram(&mut self, start: u16, end: u16)
+    {
+        self.map(start, end, RegionKind::Ram);
+    }
+
+    /// Map `data` as ROM starting at `start`
+    pub fn rom(&mut self, start: u16, data: &[u8])
+    {
+        assert!(!data.is_empty(), "ROM at ${:04x} is empty", start);
+        assert!(usize::from(start) + data.len() <= self.mem.len(), "ROM at ${:04x} is larger than the address space", start);
+
+        self.load(start, data);
+        self.map(start, start + (data.len() - 1) as u16, RegionKind::Rom);
+    }
+
+    /// Make `start..=end` repeat the `size` bytes starting at `base`
+    pub fn mirror(&mut self, start: u16, end: u16, base: u16, size: u16)
+    {
+        self.map(start, end, RegionKind::Mirror { base, size });
+    }
+
+    pub fn unmapped(&mut self, start: u16, end: u16)
+    {
+        self.map(start, end, RegionKind::Unmapped);
+    }
+
+    /// Copy `data` to the backing storage at `start`, ignoring write protection
+    pub fn load(&mut self, start: u16, data: &[u8])
+    {
+        let start = usize::from(start);
+        self.mem[start..start + data.len()].copy_from_slice(data);
+    }
+
+    pub fn regions(&self) -> &[Region]
+    {
+        &self.regions
+    }
+
+    /// Kind of the memory answering at `address`, mirrors are followed to the
+    /// address they point at
+    pub fn resolve(&self, address: u16) -> (u16, RegionKind)
+    {
+        let mut address = address;
+
+        // Bounded so mirrors pointing at each other can't loop forever
+        for _ in 0..=self.regions.len()
+        {
+            let region = self.regions.iter().rev().find(|r| r.start <= address && address <= r.end);
+
+            match region
+            {
+                Some(Region { start, kind: RegionKind::Mirror { base, size }, .. }) =>
+                {
+                    address = base.wrapping_add((address - start) % size);
+                },
+                Some(region) => return (address, region.kind),
+                None => return (address, RegionKind::Unmapped),
+            }
+        }
+
+        (address, RegionKind::Unmapped)
+    }
+}
+
+impl Bus for MemoryMap
+{
+    fn read(&mut self, address: u16) -> u8
+    {
+        match self.resolve(address)
+        {
+            (target, RegionKind::Ram) | (target, RegionKind::Rom) =>
+            {
+                self.open_bus = self.mem[usize::from(target)];
+            },
+            _ => self.warn(|| format!("Read from unmapped address ${:04x}", address)),
+        }
+
+        self.open_bus
+    }
+
+    fn write(&mut self, address: u16, value: u8)
+    {
+        self.open_bus = value;
+
+        match self.resolve(address)
+        {
+            (target, RegionKind::Ram) => self.mem[usize::from(target)] = value,
+            (_, RegionKind::Rom) => self.warn(|| format!("Write of ${:02x} to ROM at ${:04x}", value, address)),
+            _ => self.warn(|| format!("Write of ${:02x} to unmapped address ${:04x}", value, address)),
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8
+    {
+        match self.resolve(address)
+        {
+            (target, RegionKind::Ram) | (target, RegionKind::Rom) => self.mem[usize::from(target)],
+            _ => self.open_bus,
+        }
+    }
+
+    /// ROM can be poked, unmapped addresses are ignored
+    fn poke(&mut self, address: u16, value: u8)
+    {
+        match self.resolve(address)
+        {
+            (target, RegionKind::Ram) | (target, RegionKind::Rom) => self.mem[usize::from(target)] = value,
+            _ => {},
+        }
+    }
+
+    /// RAM and ROM that no mirror points at, unmapped reads return the open bus value instead
+    fn cacheable(&self, address: u16) -> bool
+    {
+        let mirrored = self.regions.iter().any(|r| match r.kind
+        {
+            RegionKind::Mirror { base, size } => address.wrapping_sub(base) < size,
+            _ => false,
+        });
+
+        !mirrored && matches!(self.resolve(address), (target, RegionKind::Ram) | (target, RegionKind::Rom) if target == address)
+    }
+
+    /// The regions are part of the machine, only the memory and the open bus value are saved
+    fn save_state(&self, out: &mut Vec<u8>)
+    {
+        out.extend_from_slice(&self.mem);
+        out.push(self.open_bus);
+    }
+
+    fn check_state(&self, input: &mut Reader) -> Result<(), StateError>
+    {
+        input.bytes(self.mem.len())?;
+        input.u8()?;
+
+        Ok(())
+    }
+
+    fn load_state(&mut self, input: &mut Reader) -> Result<(), StateError>
+    {
+        let mem = input.bytes(self.mem.len())?;
+        let open_bus = input.u8()?;
+
+        self.mem.copy_from_slice(mem);
+        self.open_bus = open_bus;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::cpu::Cpu;
+
+    /// RAM with a key latch at $d000 that is cleared when it is read and a log of the writes
+    #[derive(Default)]
+    struct Keyboard
+    {
+        ram: Ram,
+        key: u8,
+        writes: Vec<(u16, u8)>,
+    }
+
+    impl Bus for Keyboard
+    {
+        fn read(&mut self, address: u16) -> u8
+        {
+            if address == 0xd000
+            {
+                return std::mem::take(&mut self.key);
+            }
+
+            self.ram.read(address)
+        }
+
+        fn write(&mut self, address: u16, value: u8)
+        {
+            self.writes.push((address, value));
+            self.ram.write(address, value);
+        }
+
+        fn peek(&self, address: u16) -> u8
+        {
+            if address == 0xd000 { self.key } else { self.ram.peek(address) }
+        }
+
+        fn poke(&mut self, address: u16, value: u8)
+        {
+            self.ram.poke(address, value);
+        }
+
+        fn save_state(&self, out: &mut Vec<u8>)
+        {
+            self.ram.save_state(out);
+        }
+
+        fn check_state(&self, input: &mut Reader) -> Result<(), StateError>
+        {
+            self.ram.check_state(input)
+        }
+
+        fn load_state(&mut self, input: &mut Reader) -> Result<(), StateError>
+        {
+            self.ram.load_state(input)
+        }
+    }
+
+    #[test]
+    fn ram_reads_back_what_is_written()
+    {
+        let mut ram = Ram::default();
+
+        ram.write(0x1234, 0x56);
+        ram.poke(0xffff, 0x78);
+        assert_eq!(ram.read(0x1234), 0x56);
+        assert_eq!(ram.peek(0xffff), 0x78);
+        assert!(ram.cacheable(0x1234));
+    }
+
+    #[test]
+    fn cpu_accesses_go_through_the_bus()
+    {
+        // LDA $d000, LDX $d000, STA $0200
+        let mut keyboard = Keyboard { key: 0x41, ..Default::default() };
+        keyboard.ram.mem[0x0600..0x0609].copy_from_slice(&[0xad, 0x00, 0xd0, 0xae, 0x00, 0xd0, 0x8d, 0x00, 0x02]);
+
+        let mut cpu = Cpu::new(keyboard);
+        cpu.pc = 0x0600;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x41);
+        assert_eq!(cpu.bus.peek(0xd000), 0x00);
+
+        // The latch was cleared by the first read
+        cpu.step().unwrap();
+        assert_eq!(cpu.x, 0x00);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.writes, [(0x0200, 0x41)]);
+    }
+
+    #[test]
+    fn peek_has_no_side_effects()
+    {
+        let mut keyboard = Keyboard { key: 0x41, ..Default::default() };
+
+        assert_eq!(keyboard.peek(0xd000), 0x41);
+        assert_eq!(keyboard.peek(0xd000), 0x41);
+        assert_eq!(keyboard.read(0xd000), 0x41);
+        assert_eq!(keyboard.peek(0xd000), 0x00);
+    }
+
+    /// Map whose warnings are collected in the returned log
+    fn logged(map: &mut MemoryMap) -> std::rc::Rc<std::cell::RefCell<Vec<String>>>
+    {
+        let log = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = log.clone();
+        map.on_warning(move |message| sink.borrow_mut().push(message));
+        log
+    }
+
+    #[test]
+    fn rom_is_write_protected()
+    {
+        let mut map = MemoryMap::default();
+        map.ram(0x0000, 0xffff);
+        map.rom(0xc000, &[0x11, 0x22]);
+        let log = logged(&mut map);
+
+        map.write(0xc001, 0x99);
+        assert_eq!(map.read(0xc001), 0x22);
+        assert_eq!(*log.borrow(), ["Write of $99 to ROM at $c001"]);
+
+        // Poking ignores the protection, rewinding needs it
+        map.poke(0xc001, 0x33);
+        assert_eq!(map.peek(0xc001), 0x33);
+    }
+
+    #[test]
+    fn mirrors_repeat_their_base()
+    {
+        // 2 KiB of RAM repeated up to $1fff
+        let mut map = MemoryMap::default();
+        map.ram(0x0000, 0x07ff);
+        map.mirror(0x0800, 0x1fff, 0x0000, 0x0800);
+
+        map.write(0x0810, 0x42);
+        assert_eq!(map.read(0x0010), 0x42);
+        assert_eq!(map.read(0x1810), 0x42);
+        assert_eq!(map.resolve(0x1fff), (0x07ff, RegionKind::Ram));
+
+        // Neither the mirror nor the memory it shows can be cached
+        assert!(!map.cacheable(0x0810));
+        assert!(!map.cacheable(0x0010));
+    }
+
+    #[test]
+    fn unmapped_reads_return_the_open_bus_value()
+    {
+        let mut map = MemoryMap::default();
+        map.ram(0x0000, 0x7fff);
+        map.unmapped(0x4000, 0x4fff);
+        let log = logged(&mut map);
+        map.mem[0x0010] = 0x5a;
+
+        assert_eq!(map.read(0x0010), 0x5a);
+        assert_eq!(map.read(0x4000), 0x5a);
+        assert_eq!(map.read(0x9000), 0x5a);
+
+        map.write(0x4000, 0x77);
+        assert_eq!(map.read(0x4000), 0x77);
+        assert_eq!(map.mem[0x4000], 0x00);
+        assert_eq!(log.borrow().len(), 4);
+        assert_eq!(log.borrow()[2], "Write of $77 to unmapped address $4000");
+    }
+
+    #[test]
+    fn warnings_are_off_by_default()
+    {
+        let mut map = MemoryMap::default();
+        map.rom(0xc000, &[0x11]);
+
+        map.write(0xc000, 0x99);
+        assert_eq!(map.read(0x0000), 0x99);
+    }
+
+    #[test]
+    fn machine_map()
+    {
+        let mut map = MemoryMap::machine(0x0600);
+
+        assert_eq!(map.resolve(0x7fff), (0x7fff, RegionKind::Ram));
+        assert_eq!(map.resolve(MemoryMap::CARTRIDGE_START), (0x8000, RegionKind::Unmapped));
+        assert_eq!(map.resolve(0xfffc), (0xfffc, RegionKind::Rom));
+        assert_eq!([map.peek(0xfffc), map.peek(0xfffd)], [0x00, 0x06]);
+
+        map.write(0xfffc, 0x12);
+        assert_eq!(map.read(0xfffc), 0x00);
+    }
+}
@@ -1,43 +1,59 @@
-mod isa;
-mod lexer;
-mod cpu;
+use e6502::lexer;
 
-use std::collections::HashMap;
 // use std::io::Write;
 use std::env;
 use std::fs;
+use std::path::Path;
 
 fn main() {
-    let mut lex = lexer::Lexer 
-    {
-        tokens:Vec::new(),
-        current_token:0,
-        labels:HashMap::new()
-    };
+    let mut lex = lexer::Lexer::default();
 
     // Read file from disk
     let args: Vec<String> = env::args().collect();
 
-    if args.len() < 2
+    if args.len() < 3
     {
-        // TODO(James) : Better arg handling/Errors?
         println!("Example : e6502c source.asm out.bin");
-        panic!();
+        std::process::exit(1);
     }
 
     let source_file_path = &args[1];
     let out_file_path = &args[2];
+    lex.file = source_file_path.clone();
 
     let source = fs::read_to_string(source_file_path).expect("Error occured while reading file");
     // println!("{}",source);
 
     lex.tokenize(source);
     // lex.print_tokens();
-    let out = lex.parse();
+    let out = match lex.parse()
+    {
+        Ok(out) => out,
+        Err(diagnostics) =>
+        {
+            for diagnostic in &diagnostics
+            {
+                eprintln!("{}", diagnostic.render(&lex.source));
+            }
+            eprintln!("{} could not be assembled, {} error(s)", source_file_path, diagnostics.len());
+            std::process::exit(1);
+        },
+    };
     // lex.print_tokens();
     // println!("{:?}", out);
     // lex.source_from_tokens();
 
     fs::write(out_file_path, out).expect("Unknown error while writinng to bin");
 
+    // Banks go to a separate cartridge image next to the program, e.g out.rom
+    if !lex.banks.is_empty()
+    {
+        let image_path = Path::new(out_file_path).with_extension("rom");
+        fs::write(&image_path, lex.bank_image()).expect("Unknown error while writing the bank image");
+    }
+
+    // Labels go to a symbol file for debuggers, e.g out.sym
+    let symbol_path = Path::new(out_file_path).with_extension("sym");
+    fs::write(&symbol_path, lex.symbol_file()).expect("Unknown error while writing the symbol file");
+
 }
@@ -1,20 +1,167 @@
 
 use crate::isa;
+use crate::bus::{Bus, Ram};
+use crate::decode::{DecodeCache, Decoded, Handler};
+use crate::flags::StatusFlags;
+use crate::state::{self, Reader, StateError};
+use std::collections::VecDeque;
+use std::fmt;
+
+/// Execution state of the cpu
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State
+{
+    Running,
+    /// A JAM opcode locked up the cpu, only a reset recovers it
+    Jammed,
+    /// WAI is waiting for an interrupt
+    Waiting,
+    /// STP stopped the cpu until the next reset
+    Stopped,
+}
+
+/// What happened during a call to `Cpu::step`
+///
+/// `cycles` is the number of cycles taken, it is also added to `Cpu::cycles`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StepResult
+{
+    /// An instruction ran or an interrupt was taken
+    Executed { cycles: u8 },
+    /// BRK was executed, `pc` is the address of the BRK opcode
+    Break { pc: u16, cycles: u8 },
+    /// WAI is waiting for an interrupt
+    Waiting { cycles: u8 },
+    /// STP stopped the cpu, only a reset restarts it
+    Stopped { cycles: u8 },
+}
+
+#[allow(dead_code)]
+impl StepResult
+{
+    /// Number of cycles taken by the step
+    pub fn cycles(&self) -> u8
+    {
+        match *self
+        {
+            StepResult::Executed { cycles }
+            | StepResult::Break { cycles, .. }
+            | StepResult::Waiting { cycles }
+            | StepResult::Stopped { cycles } => cycles,
+        }
+    }
+}
+
+/// Why `Cpu::step` could not execute an instruction
+///
+/// pc is left on the offending opcode, stepping again returns the same error
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CpuError
+{
+    /// The opcode is not supported by the selected variant
+    IllegalOpcode { pc: u16, opcode: u8 },
+    /// The cpu executed the JAM opcode at `pc` and is locked up
+    Jammed { pc: u16 },
+}
 
-pub struct Cpu
+impl fmt::Display for CpuError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            CpuError::IllegalOpcode { pc, opcode } => write!(f, "Illegal opcode {:#04x} at {:#06x}", opcode, pc),
+            CpuError::Jammed { pc } => write!(f, "Cpu jammed at {:#06x}", pc),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+pub struct Cpu<B: Bus = Ram>
 {
     pub a: u8,
     pub x: u8,
     pub y: u8,
     pub sp: u8,
     pub pc: u16,
-    pub sr: u8,
-    pub mem: [u8;65536]
+    pub sr: StatusFlags,
+    /// Memory and devices, every read and write of the cpu goes through it
+    pub bus: B,
+    /// Number of cycles executed so far
+    pub cycles: u64,
+    pub state: State,
+    /// Instruction set to emulate, NMOS by default
+    pub variant: isa::Variant,
+    /// Execute the undocumented NMOS opcodes (LAX, SAX, DCP...) instead of
+    /// treating them as unsupported, ignored on the 65C02
+    pub undocumented: bool,
+    // Extra cycles taken by the instruction being executed
+    page_crossed: bool,
+    extra_cycles: u8,
+    // Interrupt lines, IRQ is level triggered and NMI edge triggered
+    irq_line: bool,
+    nmi_line: bool,
+    nmi_pending: bool,
+    // Undo information of the last history_limit instructions, see set_history
+    history: VecDeque<Delta>,
+    history_limit: usize,
+    // Bytes overwritten by the instruction being executed
+    journal: Vec<(u16, u8)>,
+    // State of the devices before the first write of the instruction to a device register
+    devices_journal: Option<Vec<u8>>,
+    // Instructions decoded by run, created by its first call
+    decode_cache: Option<Box<DecodeCache<B>>>,
+    // Operand bytes of the decoded instruction being run, fetched instead of reading the bus
+    prefetched: bool,
+    operand: u16,
 }
 
+/// Cpu state before an instruction and the bytes it overwrote, enough to undo it
+struct Delta
+{
+    a: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    pc: u16,
+    sr: StatusFlags,
+    cycles: u64,
+    state: State,
+    nmi_pending: bool,
+    /// Address and previous value of every byte written, in write order
+    writes: Vec<(u16, u8)>,
+    /// State of the devices written by `Bus::save_devices`, when the instruction wrote to a device register
+    devices: Option<Vec<u8>>,
+}
+
+/// A cpu with 64 KiB of RAM, use Cpu::new to connect another bus
 impl Default for Cpu
 {
     fn default() -> Cpu
+    {
+        Cpu::new(Ram::default())
+    }
+}
+
+// Vectors do not depend on the bus, they live on Cpu<Ram>
+// so they can be named as Cpu::RESET_VECTOR
+#[allow(dead_code)]
+impl Cpu
+{
+    pub const NMI_VECTOR:u16        = 0xfffa;
+    pub const RESET_VECTOR:u16      = 0xfffc;
+    pub const IRQ_VECTOR:u16        = 0xfffe;
+
+    /// Cycles taken to push the state and jump through an interrupt vector
+    const INTERRUPT_CYCLES:u8       = 7;
+}
+
+#[allow(dead_code)]
+impl<B: Bus> Cpu<B>
+{
+    /// Create a cpu connected to `bus`, call reset to start executing
+    pub fn new(bus: B) -> Cpu<B>
     {
         Cpu
         {
@@ -23,279 +170,338 @@ impl Default for Cpu
             y: 0,
             sp: 0,
             pc: 0,
-            sr: 0,
-            mem: [0;1<<16],
+            sr: StatusFlags::default(),
+            bus,
+            cycles: 0,
+            state: State::Running,
+            variant: isa::Variant::Nmos,
+            undocumented: false,
+            page_crossed: false,
+            extra_cycles: 0,
+            irq_line: false,
+            nmi_line: false,
+            nmi_pending: false,
+            history: VecDeque::new(),
+            history_limit: 0,
+            journal: Vec::new(),
+            devices_journal: None,
+            decode_cache: None,
+            prefetched: false,
+            operand: 0,
         }
     }
-}
 
-#[allow(dead_code)]
-#[allow(non_upper_case_globals)]
-impl Cpu
-{
-    const NegFlag:u8                = 0b10000000;
-    const OverFlowFlag:u8           = 0b01000000;
-    const BreakFlag:u8              = 0b00100000;
-    // const UnusedFlag:u8             = 0b00010000;
-    const DecimalFlag:u8            = 0b00001000;
-    const InterruptFlag:u8          = 0b00000100;
-    const ZeroFlag:u8               = 0b00000010;
-    const CarryFlag:u8              = 0b00000001;
-
-    // Does not reset mem 
+    /// Reset the cpu and start executing from the address in the reset vector
+    ///
+    /// Does not reset the bus
     pub fn reset(&mut self)
     {
         self.a = 0;
         self.x = 0;
         self.y = 0;
-        self.sp = 0;
-        self.pc = 0;
-        self.sr = 0;
+        self.sp = 0xfd;
+        self.sr = StatusFlags::UNUSED | StatusFlags::INTERRUPT;
+        self.pc = self.read_u16(Cpu::RESET_VECTOR);
+
+        self.irq_line = false;
+        self.nmi_line = false;
+        self.nmi_pending = false;
+        self.state = State::Running;
+        self.cycles += u64::from(Cpu::INTERRUPT_CYCLES);
+        self.history.clear();
     }
 
-    /// Absolute addressing mode
-    ///
-    /// Data is accessed using 16-bit address specified as a constant
+    /// Set the state of the IRQ line
     ///
-    /// e.g LDA $06D3 
-    fn get_abs(&mut self) -> u8
+    /// The interrupt is taken before the next instruction for as long as the line is
+    /// held and the interrupt disable flag is clear, the device raising it
+    /// should release the line once the handler has acknowledged it
+    pub fn irq(&mut self, active: bool)
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
-
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        self.pc += 2;
-        self.mem[usize::from(res)]
+        self.irq_line = active;
     }
 
-    /// Absolute addressing mode but returns address of data
-    fn get_abs_address(&mut self) -> u16
+    /// Set the state of the NMI line
+    ///
+    /// NMI is edge triggered, only a change from inactive to active raises
+    /// an interrupt and it cannot be masked
+    pub fn nmi(&mut self, active: bool)
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
+        if active && !self.nmi_line
+        {
+            self.nmi_pending = true;
+        }
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        self.pc += 2;
-        res
+        self.nmi_line = active;
     }
 
-    /// Absolute addressing mode but returns reference to data 
-    fn get_abs_ref(&mut self) -> &mut u8
+    /// Snapshot of the registers, the bus and the devices, see `state::VERSION` for the layout
+    pub fn save_state(&self) -> Vec<u8>
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
+        let mut out = Vec::with_capacity(0x10100);
+        out.extend_from_slice(state::MAGIC);
+        out.push(state::VERSION);
+
+        out.extend_from_slice(&[self.a, self.x, self.y, self.sp]);
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.push(self.sr.bits());
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.push(match self.state
+        {
+            State::Running => 0,
+            State::Jammed => 1,
+            State::Waiting => 2,
+            State::Stopped => 3,
+        });
+        out.push(match self.variant
+        {
+            isa::Variant::Nmos => 0,
+            isa::Variant::Cmos => 1,
+            isa::Variant::Wdc => 2,
+        });
+        for flag in [self.undocumented, self.irq_line, self.nmi_line, self.nmi_pending]
+        {
+            out.push(u8::from(flag));
+        }
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        self.pc += 2;
-        &mut self.mem[usize::from(res)]
+        self.bus.save_state(&mut out);
+        out
     }
 
-    /// Absolute,X addressing mode
+    /// Restore a snapshot written by `save_state`
     ///
-    /// Data is accessed using 16-bit address specified as a constant,
-    /// to which the value of X register is added(with carry)
-    ///
-    /// e.g LDA $06D3,X
-    fn get_absx(&mut self) -> u8
+    /// The whole snapshot is checked first, nothing is changed when it is not valid
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError>
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
+        let mut input = Reader::new(data);
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-
-
-        res = res.wrapping_add(u16::from(self.x));
-
-        // // Add with carry
-        // let x = self.x;
-        // let option = res.checked_add(u16::from(x));
-
-        // match option
-        // {
-        //     None => 
-        //     {
-        //         res = res.wrapping_add(u16::from(x));
-        //         self.sr |= Cpu::CarryFlag;
-        //     },
-        //     Some(value) =>
-        //     {
-        //         res = value;
-        //         self.sr &= !Cpu::CarryFlag;
-        //     }
-        // }
-        // if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
-        // {
-        //     res += 1;
-        // }
-
-        self.pc += 2;
-        self.mem[usize::from(res)]
+        if input.bytes(state::MAGIC.len()).ok() != Some(&state::MAGIC[..])
+        {
+            return Err(StateError::BadMagic);
+        }
+        let version = input.u8()?;
+        if version != state::VERSION
+        {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let registers = input.bytes(4)?;
+        let pc = input.u16()?;
+        let sr = input.u8()?;
+        let cycles = input.u64()?;
+        let cpu_state = match input.u8()?
+        {
+            0 => State::Running,
+            1 => State::Jammed,
+            2 => State::Waiting,
+            3 => State::Stopped,
+            value => return Err(StateError::Mismatch(format!("unknown cpu state {}", value))),
+        };
+        let variant = match input.u8()?
+        {
+            0 => isa::Variant::Nmos,
+            1 => isa::Variant::Cmos,
+            2 => isa::Variant::Wdc,
+            value => return Err(StateError::Mismatch(format!("unknown cpu variant {}", value))),
+        };
+        let undocumented = input.bool()?;
+        let irq_line = input.bool()?;
+        let nmi_line = input.bool()?;
+        let nmi_pending = input.bool()?;
+
+        let mut check = input.clone();
+        self.bus.check_state(&mut check)?;
+        if !check.is_empty()
+        {
+            return Err(StateError::Mismatch("unexpected data after the bus state".to_string()));
+        }
+
+        self.flush_decode_cache();
+        self.bus.load_state(&mut input)?;
+
+        self.a = registers[0];
+        self.x = registers[1];
+        self.y = registers[2];
+        self.sp = registers[3];
+        self.pc = pc;
+        self.sr = StatusFlags::from_bits(sr);
+        self.cycles = cycles;
+        self.state = cpu_state;
+        self.variant = variant;
+        self.undocumented = undocumented;
+        self.irq_line = irq_line;
+        self.nmi_line = nmi_line;
+        self.nmi_pending = nmi_pending;
+        self.history.clear();
+
+        Ok(())
     }
 
-    /// Absolute,X addressing mode but returns address of data
-    fn get_absx_address(&mut self) -> u16
+    /// Push pc and the status register then jump through `vector`
+    ///
+    /// The B flag is only set in the pushed status when the interrupt came from BRK,
+    /// the 65C02 also clears the decimal flag
+    fn interrupt(&mut self, vector: u16, brk: bool)
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
-
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
+        self.stack_push_16(self.pc);
 
-        res = res.wrapping_add(u16::from(self.x));
+        let mut status = self.sr | StatusFlags::UNUSED;
+        status.set(StatusFlags::BREAK, brk);
+        self.stack_push(status.bits());
 
-        self.pc += 2;
-        res
+        self.sr.insert(StatusFlags::INTERRUPT);
+        if self.variant != isa::Variant::Nmos
+        {
+            self.sr.remove(StatusFlags::DECIMAL);
+        }
+        self.pc = self.read_u16(vector);
     }
 
-    /// Absolute,X addressing mode but returns reference to data 
-    fn get_absx_ref(&mut self) -> &mut u8
+    /// Read a byte from the bus
+    #[inline(always)]
+    fn read(&mut self, address: u16) -> u8
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
-
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        // Add with carry
-        let x = self.x;
-        let option = res.checked_add(u16::from(x));
+        self.bus.read(address)
+    }
 
-        match option
+    /// Write a byte to the bus
+    #[inline(always)]
+    fn write(&mut self, address: u16, value: u8)
+    {
+        if self.history_limit > 0
         {
-            None => 
-            {
-                res = res.wrapping_add(u16::from(x));
-                self.sr |= Cpu::CarryFlag;
-            },
-            Some(value) =>
+            self.journal.push((address, self.bus.peek(address)));
+            if self.devices_journal.is_none() && self.bus.is_device_register(address)
             {
-                res = value;
-                self.sr &= !Cpu::CarryFlag;
+                let mut devices = Vec::new();
+                self.bus.save_devices(&mut devices);
+                self.devices_journal = Some(devices);
             }
         }
-        if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
+        if let Some(cache) = &mut self.decode_cache
         {
-            res += 1;
+            cache.invalidate(address);
         }
 
-        self.pc += 2;
-        &mut self.mem[usize::from(res)]
+        self.bus.write(address, value);
     }
 
-    /// Absolute,Y addressing mode
-    ///
-    /// Data is accessed using 16-bit address specified as a constant,
-    /// to which the value of Y register is added(with carry)
-    ///
-    /// e.g LDA $06D3,Y
-    fn get_absy(&mut self) -> u8
+    /// Read-modify-write the byte at `address` with `op`, returns the new value
+    #[inline(always)]
+    fn modify(&mut self, address: u16, op: fn(&mut Self, u8) -> u8) -> u8
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
+        let value = self.read(address);
+        let result = op(self, value);
+        self.write(address, result);
+        result
+    }
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        let y = self.y;
-        let option = res.checked_add(u16::from(y));
+    /// Read a little-endian 16-bit value
+    fn read_u16(&mut self, address: u16) -> u16
+    {
+        let lsb = self.read(address);
+        let hsb = self.read(address.wrapping_add(1));
+
+        (u16::from(hsb) << 8) | u16::from(lsb)
+    }
 
-        match option
+    /// Fetch the byte at pc and move pc past it, decoded instructions take it from their operand
+    #[inline(always)]
+    fn fetch(&mut self) -> u8
+    {
+        let value = if self.prefetched
         {
-            None => 
-            {
-                res = res.wrapping_add(u16::from(y));
-                self.sr |= Cpu::CarryFlag;
-            },
-            Some(value) =>
-            {
-                res = value;
-                self.sr &= !Cpu::CarryFlag;
-            }
+            let byte = self.operand as u8;
+            self.operand >>= 8;
+            byte
         }
-        if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
+        else
         {
-            res += 1;
-        }
+            self.read(self.pc)
+        };
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    /// Fetch a little-endian 16-bit operand and move pc past it
+    #[inline(always)]
+    fn fetch_u16(&mut self) -> u16
+    {
+        let lsb = self.fetch();
+        let hsb = self.fetch();
 
-        self.pc += 2;
-        self.mem[usize::from(res)]
+        (u16::from(hsb) << 8) | u16::from(lsb)
     }
 
-    /// Absolute,Y addressing mode but returns address of data
-    fn get_absy_address(&mut self) -> u16
+    /// Read a 16-bit pointer from the zero page, the high byte wraps around to $00
+    #[inline(always)]
+    fn read_zp_u16(&mut self, address: u8) -> u16
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
+        let lsb = self.read(u16::from(address));
+        let hsb = self.read(u16::from(address.wrapping_add(1)));
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        let y = self.y;
-        let option = res.checked_add(u16::from(y));
+        (u16::from(hsb) << 8) | u16::from(lsb)
+    }
 
-        match option
-        {
-            None => 
-            {
-                res = res.wrapping_add(u16::from(y));
-                self.sr |= Cpu::CarryFlag;
-            },
-            Some(value) =>
-            {
-                res = value;
-                self.sr &= !Cpu::CarryFlag;
-            }
-        }
-        if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
-        {
-            res += 1;
-        }
+    /// Absolute addressing mode
+    ///
+    /// Data is accessed using 16-bit address specified as a constant
+    ///
+    /// e.g LDA $06D3 
+    fn get_abs(&mut self) -> u8
+    {
+        let address = self.get_abs_address();
+        self.read(address)
+    }
 
-        self.pc += 2;
-        res
+    /// Absolute addressing mode but returns address of data
+    fn get_abs_address(&mut self) -> u16
+    {
+        self.fetch_u16()
     }
 
-    /// Absolute,Y addressing mode but returns reference of data
-    fn get_absy_ref(&mut self) -> &mut u8
+    /// Absolute,X addressing mode
+    ///
+    /// Data is accessed using 16-bit address specified as a constant,
+    /// to which the value of X register is added(with carry)
+    ///
+    /// e.g LDA $06D3,X
+    fn get_absx(&mut self) -> u8
     {
-        let hsb = self.mem[usize::from(self.pc)];
-        let lsb = self.mem[usize::from(self.pc+1)];
+        let address = self.get_absx_address();
+        self.read(address)
+    }
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        let y = self.y;
-        let option = res.checked_add(u16::from(y));
+    /// Absolute,X addressing mode but returns address of data
+    fn get_absx_address(&mut self) -> u16
+    {
+        let base = self.fetch_u16();
+        let address = base.wrapping_add(u16::from(self.x));
 
-        match option
-        {
-            None => 
-            {
-                res = res.wrapping_add(u16::from(y));
-                self.sr |= Cpu::CarryFlag;
-            },
-            Some(value) =>
-            {
-                res = value;
-                self.sr &= !Cpu::CarryFlag;
-            }
-        }
-        if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
-        {
-            res += 1;
-        }
+        self.check_page_cross(base, address);
+        address
+    }
+
+    /// Absolute,Y addressing mode
+    ///
+    /// Data is accessed using 16-bit address specified as a constant,
+    /// to which the value of Y register is added(with carry)
+    ///
+    /// e.g LDA $06D3,Y
+    fn get_absy(&mut self) -> u8
+    {
+        let address = self.get_absy_address();
+        self.read(address)
+    }
+
+    /// Absolute,Y addressing mode but returns address of data
+    fn get_absy_address(&mut self) -> u16
+    {
+        let base = self.fetch_u16();
+        let address = base.wrapping_add(u16::from(self.y));
 
-        self.pc += 2;
-        &mut self.mem[usize::from(res)]
+        self.check_page_cross(base, address);
+        address
     }
 
     /// Immediate mode
@@ -305,9 +511,7 @@ impl Cpu
     /// e.g LDA #$80
     fn get_imm(&mut self) -> u8
     {
-        let value = self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        value
+        self.fetch()
     }
 
     /// Indirect mode
@@ -315,134 +519,112 @@ impl Cpu
     /// Operand is address.
     /// Data is contents of word at address. 
     ///
+    /// The NMOS 6502 does not carry into the high byte of the pointer,
+    /// JMP ($10FF) reads the target from $10FF and $1000
+    ///
     /// e.g JMP ($9000) ; Jumps to the location pointed to by addresses $9000 (low) and $9001 (high)
     fn get_ind(&mut self) -> u16
     {
-        let address_hsb = self.mem[usize::from(self.pc)];
-        let address_lsb = self.mem[usize::from(self.pc+1)];
+        let address = self.fetch_u16();
+        let next = (address & 0xff00) | (address.wrapping_add(1) & 0x00ff);
 
-        let mut address: u16 = address_hsb as u16;
-        address = address << 8;
-        address |= address_lsb as u16;
+        let lsb = self.read(address);
+        let hsb = self.read(next);
 
-        let lsb = self.mem[usize::from(address)];
-        let hsb = self.mem[usize::from(address + 1)];
+        (u16::from(hsb) << 8) | u16::from(lsb)
+    }
 
-        let mut res: u16 = u16::from(hsb);
-        res = res << 8;
-        res = res | u16::from(lsb);
-        self.pc += 2;
-        res
+    /// Indirect mode as fixed on the 65C02, the pointer may cross a page
+    ///
+    /// e.g JMP ($10FF) ; Jumps to the location pointed to by addresses $10FF (low) and $1100 (high)
+    fn get_ind_cmos(&mut self) -> u16
+    {
+        let address = self.fetch_u16();
+        self.read_u16(address)
+    }
+
+    /// Absolute indexed indirect mode (65C02)
+    ///
+    /// Add content of X to the 16-bit operand, the word at the result is the jump target
+    ///
+    /// e.g JMP ($9000,X)
+    fn get_absindx(&mut self) -> u16
+    {
+        let address = self.fetch_u16().wrapping_add(u16::from(self.x));
+        self.read_u16(address)
     }
 
     /// Indirect,X mode
     ///
-    /// Add content of Y to addresss without carry(wrapped).
+    /// Add content of X to the zero page operand without carry(wrapped).
     /// Address at result is used to access the data.
     ///
     /// e.g LDA($05,X)
     fn get_indx(&mut self) -> u8 
     {
-        let operand = self.mem[usize::from(self.pc)];
-        let mut address: u16 = self.mem[usize::from(operand + 1)] as u16;
-        address <<= 8;
-        address |= self.mem[usize::from(operand)] as u16;
-        address += self.x as u16;
-
-        self.pc += 1;
-        self.mem[usize::from(address)]
+        let address = self.get_indx_address();
+        self.read(address)
     }
 
     /// Indirect,X addressing mode but returns address of data
     fn get_indx_address(&mut self) -> u16
     {
-        let operand = self.mem[usize::from(self.pc)];
-        let mut address: u16 = self.mem[usize::from(operand + 1)] as u16;
-        address <<= 8;
-        address |= self.mem[usize::from(operand)] as u16;
-        address += self.x as u16;
-
-        self.pc += 1;
-        address
-    }
-
-    /// Indirect,X addressing mode but returns reference of data
-    fn get_indx_ref(&mut self) -> &mut u8 
-    {
-        let operand = self.mem[usize::from(self.pc)];
-        let mut address: u16 = self.mem[usize::from(operand + 1)] as u16;
-        address <<= 8;
-        address |= self.mem[usize::from(operand)] as u16;
-        address += self.x as u16;
-
-        self.pc += 1;
-        &mut self.mem[usize::from(address)]
+        let operand = self.fetch();
+        self.read_zp_u16(operand.wrapping_add(self.x))
     }
 
     /// Indirect,Y mode
     ///
-    /// Add content of Y to addresss without carry(wrapped).
-    /// Address at result is used to access the data.
+    /// Read the address stored in the zero page operand and add the content of Y to it.
+    /// Result is used to access the data.
     ///
     /// e.g LDA($05),Y
     fn get_indy(&mut self) -> u8 
     {
-        let operand = self.mem[usize::from(self.pc)];
-        let mut address: u16 = self.mem[usize::from(operand + 1)] as u16;
-        address <<= 8;
-        address |= self.mem[usize::from(operand)] as u16;
-        address += self.y as u16;
-
-        self.pc += 1;
-        self.mem[usize::from(address)]
+        let address = self.get_indy_address();
+        self.read(address)
     }
 
     /// Indirect,Y addressing mode but returns addresss of data
     fn get_indy_address(&mut self) -> u16
     {
-        let operand = self.mem[usize::from(self.pc)];
-        let mut address: u16 = self.mem[usize::from(operand + 1)] as u16;
-        address <<= 8;
-        address |= self.mem[usize::from(operand)] as u16;
-        address += self.y as u16;
+        let operand = self.fetch();
+        let base = self.read_zp_u16(operand);
+        let address = base.wrapping_add(u16::from(self.y));
 
-        self.pc += 1;
+        self.check_page_cross(base, address);
         address
     }
 
-    /// Indirect,Y addressing mode but returns reference of data
-    fn get_indy_ref(&mut self) -> &mut u8 
-    { 
-        let operand = self.mem[usize::from(self.pc)];
-        let mut address: u16 = self.mem[usize::from(operand + 1)] as u16;
-        address <<= 8;
-        address |= self.mem[usize::from(operand)] as u16;
-        address += self.y as u16;
+    /// Zero page indirect mode (65C02)
+    ///
+    /// Read the address stored in the zero page operand, the result is used to access the data.
+    ///
+    /// e.g LDA ($05)
+    fn get_zpi(&mut self) -> u8
+    {
+        let address = self.get_zpi_address();
+        self.read(address)
+    }
 
-        self.pc += 1;
-        &mut self.mem[usize::from(address)]
+    /// Zero page indirect addressing mode but returns address of data
+    fn get_zpi_address(&mut self) -> u16
+    {
+        let operand = self.fetch();
+        self.read_zp_u16(operand)
     }
 
     /// Relative mode
     ///
-    /// Branch target is PC + signed offset of value
+    /// Branch target is PC + signed offset of value,
+    /// PC being the address of the next instruction
     ///
     /// e.g BPL $05
-    /// 
-    /// This fn works differently from the spec
-    /// Since the branching cannot occur for values >256
-    /// the value of the operand will be the lowest byte of the pc.
-    /// I might be wrong but this seems to work
     fn get_rel(&mut self) -> u16
     {
-        let operand = self.mem[usize::from(self.pc)];
-
-        let value = (self.pc >> 8) << 8;
-        let value = value | u16::from(operand);
-
-        self.pc += 1;
+        let offset = self.fetch() as i8;
 
-        value
+        self.pc.wrapping_add(offset as u16)
     }
 
     /// Zeropage mode
@@ -452,98 +634,88 @@ impl Cpu
     /// e.g LDA $39
     fn get_zp(&mut self) -> u8
     {
-        let address = self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        self.mem[usize::from(address)]
+        let address = self.get_zp_address();
+        self.read(address)
     }
 
     /// Zero page addressing mode but returns address of data
     fn get_zp_address(&mut self) -> u16
     {
-        let address = self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        u16::from(address)
-    }
-
-    /// Zero page addressing mode but returns reference of data
-    fn get_zp_ref(&mut self) -> &mut u8
-    {
-        let address = self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        &mut self.mem[usize::from(address)]
+        u16::from(self.fetch())
     }
 
     /// Zeropage,X mode
     ///
-    /// Similar to Absolute,X
+    /// Similar to Absolute,X but the address wraps around inside the zero page
     ///
     /// e.g STA $39,X
     fn get_zpx(&mut self) -> u8
     {
-        let value = self.x + self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        self.mem[usize::from(value)]
+        let address = self.get_zpx_address();
+        self.read(address)
     }
 
     /// Zero page,X addressing mode but returns address of data
     fn get_zpx_address(&mut self) -> u16
     {
-        let address = self.x + self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        u16::from(address)
-    }
-
-    /// Zero page,X addressing mode but returns reference of data
-    fn get_zpx_ref(&mut self) -> &mut u8
-    {
-        let value = self.x + self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        &mut self.mem[usize::from(value)]
+        u16::from(self.fetch().wrapping_add(self.x))
     }
 
     /// Zeropage,Y mode
     ///
-    /// Similar to Absolute,Y
+    /// Similar to Absolute,Y but the address wraps around inside the zero page
     ///
-    /// e.g STA $39,Y
+    /// e.g LDX $39,Y
     fn get_zpy(&mut self) -> u8
     {
-        let value = self.y + self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        self.mem[usize::from(value)]
+        let address = self.get_zpy_address();
+        self.read(address)
     }
 
-    /// Zero page,X addressing mode but returns reference of data
-    fn get_zpy_ref(&mut self) -> &mut u8
+    /// Zero page,Y addressing mode but returns address of data
+    fn get_zpy_address(&mut self) -> u16
     {
-        let value = self.y + self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        &mut self.mem[usize::from(value)]
+        u16::from(self.fetch().wrapping_add(self.y))
     }
 
-    /// Zero page,X addressing mode but returns address of data
-    fn get_zpy_address(&mut self) -> u16
+    /// Record whether indexing `base` to get `address` crossed a page,
+    /// some instructions take an extra cycle when it does
+    #[inline(always)]
+    fn check_page_cross(&mut self, base: u16, address: u16)
+    {
+        self.page_crossed = (base & 0xff00) != (address & 0xff00);
+    }
+
+    /// Jump to a branch target
+    ///
+    /// A taken branch takes an extra cycle, two if the target is on another page
+    fn branch(&mut self, target: u16)
     {
-        let address = self.y + self.mem[usize::from(self.pc)];
-        self.pc += 1;
-        u16::from(address)
+        if (self.pc & 0xff00) == (target & 0xff00)
+        {
+            self.extra_cycles += 1;
+        }
+        else
+        {
+            self.extra_cycles += 2;
+        }
+
+        self.pc = target;
     }
 
     /// Push an 8-bit value to the stack
     fn stack_push(&mut self, value : u8)
     {
-        // println!("Stack push {:#4x} at {:#4x}", value, self.pc);
-        self.mem[usize::from(0x100 + u16::from(self.sp))] = value;
+        self.write(0x100 + u16::from(self.sp), value);
         self.sp = self.sp.wrapping_sub(1);
     }
 
     /// Push a 16-bit value to the stack
     fn stack_push_16(&mut self, value : u16)
     {
-        // println!("Stack push {:#4x} at {:#4x}", value, self.pc);
-        self.mem[usize::from(0x100 + u16::from(self.sp))] = (value >> 8 ) as u8;
+        self.write(0x100 + u16::from(self.sp), (value >> 8 ) as u8);
         self.sp = self.sp.wrapping_sub(1);
-        self.mem[usize::from(0x100 + u16::from(self.sp))]  = ((value << 8) >> 8) as u8;
+        self.write(0x100 + u16::from(self.sp), ((value << 8) >> 8) as u8);
         self.sp = self.sp.wrapping_sub(1);
     }
 
@@ -551,10 +723,9 @@ impl Cpu
     fn stack_pop(&mut self) -> u8
     {
         self.sp = self.sp.wrapping_add(1);
-        let value = self.mem[usize::from(0x100 + u16::from(self.sp))];
-        self.mem[usize::from(0x100 + u16::from(self.sp))] = 0;
+        let value = self.read(0x100 + u16::from(self.sp));
+        self.write(0x100 + u16::from(self.sp), 0);
 
-        // println!("Stack pop {:#4x} at {:#4x}", value, self.pc);
         value
     }
 
@@ -562,86 +733,43 @@ impl Cpu
     fn stack_pop_16(&mut self) -> u16
     {
         self.sp = self.sp.wrapping_add(1);
-        let lsb = self.mem[usize::from(0x100 + u16::from(self.sp))];
-        self.mem[usize::from(0x100 + u16::from(self.sp))] = 0;
+        let lsb = self.read(0x100 + u16::from(self.sp));
+        self.write(0x100 + u16::from(self.sp), 0);
         self.sp = self.sp.wrapping_add(1);
-        let hsb = self.mem[usize::from(0x100 + u16::from(self.sp))];
-        self.mem[usize::from(0x100 + u16::from(self.sp))] = 0;
+        let hsb = self.read(0x100 + u16::from(self.sp));
+        self.write(0x100 + u16::from(self.sp), 0);
 
         let mut res: u16 = u16::from(hsb);
         res = (res << 8) | u16::from(lsb);
 
-        // println!("Stack pop {:#4x} at {:#4x}", res, self.pc);
         res
     }
 
-    /// Set the zero flag, clear if `clear` is set
-    #[inline(always)]
-    fn set_zerof(&mut self, value: u8, clear : bool)
-    {
-        if value == 0
-        {
-            self.sr |= Cpu::ZeroFlag;
-        }
-        else if clear
-        {
-            self.sr &= !Cpu::ZeroFlag;
-        }
-    }
-
-    /// Set the negative flag, clear if `clear` is set
+    /// Set V when `left` and `right` have the same sign and `result`'s sign differs,
+    /// the signed overflow of `result = left + right`
     #[inline(always)]
-    fn set_negf(&mut self, value: u8, clear : bool)
-    {
-        if value >> 7 == 1
-        {
-            self.sr |= Cpu::NegFlag;
-        }
-        else if clear
-        {
-            self.sr &= !Cpu::NegFlag;
-        }
-    }
-
-    /// Set the overflow flag, clear if `clear` is set
-    fn set_vflag(&mut self, prev_value:u8, value:u8)
+    fn set_vflag(&mut self, left: u8, right: u8, result: u8)
     {
-        let bit_six_prev = (prev_value & 0b01000000 ) >> 6;
-        let bit_six = (value & 0b01000000 ) >> 6;
-
-        if bit_six_prev != bit_six
-        {
-            self.sr |= Cpu::OverFlowFlag;
-        }
-        else
-        {
-            self.sr &= !Cpu::OverFlowFlag;
-        }
-        // if prev_value &  0b01000000 == 0b01000000
-        //     && value & 0b10000000 == 0b10000000
-        //     {
-        //         self.sr |= Cpu::OverFlowFlag;
-        //     }
-        // else
-        // {
-        //     self.sr &= !Cpu::OverFlowFlag;
-        // }
+        self.sr.set(StatusFlags::OVERFLOW, !(left ^ right) & (left ^ result) & 0x80 != 0);
     }
 
     pub fn push_instruction(&mut self, instruction : u8, operand: u8)
     {
-        self.mem[usize::from(self.pc)] = instruction;
-        self.mem[usize::from(self.pc + 1)] = operand;
+        self.write(self.pc, instruction);
+        self.write(self.pc.wrapping_add(1), operand);
     }
 
     pub fn push_instruction_16(&mut self, instruction : u8, operand: u8, operand2 : u8)
     {
-        self.mem[usize::from(self.pc)] = instruction;
-        self.mem[usize::from(self.pc + 1)] = operand;
-        self.mem[usize::from(self.pc + 2)] = operand2;
+        self.write(self.pc, instruction);
+        self.write(self.pc.wrapping_add(1), operand);
+        self.write(self.pc.wrapping_add(2), operand2);
     }
 
-    fn execute_instruction(&mut self, opcode: u8)
+    /// Execute an NMOS 6502 opcode
+    ///
+    /// Returns false if the opcode is not supported
+    fn execute_instruction(&mut self, opcode: u8) -> bool
     {
         match opcode
         {
@@ -692,132 +820,107 @@ impl Cpu
                 let operand = self.get_abs();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_ABSX =>
             {
                 let operand = self.get_absx();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_ABSY =>
             {
                 let operand = self.get_absy();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_IMM =>
             {
                 let operand = self.get_imm();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_INDX =>
             {
                 let operand = self.get_indx();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_INDY =>
             {
                 let operand = self.get_indy();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_ZP =>
             {
                 let operand = self.get_zp();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::AND_ZPX =>
             {
                 let operand = self.get_zpx();
                 self.a &= operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::ASL_ACC =>
             {
-                let prev_a = self.a;
-                self.a = self.a << 1;
-
-                if (prev_a >> 7) == 0 || prev_a == 0
-                {
-                    self.sr &= !Cpu::CarryFlag;
-                }
-                else
-                {
-                    self.sr |= Cpu::CarryFlag;
-                }
-
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.a = self.asl(self.a);
             },
             isa::Instruction::ASL_ABS =>
             {
-                let value = self.get_abs_address() as usize;
+                let address = self.get_abs_address();
 
-                self.mem[value] = self.asl(self.mem[value]);
+                self.modify(address, Self::asl);
             },
             isa::Instruction::ASL_ABSX =>
             {
-                let value = self.get_absx_address() as usize;
+                let address = self.get_absx_address();
 
-                self.mem[value] = self.asl(self.mem[value]);
+                self.modify(address, Self::asl);
             },
             isa::Instruction::ASL_ZP =>
             {
-                let value = self.get_zp_address() as usize;
+                let address = self.get_zp_address();
 
-                self.mem[value] = self.asl(self.mem[value]);
+                self.modify(address, Self::asl);
             },
             isa::Instruction::ASL_ZPX =>
             {
+                let address = self.get_zpx_address();
 
-                let value = self.get_zpx_address() as usize;
-
-                self.mem[value] = self.asl(self.mem[value]);
+                self.modify(address, Self::asl);
             },
-            isa::Instruction::BCC_REL | isa::Instruction::BCC_REL_16 =>
+            isa::Instruction::BCC_REL =>
             {
-                let carry_flag = self.sr & Cpu::CarryFlag;
                 let operand = self.get_rel();
-                if carry_flag == 0
+                if !self.sr.contains(StatusFlags::CARRY)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
-            isa::Instruction::BCS_REL | isa::Instruction::BCS_REL_16 =>
+            isa::Instruction::BCS_REL =>
             {
-                let carry_flag = self.sr & Cpu::CarryFlag;
                 let operand = self.get_rel();
-                if carry_flag == Cpu::CarryFlag
+                if self.sr.contains(StatusFlags::CARRY)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
-            isa::Instruction::BEQ_REL | isa::Instruction::BEQ_REL_16 =>
+            isa::Instruction::BEQ_REL =>
             {
-                let zero_flag = self.sr & Cpu::ZeroFlag;
                 let operand = self.get_rel();
-                if zero_flag == Cpu::ZeroFlag
+                if self.sr.contains(StatusFlags::ZERO)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
             isa::Instruction::BIT_ABS =>
@@ -831,70 +934,67 @@ impl Cpu
                 let operand = self.get_zp();
                 self.bit(operand);
             },
-            isa::Instruction::BMI_REL | isa::Instruction::BMI_REL_16 =>
+            isa::Instruction::BMI_REL =>
             {
-                let neg_flag = self.sr & Cpu::NegFlag;
                 let operand = self.get_rel();
-                if neg_flag == Cpu::NegFlag 
+                if self.sr.contains(StatusFlags::NEGATIVE)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
-            isa::Instruction::BNE_REL | isa::Instruction::BNE_REL_16 =>
+            isa::Instruction::BNE_REL =>
             {
-                let zero_flag = self.sr & Cpu::ZeroFlag;
                 let operand = self.get_rel();
-                if zero_flag == 0
+                if !self.sr.contains(StatusFlags::ZERO)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
-            isa::Instruction::BPL_REL | isa::Instruction::BPL_REL_16 =>
+            isa::Instruction::BPL_REL =>
             {
-                let neg_flag = self.sr & Cpu::NegFlag;
                 let operand = self.get_rel();
-                if neg_flag == 0
+                if !self.sr.contains(StatusFlags::NEGATIVE)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
             isa::Instruction::BRK_IMP =>
             {
-                self.sr |= Cpu::BreakFlag;
+                // BRK is followed by a padding byte, the return address skips it
+                self.pc = self.pc.wrapping_add(1);
+                self.interrupt(Cpu::IRQ_VECTOR, true);
             },
-            isa::Instruction::BVC_REL | isa::Instruction::BVC_REL_16 =>
+            isa::Instruction::BVC_REL =>
             {
-                let overflow_flag = self.sr & Cpu::OverFlowFlag;
                 let operand = self.get_rel();
-                if overflow_flag == 0
+                if !self.sr.contains(StatusFlags::OVERFLOW)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
-            isa::Instruction::BVS_REL | isa::Instruction::BVS_REL_16 =>
+            isa::Instruction::BVS_REL =>
             {
-                let overflow_flag = self.sr & Cpu::OverFlowFlag;
                 let operand = self.get_rel();
-                if overflow_flag == Cpu::OverFlowFlag 
+                if self.sr.contains(StatusFlags::OVERFLOW)
                 {
-                    self.pc = operand;
+                    self.branch(operand);
                 }
             },
             isa::Instruction::CLC_IMP =>
             {
-                self.sr &= !Cpu::CarryFlag;
+                self.sr.remove(StatusFlags::CARRY);
             },
             isa::Instruction::CLD_IMP =>
             {
-                self.sr &= !Cpu::DecimalFlag;
+                self.sr.remove(StatusFlags::DECIMAL);
             },
             isa::Instruction::CLI_IMP =>
             {
-                self.sr &= !Cpu::InterruptFlag;
+                self.sr.remove(StatusFlags::INTERRUPT);
             },
             isa::Instruction::CLV_IMP =>
             {
-                self.sr &= !Cpu::OverFlowFlag;
+                self.sr.remove(StatusFlags::OVERFLOW);
             },
 
             isa::Instruction::CMP_ABS =>
@@ -970,37 +1070,35 @@ impl Cpu
             },
             isa::Instruction::DEC_ABS =>
             {
-                let value = self.get_abs_address() as usize;
-                self.mem[value] = self.dec(self.mem[value]);
+                let address = self.get_abs_address();
+                self.modify(address, Self::dec);
             },
             isa::Instruction::DEC_ABSX =>
             {
-                let value = self.get_absx_address() as usize;
-                self.mem[value] = self.dec(self.mem[value]);
+                let address = self.get_absx_address();
+                self.modify(address, Self::dec);
             },
             isa::Instruction::DEC_ZP =>
             {
-                let value = self.get_zp_address() as usize;
-                self.mem[value] = self.dec(self.mem[value]);
+                let address = self.get_zp_address();
+                self.modify(address, Self::dec);
             },
             isa::Instruction::DEC_ZPX =>
             {
-                let value = self.get_zpx_address() as usize;
-                self.mem[value] = self.dec(self.mem[value]);
+                let address = self.get_zpx_address();
+                self.modify(address, Self::dec);
             },
             isa::Instruction::DEX_IMP =>
             {
                 self.x = self.x.wrapping_sub(1);
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::DEY_IMP =>
             {
                 self.y = self.y.wrapping_sub(1);
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::EOR_ABS =>
             {
@@ -1044,38 +1142,36 @@ impl Cpu
             },
             isa::Instruction::INC_ABS =>
             {
-                let value = self.get_abs_address() as usize;
-                self.mem[value] = self.inc(self.mem[value]);
+                let address = self.get_abs_address();
+                self.modify(address, Self::inc);
             },
             isa::Instruction::INC_ABSX =>
             {
-                let value = self.get_absx_address() as usize;
-                self.mem[value] = self.inc(self.mem[value]);
+                let address = self.get_absx_address();
+                self.modify(address, Self::inc);
             },
             isa::Instruction::INC_ZP =>
             {
-                let value = self.get_zp_address() as usize;
-                self.mem[value] = self.inc(self.mem[value]);
+                let address = self.get_zp_address();
+                self.modify(address, Self::inc);
             },
             isa::Instruction::INC_ZPX =>
             {
-                let value = self.get_zpx_address() as usize;
-                self.mem[value] = self.inc(self.mem[value]);
+                let address = self.get_zpx_address();
+                self.modify(address, Self::inc);
             },
             isa::Instruction::INX_IMP =>
             {
                 self.x = self.x.wrapping_add(1);
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
 
             },
             isa::Instruction::INY_IMP =>
             {
                 self.y = self.y.wrapping_add(1);
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::JMP_ABS =>
             {
@@ -1095,9 +1191,9 @@ impl Cpu
             {
                 let operand = self.get_abs_address();
 
-                self.stack_push_16(self.pc);
+                // The return address pushed is the last byte of the JSR, RTS adds 1
+                self.stack_push_16(self.pc.wrapping_sub(1));
                 self.pc = operand;
-                // println!("Jumping to {:#4x} {:#4x}", self.pc, 0x600 + operand);
             },
             isa::Instruction::LDA_ABS =>
             {
@@ -1105,17 +1201,15 @@ impl Cpu
 
                 self.a = operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_ABSX =>
             {
                 let operand = self.get_absx_address();
 
-                self.a = self.mem[usize::from(operand)];
+                self.a = self.read(operand);
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_ABSY =>
             {
@@ -1123,8 +1217,7 @@ impl Cpu
 
                 self.a = operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_IMM =>
             {
@@ -1132,8 +1225,7 @@ impl Cpu
 
                 self.a = operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_INDX =>
             {
@@ -1141,8 +1233,7 @@ impl Cpu
 
                 self.a = operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_INDY =>
             {
@@ -1150,17 +1241,14 @@ impl Cpu
 
                 self.a = operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_ZP =>
             {
                 let operand = self.get_zp();
                 self.a = operand;
-                assert_eq!(self.a, operand);
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDA_ZPX =>
             {
@@ -1168,8 +1256,7 @@ impl Cpu
 
                 self.a = operand;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::LDX_ABS =>
             {
@@ -1177,8 +1264,7 @@ impl Cpu
 
                 self.x = operand;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::LDX_ABSY =>
             {
@@ -1186,8 +1272,7 @@ impl Cpu
 
                 self.x = operand;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::LDX_IMM =>
             {
@@ -1195,8 +1280,7 @@ impl Cpu
 
                 self.x = operand;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::LDX_ZP =>
             {
@@ -1204,8 +1288,7 @@ impl Cpu
 
                 self.x = operand;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::LDX_ZPY =>
             {
@@ -1213,8 +1296,7 @@ impl Cpu
 
                 self.x = operand;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::LDY_ABS =>
             {
@@ -1222,8 +1304,7 @@ impl Cpu
 
                 self.y = operand;
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::LDY_ABSX =>
             {
@@ -1231,8 +1312,7 @@ impl Cpu
 
                 self.y = operand;
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::LDY_IMM =>
             {
@@ -1240,8 +1320,7 @@ impl Cpu
 
                 self.y = operand;
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::LDY_ZP =>
             {
@@ -1249,8 +1328,7 @@ impl Cpu
 
                 self.y = operand;
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::LDY_ZPX =>
             {
@@ -1258,46 +1336,31 @@ impl Cpu
 
                 self.y = operand;
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::LSR_ACC =>
             {
-                let lsb = (self.a << 7) >> 7;
-                self.a = self.a >> 1;
-
-                self.sr &= !Cpu::NegFlag;
-
-                if lsb == 0
-                {
-                    self.sr &= !Cpu::CarryFlag;
-                }
-                else
-                {
-                    self.sr |= Cpu::CarryFlag;
-                }
-
-                self.set_zerof(self.a, true);
+                self.a = self.lsr(self.a);
             },
             isa::Instruction::LSR_ABS =>
             {
-                let value = self.get_abs_address() as usize;
-                self.mem[value] = self.lsr(self.mem[value]);
+                let address = self.get_abs_address();
+                self.modify(address, Self::lsr);
             },
             isa::Instruction::LSR_ABSX =>
             {
-                let value = self.get_absx_address() as usize;
-                self.mem[value] = self.lsr(self.mem[value]);
+                let address = self.get_absx_address();
+                self.modify(address, Self::lsr);
             },
             isa::Instruction::LSR_ZP =>
             {
-                let value = self.get_zp_address() as usize;
-                self.mem[value] = self.lsr(self.mem[value]);
+                let address = self.get_zp_address();
+                self.modify(address, Self::lsr);
             },
             isa::Instruction::LSR_ZPX =>
             {
-                let value = self.get_zpx_address() as usize;
-                self.mem[value] = self.lsr(self.mem[value]);
+                let address = self.get_zpx_address();
+                self.modify(address, Self::lsr);
             },
             isa::Instruction::NOP_IMP =>
             {
@@ -1348,94 +1411,75 @@ impl Cpu
             },
             isa::Instruction::PHP_IMP =>
             {
-                self.stack_push(self.sr);
+                self.stack_push((self.sr | StatusFlags::BREAK | StatusFlags::UNUSED).bits());
             },
             isa::Instruction::PLA_IMP =>
             {
                 self.a = self.stack_pop();
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::PLP_IMP =>
             {
-                self.sp = self.stack_pop();
+                self.sr = (StatusFlags::from_bits(self.stack_pop()) & !StatusFlags::BREAK) | StatusFlags::UNUSED;
             },
             isa::Instruction::ROL_ACC =>
             {
-                let hsb = self.a >> 7;
-
-                let prev_carry = self.sr & Cpu::CarryFlag;
-
-                self.a <<= 1;
-                self.a |= prev_carry;
-
-                // Set carry
-                self.sr &= hsb;
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.a = self.rol(self.a);
             },
             isa::Instruction::ROL_ABS =>
             {
-                let value = self.get_abs_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_abs_address();
+                self.modify(address, Self::rol);
             },
             isa::Instruction::ROL_ABSX =>
             {
-                let value = self.get_absx_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_absx_address();
+                self.modify(address, Self::rol);
             },
             isa::Instruction::ROL_ZP =>
             {
-                let value = self.get_absy_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_zp_address();
+                self.modify(address, Self::rol);
             },
             isa::Instruction::ROL_ZPX =>
             {
-                let value = self.get_zpx_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_zpx_address();
+                self.modify(address, Self::rol);
             },
             isa::Instruction::ROR_ACC =>
             {
-                let lsb = (self.a << 7) >> 7;
-                let prev_carry = self.sr & Cpu::CarryFlag;
-
-                self.a >>= 1;
-                self.a |= prev_carry << 7;
-
-                self.sr &= lsb;
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.a = self.ror(self.a);
             },
             isa::Instruction::ROR_ABS =>
             {
-                let value = self.get_abs_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_abs_address();
+                self.modify(address, Self::ror);
             },
             isa::Instruction::ROR_ABSX =>
             {
-                let value = self.get_absx_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_absx_address();
+                self.modify(address, Self::ror);
             },
             isa::Instruction::ROR_ZP =>
             {
-                let value = self.get_zp_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_zp_address();
+                self.modify(address, Self::ror);
             },
             isa::Instruction::ROR_ZPX =>
             {
-                let value = self.get_zpx_address() as usize;
-                self.mem[value] = self.rol(self.mem[value]);
+                let address = self.get_zpx_address();
+                self.modify(address, Self::ror);
             },
             isa::Instruction::RTI_IMP =>
             {
-                self.sp = self.stack_pop();
+                self.sr = (StatusFlags::from_bits(self.stack_pop()) & !StatusFlags::BREAK) | StatusFlags::UNUSED;
                 self.pc = self.stack_pop_16();
             },
             isa::Instruction::RTS_IMP =>
             {
                 let operand = self.stack_pop_16();
-                self.pc = operand;
+                self.pc = operand.wrapping_add(1);
             },
             isa::Instruction::SBC_ABS =>
             {
@@ -1479,320 +1523,746 @@ impl Cpu
             },
             isa::Instruction::SEC_IMP =>
             {
-                self.sr |= Cpu::CarryFlag;
+                self.sr.insert(StatusFlags::CARRY);
             },
             isa::Instruction::SED_IMP =>
             {
-                self.sr |= Cpu::DecimalFlag;
+                self.sr.insert(StatusFlags::DECIMAL);
             },
             isa::Instruction::SEI_IMP =>
             {
-                self.sr |= Cpu::InterruptFlag;
+                self.sr.insert(StatusFlags::INTERRUPT);
             },
             isa::Instruction::STA_ABS =>
             {
-                // *self.get_abs_ref() = self.a;
-                let val = self.a;
-                let operand = self.get_abs_ref();
-                *operand = val;
+                let address = self.get_abs_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STA_ABSX =>
             {
-                let address = self.get_absx_address() as usize;
-                self.mem[address] = self.a;
-                // println!("STA_ABSX: Storing {:#4x} to {:#4x}", self.a, address);
-                // let val = self.a;
-                // let operand = self.get_absx_ref();
-                // *operand = val;
+                let address = self.get_absx_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STA_ABSY =>
             {
-                let val = self.a;
-                let operand = self.get_absy_ref();
-                *operand = val;
+                let address = self.get_absy_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STA_INDX =>
             {
-                let address = self.get_indx_address() as usize;
-                self.mem[address] = self.a;
+                let address = self.get_indx_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STA_INDY =>
             {
-                let address = self.get_indy_address() as usize;
-                self.mem[address] = self.a;
+                let address = self.get_indy_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STA_ZP =>
             {
-                let address = self.get_zp_address() as usize;
-                self.mem[address] = self.a;
-                // println!("STA_ZP: Storing {:#4x} to {:#4x}", self.a, address);
+                let address = self.get_zp_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STA_ZPX =>
             {
-                let address = self.get_zpy_address() as usize;
-                self.mem[address] = self.a;
+                let address = self.get_zpx_address();
+                self.write(address, self.a);
             },
             isa::Instruction::STX_ABS =>
             {
-                let val = self.x;
-                let operand = self.get_abs_ref();
-                *operand = val;
+                let address = self.get_abs_address();
+                self.write(address, self.x);
             },
             isa::Instruction::STX_ZP =>
             {
-                let val = self.x;
-                let operand = self.get_zp_ref();
-                *operand = val;
+                let address = self.get_zp_address();
+                self.write(address, self.x);
             },
             isa::Instruction::STX_ZPY =>
             {
-                let val = self.x;
-                let operand = self.get_zpy_ref();
-                *operand = val;
+                let address = self.get_zpy_address();
+                self.write(address, self.x);
             },
             isa::Instruction::STY_ABS =>
             {
-                let val = self.y;
-                let operand = self.get_abs_ref();
-                *operand = val;
+                let address = self.get_abs_address();
+                self.write(address, self.y);
             },
             isa::Instruction::STY_ZP =>
             {
-                let val = self.y;
-                let operand = self.get_zp_ref();
-                *operand = val;
+                let address = self.get_zp_address();
+                self.write(address, self.y);
             },
             isa::Instruction::STY_ZPX =>
             {
-                let val = self.y;
-                let operand = self.get_zpx_ref();
-                *operand = val;
+                let address = self.get_zpx_address();
+                self.write(address, self.y);
             },
             isa::Instruction::TAX_IMP =>
             {
                 self.x = self.a;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::TAY_IMP =>
             {
                 self.y = self.a;
 
-                self.set_zerof(self.y, true);
-                self.set_negf(self.y, true);
+                self.sr.set_nz(self.y);
             },
             isa::Instruction::TSX_IMP =>
             {
-                self.x = self.sr;
+                self.x = self.sp;
 
-                self.set_zerof(self.x, true);
-                self.set_negf(self.x, true);
+                self.sr.set_nz(self.x);
             },
             isa::Instruction::TXA_IMP =>
             {
                 self.a = self.x;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
             isa::Instruction::TXS_IMP =>
             {
-                self.sr = self.x;
+                self.sp = self.x;
             },
             isa::Instruction::TYA_IMP =>
             {
                 self.a = self.y;
 
-                self.set_zerof(self.a, true);
-                self.set_negf(self.a, true);
+                self.sr.set_nz(self.a);
             },
-            _ => { self.print_regs();panic!("Opcode {:#4x} at pc {:#4x} not supported", opcode, self.pc - 1) }
+            _ =>
+            {
+                return self.execute_undocumented(opcode);
+            }
 
         }
+
+        true
     }
 
-    #[inline(always)]
-    fn adc(&mut self, operand : u8)
+    /// Address of the operand of an undocumented opcode
+    ///
+    /// Like the documented opcodes (aaabbbcc) the mode is encoded in bbb,
+    /// SAX and LAX index with Y where the others use X
+    fn get_undocumented_address(&mut self, opcode: u8) -> u16
+    {
+        let index_y = opcode & 0xc0 == 0x80;
+
+        match (opcode >> 2) & 0x07
+        {
+            0 => self.get_indx_address(),
+            1 => self.get_zp_address(),
+            3 => self.get_abs_address(),
+            4 => self.get_indy_address(),
+            5 if index_y => self.get_zpy_address(),
+            5 => self.get_zpx_address(),
+            6 => self.get_absy_address(),
+            7 if index_y => self.get_absy_address(),
+            _ => self.get_absx_address(),
+        }
+    }
+
+    /// Execute an undocumented NMOS opcode
+    ///
+    /// Returns false if the opcode is not one of the stable undocumented opcodes
+    ///
+    /// Ref : [NMOS 6510 Unintended Opcodes](https://csdb.dk/release/?id=198357)
+    fn execute_undocumented(&mut self, opcode: u8) -> bool
     {
-        let prev_a = self.a;
-        let option = self.a.checked_add(operand);
-        match option
+        match opcode
         {
-            None => 
+            isa::Instruction::SLO_ZP | isa::Instruction::SLO_ZPX | isa::Instruction::SLO_ABS
+                | isa::Instruction::SLO_ABSX | isa::Instruction::SLO_ABSY
+                | isa::Instruction::SLO_INDX | isa::Instruction::SLO_INDY =>
             {
-                // Overflowed
-                // let sum : u16 = u16::from(self.a) + u16::from(operand) + 1;
-                // self.a = (sum - 255_u16) as u8;
-                self.a = self.a.wrapping_add(operand);
-                if self.sr & Cpu::CarryFlag == Cpu::CarryFlag
-                {
-                    self.a += 1;
-                }
-                self.sr |= Cpu::CarryFlag;
+                let address = self.get_undocumented_address(opcode);
+                let value = self.modify(address, Self::asl);
+                self.ora(value);
+            },
+            isa::Instruction::RLA_ZP | isa::Instruction::RLA_ZPX | isa::Instruction::RLA_ABS
+                | isa::Instruction::RLA_ABSX | isa::Instruction::RLA_ABSY
+                | isa::Instruction::RLA_INDX | isa::Instruction::RLA_INDY =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                let value = self.modify(address, Self::rol);
+                self.a &= value;
+
+                self.sr.set_nz(self.a);
+            },
+            isa::Instruction::SRE_ZP | isa::Instruction::SRE_ZPX | isa::Instruction::SRE_ABS
+                | isa::Instruction::SRE_ABSX | isa::Instruction::SRE_ABSY
+                | isa::Instruction::SRE_INDX | isa::Instruction::SRE_INDY =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                let value = self.modify(address, Self::lsr);
+                self.eor(value);
+            },
+            isa::Instruction::RRA_ZP | isa::Instruction::RRA_ZPX | isa::Instruction::RRA_ABS
+                | isa::Instruction::RRA_ABSX | isa::Instruction::RRA_ABSY
+                | isa::Instruction::RRA_INDX | isa::Instruction::RRA_INDY =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                let value = self.modify(address, Self::ror);
+                self.adc(value);
+            },
+            isa::Instruction::SAX_ZP | isa::Instruction::SAX_ZPY | isa::Instruction::SAX_ABS
+                | isa::Instruction::SAX_INDX =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                self.write(address, self.a & self.x);
+            },
+            isa::Instruction::LAX_ZP | isa::Instruction::LAX_ZPY | isa::Instruction::LAX_ABS
+                | isa::Instruction::LAX_ABSY | isa::Instruction::LAX_INDX
+                | isa::Instruction::LAX_INDY =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                self.a = self.read(address);
+                self.x = self.a;
+
+                self.sr.set_nz(self.a);
+            },
+            isa::Instruction::DCP_ZP | isa::Instruction::DCP_ZPX | isa::Instruction::DCP_ABS
+                | isa::Instruction::DCP_ABSX | isa::Instruction::DCP_ABSY
+                | isa::Instruction::DCP_INDX | isa::Instruction::DCP_INDY =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                let value = self.read(address).wrapping_sub(1);
+                self.write(address, value);
+                self.cmp(self.a, value);
+            },
+            isa::Instruction::ISC_ZP | isa::Instruction::ISC_ZPX | isa::Instruction::ISC_ABS
+                | isa::Instruction::ISC_ABSX | isa::Instruction::ISC_ABSY
+                | isa::Instruction::ISC_INDX | isa::Instruction::ISC_INDY =>
+            {
+                let address = self.get_undocumented_address(opcode);
+                let value = self.read(address).wrapping_add(1);
+                self.write(address, value);
+                self.sbc(value);
+            },
+            isa::Instruction::LAS_ABSY =>
+            {
+                let operand = self.get_absy();
+                self.a = operand & self.sp;
+                self.x = self.a;
+                self.sp = self.a;
+
+                self.sr.set_nz(self.a);
             },
-            Some(_value) =>
+            // ANC has two encodings
+            isa::Instruction::ANC_IMM | isa::Instruction::ANC_IMM_ALT =>
             {
-                self.a = self.a.wrapping_add(operand);
-                if self.sr & Cpu::CarryFlag == Cpu::CarryFlag
+                let operand = self.get_imm();
+                self.a &= operand;
+
+                self.sr.set_nz(self.a);
+                if self.a & 0x80 == 0x80
                 {
-                    self.a += 1;
-                    self.sr &= !Cpu::CarryFlag;
+                    self.sr.insert(StatusFlags::CARRY);
                 }
-            }
+                else
+                {
+                    self.sr.remove(StatusFlags::CARRY);
+                }
+            },
+            isa::Instruction::ALR_IMM =>
+            {
+                let operand = self.get_imm();
+                self.a = self.lsr(self.a & operand);
+            },
+            isa::Instruction::ARR_IMM =>
+            {
+                let operand = self.get_imm();
+                self.arr(operand);
+            },
+            isa::Instruction::SBX_IMM =>
+            {
+                let operand = self.get_imm();
+                let value = self.a & self.x;
+
+                self.cmp(value, operand);
+                self.x = value.wrapping_sub(operand);
+
+                self.sr.set_nz(self.x);
+            },
+            isa::Instruction::USBC_IMM =>
+            {
+                let operand = self.get_imm();
+                self.sbc(operand);
+            },
+            0x1a | 0x3a | 0x5a | 0x7a | 0xda | 0xfa =>
+            {
+            },
+            isa::Instruction::NOP_IMM | 0x82 | 0x89 | 0xc2 | 0xe2 =>
+            {
+                self.get_imm();
+            },
+            // The remaining NOPs read their operand without using it
+            isa::Instruction::NOP_ZP | 0x44 | 0x64
+                | isa::Instruction::NOP_ZPX | 0x34 | 0x54 | 0x74 | 0xd4 | 0xf4
+                | isa::Instruction::NOP_ABS
+                | isa::Instruction::NOP_ABSX | 0x3c | 0x5c | 0x7c | 0xdc | 0xfc =>
+            {
+                self.get_undocumented_address(opcode);
+            },
+            isa::Instruction::JAM_IMP | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 | 0x62 | 0x72 | 0x92
+                | 0xb2 | 0xd2 | 0xf2 =>
+            {
+                // Stay on the JAM opcode, nothing runs until a reset
+                self.pc = self.pc.wrapping_sub(1);
+                self.state = State::Jammed;
+            },
+            _ => return false,
         }
 
-        self.set_negf(self.a, true);
-        self.set_vflag(prev_a,self.a);
-        self.set_zerof(self.a, true);
+        true
     }
-    #[inline(always)]
-    fn adci(&mut self, operand : u8)
-    {
-        let prev_a = self.a;
-        let option = self.a.checked_add(operand);
 
-        match option
+    /// Execute an opcode that the 65C02 adds or changes
+    ///
+    /// Returns false if the opcode behaves as on the NMOS 6502.
+    /// Opcodes left undefined by the 65C02 are NOPs of 1 to 3 bytes
+    fn execute_cmos(&mut self, opcode: u8) -> bool
+    {
+        match opcode
         {
-            None => 
+            isa::Instruction::ADC_ZPI =>
             {
-                self.a = self.a.wrapping_add(operand);
-        if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
-        {
-            self.a += 1;
-        }
-                self.sr |= Cpu::CarryFlag;
+                let operand = self.get_zpi();
+                self.adc(operand);
+            },
+            isa::Instruction::AND_ZPI =>
+            {
+                self.a &= self.get_zpi();
+                self.sr.set_nz(self.a);
+            },
+            isa::Instruction::BIT_IMM =>
+            {
+                // Only Z is affected by the immediate form
+                let operand = self.get_imm();
+                self.sr.set(StatusFlags::ZERO, self.a & operand == 0);
+            },
+            isa::Instruction::BIT_ZPX =>
+            {
+                let operand = self.get_zpx();
+                self.bit(operand);
+            },
+            isa::Instruction::BIT_ABSX =>
+            {
+                let operand = self.get_absx();
+                self.bit(operand);
+            },
+            isa::Instruction::BRA_REL =>
+            {
+                let operand = self.get_rel();
+                self.branch(operand);
+            },
+            isa::Instruction::CMP_ZPI =>
+            {
+                let operand = self.get_zpi();
+                self.cmp(self.a, operand);
+            },
+            isa::Instruction::DEC_ACC =>
+            {
+                self.a = self.dec(self.a);
+            },
+            isa::Instruction::EOR_ZPI =>
+            {
+                let operand = self.get_zpi();
+                self.eor(operand);
+            },
+            isa::Instruction::INC_ACC =>
+            {
+                self.a = self.inc(self.a);
+            },
+            isa::Instruction::JMP_IND =>
+            {
+                self.pc = self.get_ind_cmos();
             },
-            Some(value) =>
+            isa::Instruction::JMP_ABSINDX =>
             {
-                self.a = value;
-                if  self.sr & Cpu::CarryFlag == Cpu::CarryFlag
+                self.pc = self.get_absindx();
+            },
+            isa::Instruction::LDA_ZPI =>
+            {
+                self.a = self.get_zpi();
+                self.sr.set_nz(self.a);
+            },
+            isa::Instruction::ORA_ZPI =>
+            {
+                let operand = self.get_zpi();
+                self.ora(operand);
+            },
+            isa::Instruction::PHX_IMP =>
+            {
+                self.stack_push(self.x);
+            },
+            isa::Instruction::PHY_IMP =>
+            {
+                self.stack_push(self.y);
+            },
+            isa::Instruction::PLX_IMP =>
+            {
+                self.x = self.stack_pop();
+                self.sr.set_nz(self.x);
+            },
+            isa::Instruction::PLY_IMP =>
+            {
+                self.y = self.stack_pop();
+                self.sr.set_nz(self.y);
+            },
+            isa::Instruction::SBC_ZPI =>
+            {
+                let operand = self.get_zpi();
+                self.sbc(operand);
+            },
+            isa::Instruction::STA_ZPI =>
+            {
+                let address = self.get_zpi_address();
+                self.write(address, self.a);
+            },
+            isa::Instruction::STZ_ZP =>
+            {
+                let address = self.get_zp_address();
+                self.write(address, 0);
+            },
+            isa::Instruction::STZ_ZPX =>
+            {
+                let address = self.get_zpx_address();
+                self.write(address, 0);
+            },
+            isa::Instruction::STZ_ABS =>
+            {
+                let address = self.get_abs_address();
+                self.write(address, 0);
+            },
+            isa::Instruction::STZ_ABSX =>
+            {
+                let address = self.get_absx_address();
+                self.write(address, 0);
+            },
+            isa::Instruction::TRB_ZP | isa::Instruction::TRB_ABS =>
+            {
+                let address = if opcode == isa::Instruction::TRB_ZP
                 {
-                    self.a = self.a.wrapping_add(1);
+                    self.get_zp_address()
                 }
-                self.sr &= !Cpu::CarryFlag;
-            }
+                else
+                {
+                    self.get_abs_address()
+                };
+
+                let value = self.read(address);
+                self.sr.set(StatusFlags::ZERO, self.a & value == 0);
+                self.write(address, value & !self.a);
+            },
+            isa::Instruction::TSB_ZP | isa::Instruction::TSB_ABS =>
+            {
+                let address = if opcode == isa::Instruction::TSB_ZP
+                {
+                    self.get_zp_address()
+                }
+                else
+                {
+                    self.get_abs_address()
+                };
+
+                let value = self.read(address);
+                self.sr.set(StatusFlags::ZERO, self.a & value == 0);
+                self.write(address, value | self.a);
+            },
+            // Undefined opcodes are NOPs that still fetch their operand
+            _ if isa::CMOS_OPCODES[usize::from(opcode)].undocumented =>
+            {
+                for _ in 1..isa::CMOS_OPCODES[usize::from(opcode)].bytes
+                {
+                    self.fetch();
+                }
+            },
+            _ => return false,
         }
 
+        true
+    }
+
+    /// Execute an opcode that the WDC 65C02 adds to the 65C02
+    ///
+    /// Returns false if the opcode behaves as on the 65C02
+    fn execute_wdc(&mut self, opcode: u8) -> bool
+    {
+        // RMB, SMB, BBR and BBS keep the bit number in bits 4-6 and tell
+        // reset/branch-if-reset from set/branch-if-set with bit 7
+        let bit = 1 << ((opcode >> 4) & 0x07);
+        let set = opcode & 0x80 == 0x80;
 
-        self.set_vflag(prev_a, self.a);
-        if prev_a > self.a
+        match opcode
         {
-                self.sr |= Cpu::OverFlowFlag;
+            isa::Instruction::STP_IMP =>
+            {
+                self.state = State::Stopped;
+            },
+            isa::Instruction::WAI_IMP =>
+            {
+                self.state = State::Waiting;
+            },
+            // RMB0-7 and SMB0-7
+            _ if opcode & 0x0f == 0x07 =>
+            {
+                let address = self.get_zp_address();
+                let value = self.read(address);
+                if set
+                {
+                    self.write(address, value | bit);
+                }
+                else
+                {
+                    self.write(address, value & !bit);
+                }
+            },
+            // BBR0-7 and BBS0-7
+            _ if opcode & 0x0f == 0x0f =>
+            {
+                let value = self.get_zp();
+                let target = self.get_rel();
+                if (value & bit == bit) == set
+                {
+                    self.branch(target);
+                }
+            },
+            _ => return false,
         }
-        self.set_negf(self.a, true);
-        self.set_zerof(self.a, true);
+
+        true
     }
 
-    #[inline(always)]
-    fn asl(&mut self, operand : u8) -> u8
+    /// AND with the operand then rotate right, with ADC-like flags
+    ///
+    /// In decimal mode the result is adjusted like a BCD digit pair
+    fn arr(&mut self, operand: u8)
     {
-        let prev_val = operand;
-        let res = prev_val << 1;
+        let value = self.a & operand;
+        let res = (value >> 1) | (self.sr.carry() << 7);
 
-        if prev_val & 0b10000000 == 0b10000000
+        self.sr.set_nz(res);
+
+        if !self.sr.contains(StatusFlags::DECIMAL)
         {
-            self.sr |= Cpu::CarryFlag;
+            self.a = res;
+
+            // C is bit 6, V is bit 6 xor bit 5
+            self.sr.set(StatusFlags::CARRY, res & 0x40 == 0x40);
+            self.sr.set(StatusFlags::OVERFLOW, ((res >> 6) ^ (res >> 5)) & 0x01 == 0x01);
+            return;
         }
-        else
+
+        // V from the change of bit 6 by the rotate
+        self.sr.set(StatusFlags::OVERFLOW, (value ^ res) & 0x40 == 0x40);
+
+        let low = value & 0x0f;
+        let high = value >> 4;
+        let mut res = res;
+
+        if low + (low & 0x01) > 5
         {
-            self.sr &= !Cpu::CarryFlag;
+            res = (res & 0xf0) | (res.wrapping_add(6) & 0x0f);
         }
 
-        self.set_zerof(res, true);
-        self.set_negf(res, true);
+        let carry = high + (high & 0x01) > 5;
+        if carry
+        {
+            res = res.wrapping_add(0x60);
+        }
+        self.sr.set(StatusFlags::CARRY, carry);
 
-        res 
+        self.a = res;
     }
 
     #[inline(always)]
-    fn bit(&mut self, operand: u8)
+    fn adc(&mut self, operand : u8)
+    {
+        if self.sr.contains(StatusFlags::DECIMAL)
+        {
+            self.adc_decimal(operand);
+            return;
+        }
+
+        let sum = u16::from(self.a) + u16::from(operand) + u16::from(self.sr.carry());
+        let res = sum as u8;
+
+        self.sr.set(StatusFlags::CARRY, sum > 0xff);
+        self.set_vflag(self.a, operand, res);
+        self.sr.set_nz(res);
+        self.a = res;
+    }
+
+    /// Decimal mode ADC
+    ///
+    /// A and C hold the BCD sum. As on the NMOS 6502, Z is set from the binary sum
+    /// and N and V from the sum before the high digit is adjusted,
+    /// so they are only meaningful for valid BCD operands.
+    /// The 65C02 sets N and Z from the result and takes an extra cycle
+    ///
+    /// Ref : [Decimal mode](http://www.6502.org/tutorials/decimal_mode.html)
+    fn adc_decimal(&mut self, operand : u8)
     {
-        self.set_negf(operand, true);
+        let carry = u16::from(self.sr.carry());
+        let a = u16::from(self.a);
+        let b = u16::from(operand);
 
-        // Set overflow flag
-        let overflow_flag = (operand << 1) >> 7;
-        if overflow_flag == 0
+        let mut low = (a & 0x0f) + (b & 0x0f) + carry;
+        if low >= 0x0a
         {
-            self.sr |= Cpu::OverFlowFlag;
+            low = ((low + 0x06) & 0x0f) + 0x10;
         }
-        else
+
+        let mut sum = (a & 0xf0) + (b & 0xf0) + low;
+
+        // Intermediate sum as seen by the flag logic, high digits are signed
+        let signed = i16::from((self.a & 0xf0) as i8) + i16::from((operand & 0xf0) as i8) + low as i16;
+
+        if sum >= 0xa0
         {
-            self.sr &= !Cpu::OverFlowFlag;
+            sum += 0x60;
         }
 
-        self.set_zerof(self.a & operand, true);
+        self.sr.set(StatusFlags::NEGATIVE, signed & 0x80 != 0);
+        self.sr.set(StatusFlags::OVERFLOW, !(-128..=127).contains(&signed));
+        self.sr.set(StatusFlags::ZERO, (a + b + carry) as u8 == 0);
+        self.sr.set(StatusFlags::CARRY, sum >= 0x100);
+
+        self.a = sum as u8;
+
+        if self.variant != isa::Variant::Nmos
+        {
+            self.sr.set_nz(self.a);
+            self.extra_cycles += 1;
+        }
     }
 
-    #[inline(always)]
-    fn cmp(&mut self, left : u8, right : u8)
+    /// Decimal mode SBC
+    ///
+    /// A holds the BCD difference, the flags are the same as binary SBC on the NMOS 6502.
+    /// The 65C02 adjusts invalid BCD differently, sets N and Z from the result and
+    /// takes an extra cycle
+    fn sbc_decimal(&mut self, operand : u8)
     {
-        if left < right
+        let borrow = 1 - i16::from(self.sr.carry());
+        let a = i16::from(self.a);
+        let b = i16::from(operand);
+
+        let binary = a - b - borrow;
+        let mut low = (a & 0x0f) - (b & 0x0f) - borrow;
+
+        let diff = match self.variant
         {
-            self.sr |= Cpu::NegFlag;
-            self.sr &= !Cpu::ZeroFlag;
-            self.sr &= !Cpu::CarryFlag;
-        }
-        else if left == right 
+            isa::Variant::Nmos =>
+            {
+                if low < 0
+                {
+                    low = ((low - 0x06) & 0x0f) - 0x10;
+                }
+
+                let mut diff = (a & 0xf0) - (b & 0xf0) + low;
+                if diff < 0
+                {
+                    diff -= 0x60;
+                }
+                diff
+            },
+            isa::Variant::Cmos | isa::Variant::Wdc =>
+            {
+                let mut diff = binary;
+                if diff < 0
+                {
+                    diff -= 0x60;
+                }
+                if low < 0
+                {
+                    diff -= 0x06;
+                }
+                diff
+            },
+        };
+
+        let result = match self.variant
         {
-            self.sr |= Cpu::ZeroFlag;
-            self.sr |= Cpu::CarryFlag;
-            self.sr &= !Cpu::NegFlag;
-        }
-        else if left >= right
+            isa::Variant::Nmos => binary as u8,
+            isa::Variant::Cmos | isa::Variant::Wdc => diff as u8,
+        };
+
+        self.sr.set_nz(result);
+        // A - M overflows like A + !M
+        self.set_vflag(self.a, !operand, binary as u8);
+        self.sr.set(StatusFlags::CARRY, binary >= 0);
+
+        self.a = diff as u8;
+
+        if self.variant != isa::Variant::Nmos
         {
-            self.sr |= Cpu::CarryFlag;
-            self.sr &= !Cpu::NegFlag;
-            self.sr &= !Cpu::ZeroFlag;
+            self.extra_cycles += 1;
         }
     }
 
     #[inline(always)]
-    fn dec(&mut self, operand : u8) -> u8
+    fn asl(&mut self, operand : u8) -> u8
     {
-        let res = operand.wrapping_sub(1);
+        let res = operand << 1;
 
-        self.set_zerof(res, true);
-        self.set_negf(res, true);
+        self.sr.set(StatusFlags::CARRY, operand & 0x80 != 0);
+        self.sr.set_nz(res);
         res
     }
 
+    /// N and V are bits 7 and 6 of the operand, Z is set if A AND operand is zero
     #[inline(always)]
-    fn eor(&mut self, operand: u8)
+    fn bit(&mut self, operand: u8)
     {
-        self.a ^= operand;
-        self.set_zerof(self.a, true);
-        self.set_negf(self.a, true);
+        self.sr.set(StatusFlags::NEGATIVE, operand & 0x80 != 0);
+        self.sr.set(StatusFlags::OVERFLOW, operand & 0x40 != 0);
+        self.sr.set(StatusFlags::ZERO, self.a & operand == 0);
     }
 
+    /// Flags of `left - right`, C is set when there is no borrow
     #[inline(always)]
-    fn inc(&mut self, operand : u8) -> u8
+    fn cmp(&mut self, left : u8, right : u8)
     {
-        let res = operand.wrapping_add(1);
+        self.sr.set(StatusFlags::CARRY, left >= right);
+        self.sr.set_nz(left.wrapping_sub(right));
+    }
+
+    #[inline(always)]
+    fn dec(&mut self, operand : u8) -> u8
+    {
+        let res = operand.wrapping_sub(1);
 
-        self.set_zerof(res, true);
-        self.set_negf(res, true);
+        self.sr.set_nz(res);
         res
     }
 
     #[inline(always)]
-    fn lsr(&mut self, operand : u8) -> u8
+    fn eor(&mut self, operand: u8)
     {
-        let lsb = (operand << 7) >> 7;
-        let res = operand >> 1;
+        self.a ^= operand;
+        self.sr.set_nz(self.a);
+    }
 
-        self.sr &= !Cpu::NegFlag;
+    #[inline(always)]
+    fn inc(&mut self, operand : u8) -> u8
+    {
+        let res = operand.wrapping_add(1);
 
-        if lsb == 0
-        {
-            self.sr &= !Cpu::CarryFlag;
-        }
-        else
-        {
-            self.sr |= Cpu::CarryFlag;
-        }
+        self.sr.set_nz(res);
+        res
+    }
+
+    #[inline(always)]
+    fn lsr(&mut self, operand : u8) -> u8
+    {
+        let res = operand >> 1;
 
-        self.set_zerof(res, true);
+        self.sr.set(StatusFlags::CARRY, operand & 0x01 != 0);
+        self.sr.set_nz(res);
         res
     }
 
@@ -1800,37 +2270,26 @@ impl Cpu
     fn ora(&mut self, operand: u8)
     {
         self.a |= operand;
-        self.set_zerof(self.a, true);
-        self.set_negf(self.a, true);
+        self.sr.set_nz(self.a);
     }
 
     #[inline(always)]
     fn rol(&mut self, operand : u8) -> u8
     {
-        let hsb = operand >> 7;
-
-        let prev_carry = self.sr & Cpu::CarryFlag;
+        let res = (operand << 1) | self.sr.carry();
 
-        let res = (operand << 1) | prev_carry;
-
-        // Set carry
-        self.sr &= hsb;
-        self.set_zerof(res, true);
-        self.set_negf(res, true);
+        self.sr.set(StatusFlags::CARRY, operand & 0x80 != 0);
+        self.sr.set_nz(res);
         res
     }
 
     #[inline(always)]
     fn ror(&mut self, operand : u8) -> u8
     {
-        let lsb = (operand << 7) >> 7;
-        let prev_carry = self.sr & Cpu::CarryFlag;
-
-        let res = (operand >> 1) | (prev_carry << 7);
+        let res = (operand >> 1) | (self.sr.carry() << 7);
 
-        self.sr &= lsb;
-        self.set_zerof(res, true);
-        self.set_negf(res, true);
+        self.sr.set(StatusFlags::CARRY, operand & 0x01 != 0);
+        self.sr.set_nz(res);
         res
     }
 
@@ -1838,39 +2297,370 @@ impl Cpu
     #[inline(always)]
     fn sbc(&mut self, operand : u8)
     {
-        let prev_a = self.a;
-        let option = self.a.checked_add(!operand + 1);
+        if self.sr.contains(StatusFlags::DECIMAL)
+        {
+            self.sbc_decimal(operand);
+            return;
+        }
+
+        // A - M - (1 - C) is A + !M + C
+        self.adc(!operand);
+    }
+
+    /// Keep undo information for the last `limit` instructions, 0 disables the history
+    ///
+    /// Each instruction only keeps the registers and the bytes it overwrote, so memory use
+    /// is bounded by `limit`. Instructions writing to a device register also keep the
+    /// state of the devices (selected banks, latches...), see `Bus::save_devices`
+    pub fn set_history(&mut self, limit: usize)
+    {
+        self.history_limit = limit;
+        while self.history.len() > limit
+        {
+            self.history.pop_front();
+        }
+    }
+
+    /// Number of instructions that can be undone
+    pub fn history_len(&self) -> usize
+    {
+        self.history.len()
+    }
+
+    /// Undo the last instruction or interrupt, returns false if the history is empty
+    pub fn step_back(&mut self) -> bool
+    {
+        let delta = match self.history.pop_back()
+        {
+            Some(delta) => delta,
+            None => return false,
+        };
+
+        for &(address, value) in delta.writes.iter().rev()
+        {
+            self.bus.poke(address, value);
+            if let Some(cache) = &mut self.decode_cache
+            {
+                cache.invalidate(address);
+            }
+        }
+        if let Some(devices) = &delta.devices
+        {
+            self.bus.load_devices(&mut Reader::new(devices)).expect("Device state saved by the history does not load");
+        }
+
+        self.a = delta.a;
+        self.x = delta.x;
+        self.y = delta.y;
+        self.sp = delta.sp;
+        self.pc = delta.pc;
+        self.sr = delta.sr;
+        self.cycles = delta.cycles;
+        self.state = delta.state;
+        self.nmi_pending = delta.nmi_pending;
+
+        true
+    }
+
+    /// Undo instructions until at least `cycles` cycles have been rewound or the
+    /// history is empty, returns the number of cycles rewound
+    pub fn rewind(&mut self, cycles: u64) -> u64
+    {
+        let start = self.cycles;
+        let target = start.saturating_sub(cycles);
+
+        while self.cycles > target && self.step_back() {}
+
+        start - self.cycles
+    }
 
-        self.a = self.a.wrapping_add(!operand) ;
+    /// Execute one instruction, or take a pending interrupt
+    ///
+    /// The instruction is recorded in the history when it is enabled with set_history
+    pub fn step(&mut self) -> Result<StepResult, CpuError>
+    {
+        if self.history_limit == 0
+        {
+            return self.execute_step();
+        }
 
-        if self.sr & Cpu::CarryFlag == Cpu::CarryFlag
+        let mut delta = Delta
+        {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            sp: self.sp,
+            pc: self.pc,
+            sr: self.sr,
+            cycles: self.cycles,
+            state: self.state,
+            nmi_pending: self.nmi_pending,
+            writes: Vec::new(),
+            devices: None,
+        };
+        self.journal.clear();
+        self.devices_journal = None;
+
+        let result = self.execute_step();
+
+        // Illegal opcodes and waiting don't change anything, don't fill the history with them
+        if self.cycles != delta.cycles || self.state != delta.state || !self.journal.is_empty()
         {
-            self.a = self.a.wrapping_add(1) ;
+            delta.writes = std::mem::take(&mut self.journal);
+            delta.devices = self.devices_journal.take();
+            if self.history.len() == self.history_limit
+            {
+                self.history.pop_front();
+            }
+            self.history.push_back(delta);
         }
 
-        match option
+        result
+    }
+
+    fn execute_step(&mut self) -> Result<StepResult, CpuError>
+    {
+        match self.state
         {
-            None =>
+            State::Jammed => return Err(CpuError::Jammed { pc: self.pc }),
+            State::Stopped => return Ok(StepResult::Stopped { cycles: 0 }),
+            // WAI resumes on any interrupt, a masked IRQ continues with the next instruction
+            State::Waiting =>
             {
-                self.sr |= Cpu::CarryFlag;
+                if !self.nmi_pending && !self.irq_line
+                {
+                    return Ok(StepResult::Waiting { cycles: 0 });
+                }
+
+                self.state = State::Running;
             },
-            Some(_) =>
+            State::Running => {},
+        }
+
+        // Interrupts are checked between instructions, NMI has priority
+        if self.nmi_pending
+        {
+            self.nmi_pending = false;
+            self.interrupt(Cpu::NMI_VECTOR, false);
+            self.cycles += u64::from(Cpu::INTERRUPT_CYCLES);
+            return Ok(StepResult::Executed { cycles: Cpu::INTERRUPT_CYCLES });
+        }
+
+        if self.irq_line && !self.sr.contains(StatusFlags::INTERRUPT)
+        {
+            self.interrupt(Cpu::IRQ_VECTOR, false);
+            self.cycles += u64::from(Cpu::INTERRUPT_CYCLES);
+            return Ok(StepResult::Executed { cycles: Cpu::INTERRUPT_CYCLES });
+        }
+
+        let pc = self.pc;
+        let opcode = self.fetch();
+        let entry = &isa::opcodes(self.variant)[usize::from(opcode)];
+
+        if !self.supports(entry)
+        {
+            self.pc = pc;
+            return Err(CpuError::IllegalOpcode { pc, opcode });
+        }
+
+        self.page_crossed = false;
+        self.extra_cycles = 0;
+
+        let executed = match self.variant
+        {
+            isa::Variant::Nmos => self.execute_instruction(opcode),
+            isa::Variant::Cmos => self.execute_cmos(opcode) || self.execute_instruction(opcode),
+            isa::Variant::Wdc =>
             {
-                self.sr &= !Cpu::CarryFlag;
+                self.execute_wdc(opcode) || self.execute_cmos(opcode) || self.execute_instruction(opcode)
+            },
+        };
+
+        self.complete(pc, opcode, executed, entry.cycles, entry.page_penalty)
+    }
+
+    /// Undocumented NMOS opcodes only run when enabled, the 65C02 NOPs always do
+    fn supports(&self, entry: &isa::Opcode) -> bool
+    {
+        entry.is_defined() && !(entry.undocumented && self.variant == isa::Variant::Nmos && !self.undocumented)
+    }
+
+    /// Count the cycles of the instruction at `pc` once it has been executed and tell what happened
+    #[inline(always)]
+    fn complete(&mut self, pc: u16, opcode: u8, executed: bool, cycles: u8, page_penalty: u8) -> Result<StepResult, CpuError>
+    {
+        if !executed
+        {
+            self.pc = pc;
+            return Err(CpuError::IllegalOpcode { pc, opcode });
+        }
+
+        if self.state == State::Jammed
+        {
+            return Err(CpuError::Jammed { pc });
+        }
+
+        let mut cycles = cycles + self.extra_cycles;
+        if self.page_crossed
+        {
+            cycles += page_penalty;
+        }
+
+        self.cycles += u64::from(cycles);
+
+        if opcode == isa::Instruction::BRK_IMP
+        {
+            return Ok(StepResult::Break { pc, cycles });
+        }
+
+        Ok(match self.state
+        {
+            State::Waiting => StepResult::Waiting { cycles },
+            State::Stopped => StepResult::Stopped { cycles },
+            _ => StepResult::Executed { cycles },
+        })
+    }
+
+    /// Execute instructions until at least `cycles` cycles have passed or a step returns
+    /// something else than Executed, returns the result of the last step
+    ///
+    /// Gives the same results as calling step in a loop, but each instruction is decoded
+    /// once and kept until one of its bytes is written, and runs through the execute
+    /// function of its opcode without going through the others. Interrupts, illegal
+    /// opcodes, code the bus can't cache (see Bus::cacheable) and every instruction while
+    /// the history is enabled go through step. Fetches from the cache don't reach the bus,
+    /// so they don't update the open bus value of a MemoryMap
+    ///
+    /// Memory changed without going through the cpu (poking the bus, loading it directly)
+    /// must be followed by flush_decode_cache
+    pub fn run(&mut self, cycles: u64) -> Result<StepResult, CpuError>
+    {
+        self.run_until(cycles, |_| false)
+    }
+
+    /// Same as run, and also stops after an instruction when `stop` returns true, e.g at a breakpoint
+    pub fn run_until(&mut self, cycles: u64, mut stop: impl FnMut(&Cpu<B>) -> bool) -> Result<StepResult, CpuError>
+    {
+        let stale = match &self.decode_cache
+        {
+            Some(cache) => cache.variant != self.variant || cache.undocumented != self.undocumented,
+            None => true,
+        };
+        if stale
+        {
+            self.decode_cache = Some(Box::new(DecodeCache::new(self.variant, self.undocumented)));
+        }
+
+        let end = self.cycles.saturating_add(cycles);
+        let mut result = Ok(StepResult::Executed { cycles: 0 });
+
+        while self.cycles < end
+        {
+            result = self.step_decoded();
+            if !matches!(result, Ok(StepResult::Executed { .. })) || stop(self)
+            {
+                break;
             }
         }
 
-        self.set_negf(self.a, true);
-        self.set_vflag(prev_a,self.a);
-        self.set_zerof(self.a, true);
+        result
+    }
+
+    /// Forget the instructions decoded by run
+    pub fn flush_decode_cache(&mut self)
+    {
+        if let Some(cache) = &mut self.decode_cache
+        {
+            cache.clear();
+        }
     }
 
-    pub fn step(&mut self)
+    /// Execute the instruction at pc from the decode cache, decoding it on first use
+    #[inline(always)]
+    fn step_decoded(&mut self) -> Result<StepResult, CpuError>
     {
-        let opcode = self.mem[self.pc as usize];
-        self.pc += 1;
+        if self.state != State::Running || self.nmi_pending || self.irq_line || self.history_limit > 0
+        {
+            return self.step();
+        }
+
+        let pc = self.pc;
+        let cached = match &self.decode_cache
+        {
+            Some(cache) => cache.get(pc),
+            None => None,
+        };
+        let decoded = match cached.or_else(|| self.decode(pc))
+        {
+            Some(decoded) => decoded,
+            None => return self.step(),
+        };
+
+        self.pc = pc.wrapping_add(1);
+        self.operand = decoded.operand;
+        self.prefetched = true;
+        self.page_crossed = false;
+        self.extra_cycles = 0;
+
+        let executed = (decoded.handler)(self, decoded.opcode);
+        self.prefetched = false;
 
-        self.execute_instruction(opcode);
+        self.complete(pc, decoded.opcode, executed, decoded.cycles, decoded.page_penalty)
+    }
+
+    /// Decode the instruction at `address` into the cache, None if it has to be left to step
+    #[cold]
+    fn decode(&mut self, address: u16) -> Option<Decoded<B>>
+    {
+        let opcode = self.bus.peek(address);
+        let entry = &isa::opcodes(self.variant)[usize::from(opcode)];
+        let len = u16::from(entry.bytes);
+
+        if !self.supports(entry) || !(0..len).all(|offset| self.bus.cacheable(address.wrapping_add(offset)))
+        {
+            return None;
+        }
+
+        let byte = |offset: u16| if offset < len { self.bus.peek(address.wrapping_add(offset)) } else { 0 };
+        let decoded = Decoded
+        {
+            handler: Cpu::handler(self.variant, opcode),
+            opcode,
+            operand: u16::from_le_bytes([byte(1), byte(2)]),
+            len: entry.bytes,
+            cycles: entry.cycles,
+            page_penalty: entry.page_penalty,
+        };
+
+        if let Some(cache) = &mut self.decode_cache
+        {
+            cache.insert(address, decoded);
+        }
+
+        Some(decoded)
+    }
+
+    /// Execute function of `opcode`, the one the chain of execute_step ends up in
+    ///
+    /// The 65C02 adds opcodes, turns the undocumented NMOS ones into NOPs and fixes
+    /// JMP ($xxxx), the WDC 65C02 adds opcodes on top of that
+    fn handler(variant: isa::Variant, opcode: u8) -> Handler<B>
+    {
+        let nmos = &isa::NMOS_OPCODES[usize::from(opcode)];
+        let cmos = &isa::CMOS_OPCODES[usize::from(opcode)];
+        let wdc = &isa::WDC_OPCODES[usize::from(opcode)];
+
+        let changed_by_cmos = !nmos.is_defined() || nmos.undocumented || cmos.undocumented
+            || cmos.mnemonic != nmos.mnemonic || cmos.mode != nmos.mode
+            || opcode == isa::Instruction::JMP_IND;
+
+        match variant
+        {
+            isa::Variant::Wdc if wdc.mnemonic != cmos.mnemonic => Cpu::execute_wdc,
+            isa::Variant::Cmos | isa::Variant::Wdc if changed_by_cmos => Cpu::execute_cmos,
+            isa::Variant::Nmos if nmos.undocumented => Cpu::execute_undocumented,
+            _ => Cpu::execute_instruction,
+        }
     }
 
     #[inline(always)]
@@ -1883,7 +2673,7 @@ impl Cpu
         println!("Y\t{:#4x}\t{:#8b}", self.y, self.x);
         println!("SP\t{:#4x}\t{:#8b}", self.sp, self.sp);
         println!("PC\t{:#4x}", self.pc);
-        println!("ST\t{:08b}\n-------", self.sr);
+        println!("ST\t{:08b}\t{:?}\n-------", self.sr.bits(), self.sr);
     }
 
     #[inline(always)]
@@ -1893,7 +2683,7 @@ impl Cpu
         print!("200: ");
         for i in from..to
         {
-            print!("{:02x} ", self.mem[i]);
+            print!("{:02x} ", self.bus.peek(i as u16));
             if count == 15
             {
                 print!("\n{:04x}: ", i);
@@ -1903,3 +2693,629 @@ impl Cpu
         }
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Cpu on 64 KiB of RAM with `code` at `origin` and pc on its first byte
+    fn cpu_at(origin: u16, code: &[u8]) -> Cpu
+    {
+        let mut cpu = Cpu::default();
+        let start = usize::from(origin);
+        cpu.bus.mem[start..start + code.len()].copy_from_slice(code);
+        cpu.pc = origin;
+        cpu.sp = 0xff;
+        cpu
+    }
+
+    #[test]
+    fn branch_forward_across_a_page()
+    {
+        // $06fd BNE +5, the next instruction is on $06ff so the target is $0704
+        let mut cpu = cpu_at(0x06fd, &[0xd0, 0x05]);
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 4 }));
+        assert_eq!(cpu.pc, 0x0704);
+    }
+
+    #[test]
+    fn branch_backward_across_a_page()
+    {
+        // $0702 BNE -16 lands on $06f4
+        let mut cpu = cpu_at(0x0702, &[0xd0, 0xf0]);
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 4 }));
+        assert_eq!(cpu.pc, 0x06f4);
+    }
+
+    #[test]
+    fn branch_in_the_same_page()
+    {
+        // $0610 BNE -2 loops on itself
+        let mut cpu = cpu_at(0x0610, &[0xd0, 0xfe]);
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 3 }));
+        assert_eq!(cpu.pc, 0x0610);
+    }
+
+    #[test]
+    fn branch_not_taken()
+    {
+        // $06fd BEQ +5 with Z clear goes on with the next instruction
+        let mut cpu = cpu_at(0x06fd, &[0xf0, 0x05]);
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 2 }));
+        assert_eq!(cpu.pc, 0x06ff);
+    }
+
+    #[test]
+    fn branch_wraps_around_memory()
+    {
+        // $fffe BNE +2 goes past $ffff to $0002
+        let mut cpu = cpu_at(0xfffe, &[0xd0, 0x02]);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0002);
+    }
+
+    #[test]
+    fn zero_page_indexing_wraps()
+    {
+        // LDA $f0,X with X = $20 reads $0010, not $0110
+        let mut cpu = cpu_at(0x0600, &[0xb5, 0xf0]);
+        cpu.x = 0x20;
+        cpu.bus.mem[0x0010] = 0x11;
+        cpu.bus.mem[0x0110] = 0x22;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x11);
+
+        // LDX $ff,Y with Y = 2 reads $0001
+        let mut cpu = cpu_at(0x0600, &[0xb6, 0xff]);
+        cpu.y = 0x02;
+        cpu.bus.mem[0x0001] = 0x33;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.x, 0x33);
+    }
+
+    #[test]
+    fn indexed_indirect_adds_x_before_reading_the_pointer()
+    {
+        // LDA ($fe,X) with X = 4 reads the pointer at $02/$03, it holds $1234
+        let mut cpu = cpu_at(0x0600, &[0xa1, 0xfe]);
+        cpu.x = 0x04;
+        cpu.bus.mem[0x0002] = 0x34;
+        cpu.bus.mem[0x0003] = 0x12;
+        cpu.bus.mem[0x1234] = 0x55;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x55);
+    }
+
+    #[test]
+    fn indirect_pointers_wrap_inside_the_zero_page()
+    {
+        // LDA ($ff),Y takes the low byte from $ff and the high byte from $00
+        let mut cpu = cpu_at(0x0600, &[0xb1, 0xff]);
+        cpu.y = 0x01;
+        cpu.bus.mem[0x00ff] = 0x00;
+        cpu.bus.mem[0x0000] = 0x20;
+        cpu.bus.mem[0x0100] = 0x30;
+        cpu.bus.mem[0x2001] = 0x66;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x66);
+
+        // LDA ($ff,X) with X = 0 does the same
+        let mut cpu = cpu_at(0x0600, &[0xa1, 0xff]);
+        cpu.bus.mem[0x00ff] = 0x01;
+        cpu.bus.mem[0x0000] = 0x20;
+        cpu.bus.mem[0x2001] = 0x77;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x77);
+    }
+
+    #[test]
+    fn absolute_indexing_wraps_around_memory()
+    {
+        // LDA $ffff,X with X = 2 reads $0001
+        let mut cpu = cpu_at(0x0600, &[0xbd, 0xff, 0xff]);
+        cpu.x = 0x02;
+        cpu.bus.mem[0x0001] = 0x44;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x44);
+    }
+
+    #[test]
+    fn jmp_indirect_is_little_endian()
+    {
+        // JMP ($0200) with $0200 = $34 and $0201 = $12
+        let mut cpu = cpu_at(0x0600, &[0x6c, 0x00, 0x02]);
+        cpu.bus.mem[0x0200] = 0x34;
+        cpu.bus.mem[0x0201] = 0x12;
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 5 }));
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn jmp_indirect_page_bug()
+    {
+        // JMP ($10ff) takes the high byte from $1000 on the NMOS 6502 and from $1100 on the 65C02
+        let setup = |variant|
+        {
+            let mut cpu = cpu_at(0x0600, &[0x6c, 0xff, 0x10]);
+            cpu.variant = variant;
+            cpu.bus.mem[0x10ff] = 0x34;
+            cpu.bus.mem[0x1000] = 0x12;
+            cpu.bus.mem[0x1100] = 0x56;
+            cpu
+        };
+
+        let mut nmos = setup(isa::Variant::Nmos);
+        nmos.step().unwrap();
+        assert_eq!(nmos.pc, 0x1234);
+
+        let mut cmos = setup(isa::Variant::Cmos);
+        assert_eq!(cmos.step(), Ok(StepResult::Executed { cycles: 6 }));
+        assert_eq!(cmos.pc, 0x5634);
+    }
+
+    /// Cpu running the undocumented NMOS opcodes with `code` at $0600
+    fn nmos_at(code: &[u8]) -> Cpu
+    {
+        let mut cpu = cpu_at(0x0600, code);
+        cpu.undocumented = true;
+        cpu
+    }
+
+    #[test]
+    fn undocumented_loads_and_stores()
+    {
+        // LAX $10 loads A and X
+        let mut cpu = nmos_at(&[0xa7, 0x10]);
+        cpu.bus.mem[0x10] = 0x80;
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 3 }));
+        assert_eq!((cpu.a, cpu.x), (0x80, 0x80));
+        assert!(cpu.sr.contains(StatusFlags::NEGATIVE));
+
+        // SAX $10 stores A & X without changing the flags
+        let mut cpu = nmos_at(&[0x87, 0x10]);
+        cpu.a = 0xf0;
+        cpu.x = 0x3c;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x10], 0x30);
+        assert!(!cpu.sr.contains(StatusFlags::ZERO));
+    }
+
+    #[test]
+    fn undocumented_read_modify_write()
+    {
+        // DCP $10 decrements memory then compares it with A
+        let mut cpu = nmos_at(&[0xc7, 0x10]);
+        cpu.bus.mem[0x10] = 0x05;
+        cpu.a = 0x04;
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 5 }));
+        assert_eq!(cpu.bus.mem[0x10], 0x04);
+        assert!(cpu.sr.contains(StatusFlags::ZERO | StatusFlags::CARRY));
+
+        // ISC $10 increments memory then subtracts it from A
+        let mut cpu = nmos_at(&[0xe7, 0x10]);
+        cpu.bus.mem[0x10] = 0x01;
+        cpu.a = 0x05;
+        cpu.sr.insert(StatusFlags::CARRY);
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x10], 0x02);
+        assert_eq!(cpu.a, 0x03);
+        assert!(cpu.sr.contains(StatusFlags::CARRY));
+
+        // SLO $10 shifts memory left then ORs it into A
+        let mut cpu = nmos_at(&[0x07, 0x10]);
+        cpu.bus.mem[0x10] = 0x81;
+        cpu.a = 0x01;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x10], 0x02);
+        assert_eq!(cpu.a, 0x03);
+        assert!(cpu.sr.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn undocumented_immediates()
+    {
+        // ANC #$80 ands and copies N to C
+        let mut cpu = nmos_at(&[0x0b, 0x80]);
+        cpu.a = 0xff;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.sr.contains(StatusFlags::NEGATIVE | StatusFlags::CARRY));
+
+        // ALR #$03 ands then shifts right
+        let mut cpu = nmos_at(&[0x4b, 0x03]);
+        cpu.a = 0xff;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.sr.contains(StatusFlags::CARRY));
+
+        // SBX #$02 puts (A & X) - 2 in X
+        let mut cpu = nmos_at(&[0xcb, 0x02]);
+        cpu.a = 0x0f;
+        cpu.x = 0xf3;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.x, 0x01);
+        assert!(cpu.sr.contains(StatusFlags::CARRY));
+    }
+
+    #[test]
+    fn jam_locks_up_the_cpu()
+    {
+        let mut cpu = nmos_at(&[0x02]);
+
+        assert_eq!(cpu.step(), Err(CpuError::Jammed { pc: 0x0600 }));
+        assert_eq!(cpu.state, State::Jammed);
+        assert_eq!(cpu.step(), Err(CpuError::Jammed { pc: 0x0600 }));
+
+        cpu.reset();
+        assert_eq!(cpu.state, State::Running);
+    }
+
+    #[test]
+    fn undocumented_opcodes_are_illegal_unless_enabled()
+    {
+        let mut cpu = cpu_at(0x0600, &[0xa7, 0x10]);
+
+        assert_eq!(cpu.step(), Err(CpuError::IllegalOpcode { pc: 0x0600, opcode: 0xa7 }));
+        assert_eq!(cpu.pc, 0x0600);
+    }
+
+    /// 65C02 with `code` at $0600
+    fn cmos_at(code: &[u8]) -> Cpu
+    {
+        let mut cpu = cpu_at(0x0600, code);
+        cpu.variant = isa::Variant::Cmos;
+        cpu
+    }
+
+    #[test]
+    fn cmos_bra_and_stz()
+    {
+        // BRA +2, STZ $10 is skipped, STZ $1234
+        let mut cpu = cmos_at(&[0x80, 0x02, 0x64, 0x10, 0x9c, 0x34, 0x12]);
+        cpu.bus.mem[0x10] = 0xff;
+        cpu.bus.mem[0x1234] = 0xff;
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 3 }));
+        assert_eq!(cpu.pc, 0x0604);
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x1234], 0x00);
+        assert_eq!(cpu.bus.mem[0x10], 0xff);
+    }
+
+    #[test]
+    fn cmos_push_and_pull_index_registers()
+    {
+        // PHX, PLY
+        let mut cpu = cmos_at(&[0xda, 0x7a]);
+        cpu.x = 0x80;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.sp, 0xfe);
+        cpu.step().unwrap();
+        assert_eq!(cpu.y, 0x80);
+        assert_eq!(cpu.sp, 0xff);
+        assert!(cpu.sr.contains(StatusFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn cmos_test_and_set_or_reset_bits()
+    {
+        // TSB $10, TRB $11, Z is set from A & memory before the change
+        let mut cpu = cmos_at(&[0x04, 0x10, 0x14, 0x11]);
+        cpu.a = 0x0f;
+        cpu.bus.mem[0x10] = 0xf0;
+        cpu.bus.mem[0x11] = 0xff;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x10], 0xff);
+        assert!(cpu.sr.contains(StatusFlags::ZERO));
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x11], 0xf0);
+        assert!(!cpu.sr.contains(StatusFlags::ZERO));
+    }
+
+    #[test]
+    fn cmos_accumulator_increment_and_bit_immediate()
+    {
+        // INC A, DEC A, DEC A, BIT #$40 only changes Z
+        let mut cpu = cmos_at(&[0x1a, 0x3a, 0x3a, 0x89, 0x40]);
+        cpu.a = 0x00;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0x01);
+        cpu.step().unwrap();
+        assert!(cpu.sr.contains(StatusFlags::ZERO));
+        cpu.step().unwrap();
+        assert_eq!(cpu.a, 0xff);
+        assert!(cpu.sr.contains(StatusFlags::NEGATIVE));
+
+        cpu.step().unwrap();
+        assert!(!cpu.sr.contains(StatusFlags::ZERO));
+        assert!(cpu.sr.contains(StatusFlags::NEGATIVE));
+        assert!(!cpu.sr.contains(StatusFlags::OVERFLOW));
+    }
+
+    #[test]
+    fn cmos_zero_page_indirect()
+    {
+        // LDA ($10), STA ($12)
+        let mut cpu = cmos_at(&[0xb2, 0x10, 0x92, 0x12]);
+        cpu.bus.mem[0x10..0x14].copy_from_slice(&[0x00, 0x20, 0x00, 0x30]);
+        cpu.bus.mem[0x2000] = 0x5a;
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 5 }));
+        assert_eq!(cpu.a, 0x5a);
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x3000], 0x5a);
+    }
+
+    #[test]
+    fn cmos_opcodes_are_illegal_on_the_nmos_6502()
+    {
+        // $b2 is LDA (zp) on the 65C02 and an undocumented JAM on the NMOS 6502
+        let mut cpu = cpu_at(0x0600, &[0xb2, 0x10]);
+
+        assert_eq!(cpu.step(), Err(CpuError::IllegalOpcode { pc: 0x0600, opcode: 0xb2 }));
+    }
+
+    /// WDC 65C02 with `code` at $0600
+    fn wdc_at(code: &[u8]) -> Cpu
+    {
+        let mut cpu = cpu_at(0x0600, code);
+        cpu.variant = isa::Variant::Wdc;
+        cpu
+    }
+
+    #[test]
+    fn wdc_reset_and_set_memory_bits()
+    {
+        // RMB3 $10, SMB0 $10
+        let mut cpu = wdc_at(&[0x37, 0x10, 0x87, 0x10]);
+        cpu.bus.mem[0x10] = 0xff;
+
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 5 }));
+        assert_eq!(cpu.bus.mem[0x10], 0xf7);
+
+        cpu.bus.mem[0x10] = 0x00;
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.mem[0x10], 0x01);
+    }
+
+    #[test]
+    fn wdc_branch_on_memory_bits()
+    {
+        // BBR0 $10,+3 is taken when bit 0 is clear, the offset is from the end of the instruction
+        let mut cpu = wdc_at(&[0x0f, 0x10, 0x03]);
+        cpu.bus.mem[0x10] = 0xfe;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0606);
+
+        // BBS7 $10,-3 is not taken when bit 7 is clear
+        let mut cpu = wdc_at(&[0xff, 0x10, 0xfd]);
+        cpu.bus.mem[0x10] = 0x7f;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0603);
+
+        // and is taken when it is set, back to the BBS7 itself
+        cpu.pc = 0x0600;
+        cpu.bus.mem[0x10] = 0x80;
+
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0600);
+    }
+
+    #[test]
+    fn wai_waits_for_an_interrupt()
+    {
+        // WAI then NOP, the IRQ handler at $0700 is a NOP
+        let mut cpu = wdc_at(&[0xcb, 0xea]);
+        cpu.bus.mem[0xfffe..].copy_from_slice(&[0x00, 0x07]);
+        cpu.bus.mem[0x0700] = 0xea;
+
+        assert!(matches!(cpu.step(), Ok(StepResult::Waiting { .. })));
+        assert_eq!(cpu.state, State::Waiting);
+        assert_eq!(cpu.step(), Ok(StepResult::Waiting { cycles: 0 }));
+
+        cpu.irq(true);
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 7 }));
+        assert_eq!(cpu.state, State::Running);
+        assert_eq!(cpu.pc, 0x0700);
+    }
+
+    #[test]
+    fn wai_continues_after_a_masked_irq()
+    {
+        let mut cpu = wdc_at(&[0xcb, 0xea]);
+        cpu.sr.insert(StatusFlags::INTERRUPT);
+
+        cpu.step().unwrap();
+        cpu.irq(true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0602);
+    }
+
+    #[test]
+    fn stp_stops_until_reset()
+    {
+        let mut cpu = wdc_at(&[0xdb, 0xea]);
+        cpu.bus.mem[0xfffc..0xfffe].copy_from_slice(&[0x01, 0x06]);
+
+        assert!(matches!(cpu.step(), Ok(StepResult::Stopped { .. })));
+        cpu.irq(true);
+        assert_eq!(cpu.step(), Ok(StepResult::Stopped { cycles: 0 }));
+        assert_eq!(cpu.pc, 0x0601);
+
+        cpu.reset();
+        assert_eq!(cpu.state, State::Running);
+        assert_eq!(cpu.step(), Ok(StepResult::Executed { cycles: 2 }));
+    }
+
+    #[test]
+    fn step_reports_executed_instructions_and_brk()
+    {
+        // LDA #1, BRK
+        let mut cpu = cpu_at(0x0600, &[0xa9, 0x01, 0x00]);
+
+        let result = cpu.step();
+        assert_eq!(result, Ok(StepResult::Executed { cycles: 2 }));
+        assert_eq!(result.unwrap().cycles(), 2);
+        assert_eq!(cpu.step(), Ok(StepResult::Break { pc: 0x0602, cycles: 7 }));
+        assert_eq!(cpu.cycles, 9);
+    }
+
+    #[test]
+    fn illegal_opcode_leaves_pc_on_it()
+    {
+        let mut cpu = cpu_at(0x0600, &[0x03]);
+
+        let error = CpuError::IllegalOpcode { pc: 0x0600, opcode: 0x03 };
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!(cpu.step(), Err(error));
+        assert_eq!((cpu.pc, cpu.cycles), (0x0600, 0));
+        assert_eq!(error.to_string(), "Illegal opcode 0x03 at 0x0600");
+        assert_eq!(CpuError::Jammed { pc: 0x1234 }.to_string(), "Cpu jammed at 0x1234");
+    }
+
+    #[test]
+    fn every_opcode_steps_without_panicking()
+    {
+        for variant in [isa::Variant::Nmos, isa::Variant::Cmos, isa::Variant::Wdc]
+        {
+            for undocumented in [false, true]
+            {
+                for opcode in 0..=255
+                {
+                    let mut cpu = cpu_at(0x0600, &[opcode, 0xff, 0xff]);
+                    cpu.variant = variant;
+                    cpu.undocumented = undocumented;
+
+                    // Only opcodes missing from the table of the variant may be illegal
+                    let defined = isa::opcodes(variant)[usize::from(opcode)].is_defined();
+                    match cpu.step()
+                    {
+                        Err(CpuError::IllegalOpcode { .. }) => assert!(!defined || !undocumented, "{:?} {:#04x} is illegal", variant, opcode),
+                        Err(CpuError::Jammed { .. }) | Ok(_) => {},
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn step_back_undoes_registers_and_memory()
+    {
+        // LDA #$42, STA $10, INC $10
+        let mut cpu = cpu_at(0x0600, &[0xa9, 0x42, 0x85, 0x10, 0xe6, 0x10]);
+        cpu.bus.mem[0x10] = 0x07;
+        cpu.set_history(10);
+
+        for _ in 0..3
+        {
+            cpu.step().unwrap();
+        }
+        assert_eq!((cpu.bus.mem[0x10], cpu.history_len()), (0x43, 3));
+
+        assert!(cpu.step_back());
+        assert_eq!((cpu.pc, cpu.bus.mem[0x10]), (0x0604, 0x42));
+        assert!(cpu.step_back());
+        assert_eq!((cpu.pc, cpu.bus.mem[0x10]), (0x0602, 0x07));
+        assert!(cpu.step_back());
+        assert_eq!((cpu.pc, cpu.a, cpu.cycles), (0x0600, 0x00, 0));
+        assert!(!cpu.step_back());
+    }
+
+    #[test]
+    fn history_is_bounded()
+    {
+        // INX forever
+        let mut cpu = cpu_at(0x0600, &[0xe8, 0x4c, 0x00, 0x06]);
+        cpu.set_history(4);
+
+        for _ in 0..100
+        {
+            cpu.step().unwrap();
+        }
+        assert_eq!(cpu.history_len(), 4);
+
+        cpu.set_history(2);
+        assert_eq!(cpu.history_len(), 2);
+
+        cpu.set_history(0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.history_len(), 0);
+    }
+
+    #[test]
+    fn rewind_goes_back_by_cycles()
+    {
+        // NOP x 8, 2 cycles each
+        let mut cpu = cpu_at(0x0600, &[0xea; 8]);
+        cpu.set_history(100);
+
+        for _ in 0..8
+        {
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(cpu.rewind(5), 6);
+        assert_eq!((cpu.pc, cpu.cycles), (0x0605, 10));
+        assert_eq!(cpu.rewind(1000), 10);
+        assert_eq!(cpu.pc, 0x0600);
+    }
+
+    #[test]
+    fn failed_steps_are_not_recorded()
+    {
+        let mut cpu = cpu_at(0x0600, &[0x03]);
+        cpu.set_history(10);
+
+        assert!(cpu.step().is_err());
+        assert_eq!(cpu.history_len(), 0);
+    }
+
+    #[test]
+    fn step_back_restores_device_state()
+    {
+        use crate::mapper::MapperKind;
+
+        // LDA #1, STA $8000 selects bank 1 of the latch mapper
+        let mut ram = Ram::default();
+        ram.mem[0x0600..0x0605].copy_from_slice(&[0xa9, 0x01, 0x8d, 0x00, 0x80]);
+        let image = [vec![0; 0x2000], vec![1; 0x2000]].concat();
+
+        let mut cpu = Cpu::new(MapperKind::Latch.build(ram, image));
+        cpu.pc = 0x0600;
+        cpu.set_history(10);
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.bus.peek(0x8000), 1);
+
+        assert!(cpu.step_back());
+        assert_eq!(cpu.bus.slots()[0].bank, 0);
+        assert_eq!(cpu.bus.peek(0x8000), 0);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::isa::Variant;
+
+/// Executes a decoded opcode, returns false if the opcode is not supported
+pub type Handler<B> = fn(&mut Cpu<B>, u8) -> bool;
+
+/// An instruction decoded once and run from the cache until its bytes are written
+pub struct Decoded<B: Bus>
+{
+    /// Execute function of the opcode, picked from the dispatch table of the variant
+    pub handler: Handler<B>,
+    pub opcode: u8,
+    /// Operand bytes, little-endian, handed to the instruction instead of fetching them again
+    pub operand: u16,
+    /// Length in bytes, opcode included
+    pub len: u8,
+    pub cycles: u8,
+    pub page_penalty: u8,
+}
+
+// Derived Clone and Copy would require B to be Copy
+impl<B: Bus> Clone for Decoded<B>
+{
+    fn clone(&self) -> Decoded<B>
+    {
+        *self
+    }
+}
+
+impl<B: Bus> Copy for Decoded<B> {}
+
+/// Decoded instructions by address
+pub struct DecodeCache<B: Bus>
+{
+    /// Variant and undocumented opcode setting the instructions were decoded for
+    pub variant: Variant,
+    pub undocumented: bool,
+    entries: Vec<Option<Decoded<B>>>,
+    /// Addresses that held a byte of a cached instruction since the last clear,
+    /// writes anywhere else don't need to look at the entries
+    code: Vec<bool>,
+}
+
+impl<B: Bus> DecodeCache<B>
+{
+    pub fn new(variant: Variant, undocumented: bool) -> DecodeCache<B>
+    {
+        DecodeCache
+        {
+            variant,
+            undocumented,
+            entries: vec![None; 0x10000],
+            code: vec![false; 0x10000],
+        }
+    }
+
+    /// Instruction decoded at `address`
+    #[inline(always)]
+    pub fn get(&self, address: u16) -> Option<Decoded<B>>
+    {
+        self.entries[usize::from(address)]
+    }
+
+    pub fn insert(&mut self, address: u16, decoded: Decoded<B>)
+    {
+        for offset in 0..decoded.len
+        {
+            self.code[usize::from(address.wrapping_add(u16::from(offset)))] = true;
+        }
+
+        self.entries[usize::from(address)] = Some(decoded);
+    }
+
+    /// Forget the instructions that have a byte at `address`, called on every write
+    ///
+    /// Instructions are at most 3 bytes long, only the 3 entries ending at `address` can hold it
+    #[inline(always)]
+    pub fn invalidate(&mut self, address: u16)
+    {
+        if !self.code[usize::from(address)]
+        {
+            return;
+        }
+
+        for offset in 0..3
+        {
+            let start = usize::from(address.wrapping_sub(offset));
+            if self.entries[start].is_some_and(|decoded| u16::from(decoded.len) > offset)
+            {
+                self.entries[start] = None;
+            }
+        }
+    }
+
+    /// Forget every instruction
+    pub fn clear(&mut self)
+    {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+        self.code.iter_mut().for_each(|byte| *byte = false);
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bus::Ram;
+    use crate::cpu::StepResult;
+
+    /// Cpu with `code` at $0600 and pc on its first byte
+    fn cpu_at(code: &[u8]) -> Cpu
+    {
+        let mut cpu = Cpu::default();
+        cpu.bus.mem[0x0600..0x0600 + code.len()].copy_from_slice(code);
+        cpu.pc = 0x0600;
+        cpu.sp = 0xff;
+        cpu
+    }
+
+    // LDX #3, loop: LDA #0, INC loop+1, DEX, BNE loop, BRK
+    const SELF_MODIFYING: [u8; 11] = [0xa2, 0x03, 0xa9, 0x00, 0xee, 0x03, 0x06, 0xca, 0xd0, 0xf8, 0x00];
+
+    #[test]
+    fn writes_to_code_invalidate_it()
+    {
+        let mut cpu = cpu_at(&SELF_MODIFYING);
+
+        assert_eq!(cpu.run(10_000), Ok(StepResult::Break { pc: 0x060a, cycles: 7 }));
+        assert_eq!(cpu.a, 2);
+        assert_eq!(cpu.bus.mem[0x0603], 3);
+    }
+
+    #[test]
+    fn run_matches_step()
+    {
+        let mut stepped = cpu_at(&SELF_MODIFYING);
+        while let Ok(StepResult::Executed { .. }) = stepped.step() {}
+
+        let mut run = cpu_at(&SELF_MODIFYING);
+        run.run(10_000).unwrap();
+
+        assert_eq!((run.a, run.x, run.pc, run.sr, run.cycles), (stepped.a, stepped.x, stepped.pc, stepped.sr, stepped.cycles));
+    }
+
+    #[test]
+    fn poked_code_needs_a_flush()
+    {
+        // LDA #1, BRK
+        let mut cpu = cpu_at(&[0xa9, 0x01, 0x00]);
+        cpu.run(100).unwrap();
+
+        cpu.bus.mem[0x0601] = 0x02;
+        cpu.pc = 0x0600;
+        cpu.flush_decode_cache();
+        cpu.run(100).unwrap();
+        assert_eq!(cpu.a, 2);
+    }
+
+    #[test]
+    fn run_until_stops_after_the_instruction()
+    {
+        // INX forever
+        let mut cpu = cpu_at(&[0xe8, 0x4c, 0x00, 0x06]);
+
+        let result = cpu.run_until(u64::MAX, |cpu| cpu.x == 5);
+        assert_eq!(result, Ok(StepResult::Executed { cycles: 2 }));
+        assert_eq!((cpu.x, cpu.pc), (5, 0x0601));
+
+        // The cycle budget stops it too
+        let start = cpu.cycles;
+        cpu.run(100).unwrap();
+        assert!(cpu.cycles - start >= 100 && cpu.cycles - start < 105);
+    }
+
+    #[test]
+    fn invalidate_forgets_instructions_covering_the_address()
+    {
+        let decoded = Decoded::<Ram> { handler: |_, _| true, opcode: 0xad, operand: 0x1234, len: 3, cycles: 4, page_penalty: 0 };
+        let mut cache = DecodeCache::new(Variant::Nmos, false);
+
+        cache.insert(0x10, decoded);
+        cache.invalidate(0x13);
+        assert!(cache.get(0x10).is_some());
+        cache.invalidate(0x12);
+        assert!(cache.get(0x10).is_none());
+
+        cache.insert(0x10, decoded);
+        cache.clear();
+        assert!(cache.get(0x10).is_none());
+    }
+}
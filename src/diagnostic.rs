@@ -0,0 +1,80 @@
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Severity
+{
+    /// The program can't be assembled
+    Error,
+    /// The program is assembled but probably doesn't do what was meant
+    #[allow(dead_code)]
+    Warning,
+}
+
+impl fmt::Display for Severity
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A problem found by the assembler, lines and columns start at 1
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic
+{
+    pub severity: Severity,
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+    pub message: String,
+}
+
+impl Diagnostic
+{
+    /// The diagnostic followed by the line it points at, the way rustc prints them
+    ///
+    /// ```text
+    /// error: Branch target $0700 out of range
+    ///   --> bcd.asm:12:11
+    ///    |
+    /// 12 |       bne far
+    ///    |           ^^^
+    /// ```
+    pub fn render(&self, source: &str) -> String
+    {
+        let mut out = format!("{}: {}\n", self.severity, self.message);
+
+        let number = self.line.to_string();
+        let margin = " ".repeat(number.len());
+        out += &format!("{}--> {}:{}:{}\n", margin, self.file, self.line, self.column);
+
+        let text = match source.lines().nth((self.line as usize).saturating_sub(1))
+        {
+            Some(text) => text,
+            None => return out,
+        };
+
+        // Tabs are kept so the carets line up with the text above them
+        let start = (self.column as usize).saturating_sub(1);
+        let indent: String = text.chars().take(start).map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+        let underline = text.chars().skip(start).take_while(|c| !c.is_whitespace() && *c != ',' && *c != ';').count();
+
+        out += &format!("{} |\n", margin);
+        out += &format!("{} | {}\n", number, text);
+        out += &format!("{} | {}{}\n", margin, indent, "^".repeat(underline.max(1)));
+        out
+    }
+}
+
+/// One line, `file:line:column: severity: message`
+impl fmt::Display for Diagnostic
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{}:{}:{}: {}: {}", self.file, self.line, self.column, self.severity, self.message)
+    }
+}
@@ -0,0 +1,75 @@
+use crate::isa::{self, Mode, Variant};
+
+/// Text of the instruction at `address` and its length in bytes, `read` returns the byte at an address
+///
+/// The syntax is the one e6502c assembles, branch targets are absolute addresses
+/// and undefined opcodes become DCB
+pub fn disassemble(variant: Variant, address: u16, read: impl Fn(u16) -> u8) -> (String, u8)
+{
+    let opcode = read(address);
+    let entry = &isa::opcodes(variant)[usize::from(opcode)];
+
+    if !entry.is_defined()
+    {
+        return (format!("DCB ${:02x}", opcode), 1);
+    }
+
+    let byte = |offset: u16| read(address.wrapping_add(offset));
+    let word = |offset: u16| u16::from_le_bytes([byte(offset), byte(offset + 1)]);
+    // Branch offsets are relative to the next instruction
+    let target = |offset: u8, len: u16| address.wrapping_add(len).wrapping_add(offset as i8 as u16);
+
+    let operand = match entry.mode
+    {
+        Mode::IMP => String::new(),
+        Mode::ACC => "A".to_string(),
+        Mode::IMM => format!("#${:02x}", byte(1)),
+        Mode::ZP => format!("${:02x}", byte(1)),
+        Mode::ZPX => format!("${:02x},X", byte(1)),
+        Mode::ZPY => format!("${:02x},Y", byte(1)),
+        Mode::ZPI => format!("(${:02x})", byte(1)),
+        Mode::INDX => format!("(${:02x},X)", byte(1)),
+        Mode::INDY => format!("(${:02x}),Y", byte(1)),
+        Mode::ABS => format!("${:04x}", word(1)),
+        Mode::ABSX => format!("${:04x},X", word(1)),
+        Mode::ABSY => format!("${:04x},Y", word(1)),
+        Mode::IND => format!("(${:04x})", word(1)),
+        Mode::ABSINDX => format!("(${:04x},X)", word(1)),
+        Mode::REL => format!("${:04x}", target(byte(1), 2)),
+        Mode::ZPREL => format!("${:02x},${:04x}", byte(1), target(byte(2), 3)),
+        _ => String::new(),
+    };
+
+    let text = if operand.is_empty() { entry.mnemonic.to_string() } else { format!("{} {}", entry.mnemonic, operand) };
+
+    (text, entry.bytes)
+}
+
+/// Listing of `code` loaded at `origin`, one line per instruction with its address and bytes
+///
+/// An instruction cut short by the end of the code is listed as DCB
+pub fn listing(variant: Variant, code: &[u8], origin: u16) -> Vec<String>
+{
+    let mut lines = vec![];
+    let mut offset = 0;
+
+    while offset < code.len()
+    {
+        let address = origin.wrapping_add(offset as u16);
+        let read = |at: u16| code.get(usize::from(at.wrapping_sub(origin))).copied().unwrap_or(0);
+        let (mut text, mut len) = disassemble(variant, address, read);
+
+        if offset + usize::from(len) > code.len()
+        {
+            text = format!("DCB ${:02x}", code[offset]);
+            len = 1;
+        }
+
+        let bytes: Vec<String> = code[offset..offset + usize::from(len)].iter().map(|b| format!("{:02x}", b)).collect();
+        lines.push(format!("${:04x}  {:<9} {}", address, bytes.join(" "), text));
+
+        offset += usize::from(len);
+    }
+
+    lines
+}
@@ -0,0 +1,140 @@
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+/// First byte of the screen, one byte per pixel row by row, the low 4 bits pick the color
+pub const SCREEN_ADDRESS: u16 = 0x200;
+pub const SCREEN_WIDTH: u32 = 32;
+pub const SCREEN_HEIGHT: u32 = 32;
+
+/// How the screen is scaled to the window, both keep the pixels square
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Scaling
+{
+    /// Largest whole multiple of the screen size that fits, sharp pixels
+    #[default]
+    Integer,
+    /// Fill the window, filtered
+    Smooth,
+}
+
+#[inline(always)]
+fn get_pixel_color(value : u8) -> Color
+{
+    match value & 0b00001111
+    {
+        0 =>  Color::RGB(0, 0, 0),        // Black
+        1 =>  Color::RGB(255, 255, 255),  // White
+        2 =>  Color::RGB(255, 0, 0),      // Red
+        3 =>  Color::RGB(0, 255, 255),    // Cyan
+        4 =>  Color::RGB(128, 0, 128),    // Purple
+        5 =>  Color::RGB(0, 255, 0),      // Green
+        6 =>  Color::RGB(0, 0, 255),      // Blue
+        7 =>  Color::RGB(255, 255, 0),    // Yellow
+        8 =>  Color::RGB(255, 165, 0),    // Orange
+        9 =>  Color::RGB(165, 42, 42),    // Brown
+        10 => Color::RGB(255, 119, 119),  // Light Red
+        11 => Color::RGB(169,169, 169),   // Dark Grey
+        12 => Color::RGB(128, 128, 128),  // Grey
+        13 => Color::RGB(144, 238, 144),  // Light green
+        14 => Color::RGB(173, 216, 230),  // Light blue
+        15 => Color::RGB(211, 211, 211),  // Light grey
+        _ =>  Color::RGB(0, 0, 0),
+    }
+}
+
+/// The 32x32 screen, uploaded to a streaming texture once per frame and scaled to the window
+pub struct Display<'a>
+{
+    creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
+    /// RGB24 pixels of the screen
+    pixels: Vec<u8>,
+    scaling: Scaling,
+}
+
+impl<'a> Display<'a>
+{
+    pub fn new(creator: &'a TextureCreator<WindowContext>, scaling: Scaling) -> Result<Display<'a>, String>
+    {
+        Ok(Display
+        {
+            creator,
+            texture: Display::create_texture(creator, scaling)?,
+            pixels: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT * 3) as usize],
+            scaling,
+        })
+    }
+
+    /// SDL picks the filtering of a texture when it is created
+    fn create_texture(creator: &'a TextureCreator<WindowContext>, scaling: Scaling) -> Result<Texture<'a>, String>
+    {
+        let quality = match scaling
+        {
+            Scaling::Integer => "nearest",
+            Scaling::Smooth => "linear",
+        };
+        sdl2::hint::set("SDL_RENDER_SCALE_QUALITY", quality);
+
+        creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH, SCREEN_HEIGHT)
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn scaling(&self) -> Scaling
+    {
+        self.scaling
+    }
+
+    pub fn set_scaling(&mut self, scaling: Scaling) -> Result<(), String>
+    {
+        if scaling != self.scaling
+        {
+            self.texture = Display::create_texture(self.creator, scaling)?;
+            self.scaling = scaling;
+        }
+
+        Ok(())
+    }
+
+    /// Area of a `width` x `height` window the screen is drawn to, centered
+    fn destination(&self, width: u32, height: u32) -> Rect
+    {
+        let fit = (width / SCREEN_WIDTH).min(height / SCREEN_HEIGHT);
+        let (w, h) = match self.scaling
+        {
+            // Windows smaller than the screen still get something
+            Scaling::Integer if fit > 0 => (SCREEN_WIDTH * fit, SCREEN_HEIGHT * fit),
+            _ =>
+            {
+                let scale = (width as f32 / SCREEN_WIDTH as f32).min(height as f32 / SCREEN_HEIGHT as f32);
+                ((SCREEN_WIDTH as f32 * scale) as u32, (SCREEN_HEIGHT as f32 * scale) as u32)
+            },
+        };
+
+        Rect::new(((width - w) / 2) as i32, ((height - h) / 2) as i32, w.max(1), h.max(1))
+    }
+
+    /// Draw the screen, `read` returns the byte at an address without side effects
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>, read: impl Fn(u16) -> u8) -> Result<(), String>
+    {
+        for (i, pixel) in self.pixels.chunks_exact_mut(3).enumerate()
+        {
+            let color = get_pixel_color(read(SCREEN_ADDRESS + i as u16));
+            pixel.copy_from_slice(&[color.r, color.g, color.b]);
+        }
+
+        self.texture
+            .update(None, &self.pixels, (SCREEN_WIDTH * 3) as usize)
+            .map_err(|e| e.to_string())?;
+
+        let (width, height) = canvas.output_size()?;
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.copy(&self.texture, None, self.destination(width, height))?;
+        canvas.present();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+use std::num::IntErrorKind;
+use std::ops::Range;
+
+/// Part of an expression
+#[derive(Clone, PartialEq, Debug)]
+enum Item
+{
+    Number(i32),
+    Name(String),
+    /// Operators and parentheses, `*` is the current address or a multiplication
+    Symbol(&'static str),
+}
+
+/// Operators and parentheses, the ones starting with another are listed first
+const SYMBOLS: [&str; 14] = ["<<", ">>", "<", ">", "+", "-", "*", "/", "&", "|", "^", "~", "(", ")"];
+
+/// Split `text` into numbers, names and operators, each with where it is written in `text`
+fn items(text: &str) -> Result<Vec<(Item, Range<usize>)>, String>
+{
+    let mut items = vec![];
+    let mut rest = text.trim_start();
+
+    while let Some(c) = rest.chars().next()
+    {
+        let start = text.len() - rest.len();
+        // The end is known once the item is read
+        let mut item = |item: Item| items.push((item, start..start));
+
+        if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol))
+        {
+            item(Item::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+        else if c == '\''
+        {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next())
+            {
+                (Some(value), Some('\'')) =>
+                {
+                    item(Item::Number(value as i32));
+                    rest = &rest[1 + value.len_utf8() + 1..];
+                },
+                _ => return Err("Expected a character between single quotes".to_string()),
+            }
+        }
+        else if c == '$' || c == '%' || c.is_ascii_digit()
+        {
+            let (radix, digits) = match c
+            {
+                '$' => (16, &rest[1..]),
+                '%' => (2, &rest[1..]),
+                _ => (10, rest),
+            };
+            let len = digits.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(digits.len());
+            let text = &digits[..len];
+
+            let written = &rest[..rest.len() - digits.len() + len];
+            match i32::from_str_radix(text, radix)
+            {
+                Ok(value) if value <= 0xffff => item(Item::Number(value)),
+                Ok(_) => return Err(format!("{} does not fit in two bytes", written)),
+                Err(e) if *e.kind() == IntErrorKind::PosOverflow => return Err(format!("{} does not fit in two bytes", written)),
+                Err(_) if text.is_empty() => return Err(format!("Expected a number after {}", c)),
+                Err(_) => return Err(format!("Invalid number {:?}", written)),
+            }
+            rest = &digits[len..];
+        }
+        else if c.is_alphabetic() || c == '_' || c == '@' || c == '.'
+        {
+            let len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '@' || c == '.')).unwrap_or(rest.len());
+            item(Item::Name(rest[..len].to_string()));
+            rest = &rest[len..];
+        }
+        else
+        {
+            return Err(format!("Unexpected {:?} in expression", c));
+        }
+
+        if let Some((_, range)) = items.last_mut()
+        {
+            range.end = text.len() - rest.len();
+        }
+        rest = rest.trim_start();
+    }
+
+    Ok(items)
+}
+
+/// Recursive descent over the items, one function per precedence level
+struct Parser<'a>
+{
+    items: Vec<Item>,
+    position: usize,
+    symbol: &'a dyn Fn(&str) -> Result<i32, String>,
+}
+
+impl Parser<'_>
+{
+    fn peek(&self) -> Option<&Item>
+    {
+        self.items.get(self.position)
+    }
+
+    /// Move past the operator if it is one of `symbols`
+    fn operator(&mut self, symbols: &[&'static str]) -> Option<&'static str>
+    {
+        match self.peek()
+        {
+            Some(Item::Symbol(symbol)) if symbols.contains(symbol) =>
+            {
+                let symbol = *symbol;
+                self.position += 1;
+                Some(symbol)
+            },
+            _ => None,
+        }
+    }
+
+    /// Left associative binary operators of one level, `next` parses the level above
+    fn binary(&mut self, symbols: &[&'static str], next: fn(&mut Self) -> Result<i32, String>) -> Result<i32, String>
+    {
+        let mut value = next(self)?;
+
+        while let Some(symbol) = self.operator(symbols)
+        {
+            let right = next(self)?;
+            value = match symbol
+            {
+                "|" => value | right,
+                "^" => value ^ right,
+                "&" => value & right,
+                "<<" => value.wrapping_shl(right as u32),
+                ">>" => value.wrapping_shr(right as u32),
+                "+" => value.wrapping_add(right),
+                "-" => value.wrapping_sub(right),
+                "*" => value.wrapping_mul(right),
+                _ if right == 0 => return Err("Division by zero".to_string()),
+                _ => value.wrapping_div(right),
+            };
+        }
+
+        Ok(value)
+    }
+
+    /// < and > apply to everything after them
+    fn expression(&mut self) -> Result<i32, String>
+    {
+        match self.operator(&["<", ">"])
+        {
+            Some("<") => Ok(self.expression()? & 0xff),
+            Some(_) => Ok((self.expression()? >> 8) & 0xff),
+            None => self.or(),
+        }
+    }
+
+    fn or(&mut self) -> Result<i32, String>
+    {
+        self.binary(&["|"], Parser::xor)
+    }
+
+    fn xor(&mut self) -> Result<i32, String>
+    {
+        self.binary(&["^"], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<i32, String>
+    {
+        self.binary(&["&"], Parser::shift)
+    }
+
+    fn shift(&mut self) -> Result<i32, String>
+    {
+        self.binary(&["<<", ">>"], Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<i32, String>
+    {
+        self.binary(&["+", "-"], Parser::product)
+    }
+
+    fn product(&mut self) -> Result<i32, String>
+    {
+        self.binary(&["*", "/"], Parser::unary)
+    }
+
+    fn unary(&mut self) -> Result<i32, String>
+    {
+        match self.operator(&["-", "~", "+"])
+        {
+            Some("-") => Ok(self.unary()?.wrapping_neg()),
+            Some("~") => Ok(!self.unary()?),
+            Some(_) => self.unary(),
+            None => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i32, String>
+    {
+        let item = self.peek().cloned();
+        self.position += 1;
+
+        match item
+        {
+            Some(Item::Number(value)) => Ok(value),
+            Some(Item::Name(name)) => (self.symbol)(&name),
+            // In place of a value * is the current address
+            Some(Item::Symbol("*")) => (self.symbol)("*"),
+            Some(Item::Symbol("(")) =>
+            {
+                let value = self.expression()?;
+                match self.operator(&[")"])
+                {
+                    Some(_) => Ok(value),
+                    None => Err("Missing closing parenthesis".to_string()),
+                }
+            },
+            Some(Item::Symbol(symbol)) => Err(format!("Expected a value before {}", symbol)),
+            None => Err("Expected a value".to_string()),
+        }
+    }
+}
+
+/// Value of the expression `text`, `symbol` gives the value of the names in it and of `*`
+///
+/// Numbers are written $ff, %1010, 'a' or 255. From the lowest precedence the operators are
+/// < and > (low and high byte of everything after them), |, ^, &, << and >>, + and -,
+/// * and /, then the unary -, ~ and +. Values are 32-bit, callers check the range they need
+pub fn evaluate(text: &str, symbol: &dyn Fn(&str) -> Result<i32, String>) -> Result<i32, String>
+{
+    let items = items(text)?.into_iter().map(|(item, _)| item).collect();
+    let mut parser = Parser { items, position: 0, symbol };
+    let value = parser.expression()?;
+
+    match parser.peek()
+    {
+        None => Ok(value),
+        Some(Item::Symbol(symbol)) => Err(format!("Unexpected {} in expression", symbol)),
+        Some(_) => Err("Expected an operator between two values".to_string()),
+    }
+}
+
+/// `text` with each name replaced by `rename(name)`, the rest is kept as written
+///
+/// Text that isn't an expression is returned as is, evaluating it reports the error
+pub fn rename(text: &str, rename: &dyn Fn(&str) -> String) -> String
+{
+    let items = match items(text)
+    {
+        Ok(items) => items,
+        Err(_) => return text.to_string(),
+    };
+
+    let mut renamed = String::new();
+    let mut copied = 0;
+
+    for (item, range) in items
+    {
+        if let Item::Name(name) = item
+        {
+            renamed += &text[copied..range.start];
+            renamed += &rename(&name);
+            copied = range.end;
+        }
+    }
+
+    renamed + &text[copied..]
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Value of `text` with start = $0600, end = $0610 and * = $0605
+    fn value(text: &str) -> Result<i32, String>
+    {
+        evaluate(text, &|name| match name
+        {
+            "start" => Ok(0x0600),
+            "end" => Ok(0x0610),
+            "*" => Ok(0x0605),
+            _ => Err(format!("Unknown symbol {}", name)),
+        })
+    }
+
+    #[test]
+    fn numbers_and_characters()
+    {
+        assert_eq!(value("$ff"), Ok(255));
+        assert_eq!(value("%1010"), Ok(10));
+        assert_eq!(value("42"), Ok(42));
+        assert_eq!(value("'a'"), Ok(0x61));
+    }
+
+    #[test]
+    fn operators_follow_precedence()
+    {
+        assert_eq!(value("1 + 2 * 3"), Ok(7));
+        assert_eq!(value("(1 + 2) * 3"), Ok(9));
+        assert_eq!(value("7 / 2 - 1"), Ok(2));
+        assert_eq!(value("1 << 4 | 1"), Ok(0x11));
+        assert_eq!(value("$f0 & $3c ^ $ff"), Ok(0xcf));
+        assert_eq!(value("$ff >> 4 + 1"), Ok(0x07));
+        assert_eq!(value("~0 & $ff"), Ok(0xff));
+        assert_eq!(value("-1 + 3"), Ok(2));
+    }
+
+    #[test]
+    fn low_and_high_byte_apply_to_the_whole_expression()
+    {
+        assert_eq!(value("<end"), Ok(0x10));
+        assert_eq!(value(">end"), Ok(0x06));
+        assert_eq!(value("<start + $1ff"), Ok(0xff));
+        assert_eq!(value(">start + $1ff"), Ok(0x07));
+    }
+
+    #[test]
+    fn names_and_current_address()
+    {
+        assert_eq!(value("end - start"), Ok(0x10));
+        assert_eq!(value("start+1"), Ok(0x0601));
+        assert_eq!(value("* + 2"), Ok(0x0607));
+        assert_eq!(value("* * 2"), Ok(0x0c0a));
+        assert_eq!(value("missing"), Err("Unknown symbol missing".to_string()));
+    }
+
+    #[test]
+    fn malformed_expressions()
+    {
+        assert_eq!(value("1 / 0"), Err("Division by zero".to_string()));
+        assert_eq!(value("(1 + 2"), Err("Missing closing parenthesis".to_string()));
+        assert_eq!(value("1 +"), Err("Expected a value".to_string()));
+        assert_eq!(value("1 2"), Err("Expected an operator between two values".to_string()));
+        assert_eq!(value("1 )"), Err("Unexpected ) in expression".to_string()));
+        assert_eq!(value("$10000"), Err("$10000 does not fit in two bytes".to_string()));
+        assert_eq!(value("'ab'"), Err("Expected a character between single quotes".to_string()));
+        assert_eq!(value("1 # 2"), Err("Unexpected '#' in expression".to_string()));
+    }
+
+    #[test]
+    fn rename_keeps_the_rest_as_written()
+    {
+        let qualify = |name: &str| format!("main{}", name);
+
+        assert_eq!(rename("<@loop + 1", &qualify), "<main@loop + 1");
+        assert_eq!(rename("(.a*2)", &qualify), "(main.a*2)");
+        assert_eq!(rename("1 # 2", &qualify), "1 # 2");
+    }
+}
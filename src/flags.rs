@@ -0,0 +1,134 @@
+use std::fmt;
+use std::ops::{BitAnd, BitOr, BitOrAssign, Not};
+
+/// Processor status register, NV-BDIZC
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct StatusFlags(u8);
+
+#[allow(dead_code)]
+impl StatusFlags
+{
+    pub const NEGATIVE: StatusFlags     = StatusFlags(0b10000000);
+    pub const OVERFLOW: StatusFlags     = StatusFlags(0b01000000);
+    /// Always reads as set when the status is pushed
+    pub const UNUSED: StatusFlags       = StatusFlags(0b00100000);
+    /// Only exists in the copy pushed by BRK and PHP
+    pub const BREAK: StatusFlags        = StatusFlags(0b00010000);
+    pub const DECIMAL: StatusFlags      = StatusFlags(0b00001000);
+    pub const INTERRUPT: StatusFlags    = StatusFlags(0b00000100);
+    pub const ZERO: StatusFlags         = StatusFlags(0b00000010);
+    pub const CARRY: StatusFlags        = StatusFlags(0b00000001);
+
+    pub const fn from_bits(bits: u8) -> StatusFlags
+    {
+        StatusFlags(bits)
+    }
+
+    pub const fn bits(self) -> u8
+    {
+        self.0
+    }
+
+    /// True if all of `flags` are set
+    #[inline(always)]
+    pub fn contains(self, flags: StatusFlags) -> bool
+    {
+        self.0 & flags.0 == flags.0
+    }
+
+    #[inline(always)]
+    pub fn insert(&mut self, flags: StatusFlags)
+    {
+        self.0 |= flags.0;
+    }
+
+    #[inline(always)]
+    pub fn remove(&mut self, flags: StatusFlags)
+    {
+        self.0 &= !flags.0;
+    }
+
+    /// Set `flags` if `value` is true, clear them otherwise
+    #[inline(always)]
+    pub fn set(&mut self, flags: StatusFlags, value: bool)
+    {
+        if value
+        {
+            self.insert(flags);
+        }
+        else
+        {
+            self.remove(flags);
+        }
+    }
+
+    /// The carry flag as a number, 0 or 1
+    #[inline(always)]
+    pub fn carry(self) -> u8
+    {
+        self.0 & StatusFlags::CARRY.0
+    }
+
+    /// Set N from bit 7 of `value` and Z if it is zero
+    #[inline(always)]
+    pub fn set_nz(&mut self, value: u8)
+    {
+        self.set(StatusFlags::NEGATIVE, value & 0x80 != 0);
+        self.set(StatusFlags::ZERO, value == 0);
+    }
+}
+
+impl BitOr for StatusFlags
+{
+    type Output = StatusFlags;
+
+    fn bitor(self, rhs: StatusFlags) -> StatusFlags
+    {
+        StatusFlags(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for StatusFlags
+{
+    fn bitor_assign(&mut self, rhs: StatusFlags)
+    {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for StatusFlags
+{
+    type Output = StatusFlags;
+
+    fn bitand(self, rhs: StatusFlags) -> StatusFlags
+    {
+        StatusFlags(self.0 & rhs.0)
+    }
+}
+
+impl Not for StatusFlags
+{
+    type Output = StatusFlags;
+
+    fn not(self) -> StatusFlags
+    {
+        StatusFlags(!self.0)
+    }
+}
+
+/// Set flags in upper case and clear ones in lower case, e.g `Nv-bdIzC`
+impl fmt::Debug for StatusFlags
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let names = ['N', 'V', '-', 'B', 'D', 'I', 'Z', 'C'];
+
+        for (i, name) in names.iter().enumerate()
+        {
+            let set = self.0 & (0x80 >> i) != 0;
+            write!(f, "{}", if set { *name } else { name.to_ascii_lowercase() })?;
+        }
+
+        Ok(())
+    }
+}
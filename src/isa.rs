@@ -1,20 +1,23 @@
+use crate::flags::StatusFlags;
+
 /// Instruction set of the emulator
-/// Each instruction has an internal representation
 ///
-/// Two enums
-///     - AddrMode ie Addressing Mode
+/// Two structs
+///     - Mode ie Addressing Mode
 ///     - Instruction
 ///
-/// An instruction is represented  by a u32 value in the Instruction enum and AddrMode enum
-/// i.e instruction in the MSBs and mode in the LSBs
-/// The mode will probably be a u8 and instruction the rest
+/// and one opcode table per variant (NMOS_OPCODES, CMOS_OPCODES, WDC_OPCODES) giving the
+/// mnemonic, addressing mode, length, cycles and flags of every opcode byte. The assembler,
+/// the cpu timing and the disassembler all read these tables
+///
+/// Instruction constants use the documented NMOS 6502 opcode bytes, so binaries
+/// produced by e6502c run on other emulators/hardware and vice versa
 /// e.g
-///     IMMEDIATE MODE => 01
-///     LDA => 0x200
+///     LDA IMMEDIATE => 0xa9
+///     JMP ABSOLUTE  => 0x4c
 ///
-///     LDA IMMEDIATE 0x200 & 0x201
+/// Multi-byte operands are stored little-endian (low byte first)
 ///
-
 /// Source [masswerk.at](https://www.masswerk.at/6502/6502_instruction_set.html)
 ///
 /// Source [addressing_modes](https://wiki.cdot.senecacollege.ca/wiki/6502_Addressing_Modes#Accumulator)
@@ -31,9 +34,11 @@
 /// - INDX - Add 8-bit address without cary with the content of the x register
 /// - INDY - Same as INDX, but x register
 /// - REL - 8-bit signed offset is provided. The value is added to the program counter to find the
-/// effective address
-/// ZP/ZPX/ZPY - are similar to IND/INDX/INDY respectively
-
+///   effective address
+/// - ZP/ZPX/ZPY - are similar to IND/INDX/INDY respectively
+/// - ZPI - (65C02) Data is accessed using a 16-bit pointer stored in the zero page, e.g LDA ($12)
+/// - ABSINDX - (65C02) Add X to the 16-bit operand and read the target from there, only used by JMP ($1234,X)
+/// - ZPREL - (WDC 65C02) Zero page operand followed by an 8-bit signed branch offset, e.g BBR0 $12,loop
 pub struct Instruction;
 pub struct Mode;
 
@@ -50,173 +55,859 @@ pub const IND: u32      = 0x06;
 pub const INDX: u32     = 0x07;
 pub const INDY: u32     = 0x08;
 pub const REL: u32      = 0x09;
-pub const REL_16: u32   = 0x0a0;
 pub const ZP: u32       = 0x0b;
 pub const ZPY: u32      = 0x0c;
 pub const ZPX: u32      = 0x0d;
 pub const UNKNOWN: u32  = 0x0e;
+pub const ZPI: u32      = 0x0f;
+pub const ABSINDX: u32  = 0x10;
+pub const ZPREL: u32    = 0x11;
+
+/// Number of operand bytes that follow the opcode
+pub const fn operand_len(mode: u32) -> u8
+{
+    match mode
+    {
+        Mode::ABS | Mode::ABSX | Mode::ABSY | Mode::IND | Mode::ABSINDX | Mode::ZPREL => 2,
+        Mode::IMM | Mode::INDX | Mode::INDY | Mode::REL | Mode::ZP | Mode::ZPX | Mode::ZPY | Mode::ZPI => 1,
+        _ => 0,
+    }
+}
+
+/// How the operand of the mode is written, for error messages
+pub const fn syntax(mode: u32) -> &'static str
+{
+    match mode
+    {
+        Mode::ACC => "A",
+        Mode::ABS => "abs",
+        Mode::ABSX => "abs,X",
+        Mode::ABSY => "abs,Y",
+        Mode::IMM => "#imm",
+        Mode::IMP => "implied",
+        Mode::IND => "(abs)",
+        Mode::INDX => "(zp,X)",
+        Mode::INDY => "(zp),Y",
+        Mode::REL => "relative",
+        Mode::ZP => "zp",
+        Mode::ZPX => "zp,X",
+        Mode::ZPY => "zp,Y",
+        Mode::ZPI => "(zp)",
+        Mode::ABSINDX => "(abs,X)",
+        Mode::ZPREL => "zp,relative",
+        _ => "unknown",
+    }
+}
+}
+
+/// Instruction set targeted by the assembler and emulated by the cpu
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Variant
+{
+    /// Original NMOS 6502
+    #[default]
+    Nmos,
+    /// CMOS 65C02, adds BRA, STZ, PHX/PHY/PLX/PLY, TRB/TSB and (zp) addressing
+    Cmos,
+    /// WDC 65C02, adds RMB/SMB/BBR/BBS and WAI/STP to the 65C02
+    Wdc,
 }
 
 #[allow(dead_code)]
 impl Instruction
 {
-    pub const ADC_IMM:u8 =	 0x00;
-    pub const ADC_ABS:u8 =	 0x01;
-    pub const ADC_ABSX:u8 =	 0x02;
-    pub const ADC_ABSY:u8 =	 0x03;
-    pub const ADC_INDX:u8 =	 0x04;
-    pub const ADC_INDY:u8 =	 0x05;
-    pub const ADC_ZP:u8 =	 0x06;
-    pub const ADC_ZPX:u8 =	 0x07;
-    pub const AND_ABS:u8 =	 0x08;
-    pub const AND_ABSX:u8 =	 0x09;
-    pub const AND_ABSY:u8 =	 0x0a;
-    pub const AND_IMM:u8 =	 0x0b;
-    pub const AND_INDX:u8 =	 0x0c;
-    pub const AND_INDY:u8 =	 0x0d;
-    pub const AND_ZP:u8 =	 0x0e;
-    pub const AND_ZPX:u8 =	 0x0f;
-    pub const ASL_ACC:u8 =	 0x10;
-    pub const ASL_ABS:u8 =	 0x11;
-    pub const ASL_ABSX:u8 =	 0x12;
-    pub const ASL_ZP:u8 =	 0x13;
-    pub const ASL_ZPX:u8 =	 0x14;
-    pub const BCC_REL:u8 =	 0x15;
-    pub const BCC_REL_16:u8= 0x16;
-    pub const BCS_REL:u8 =	 0x17;
-    pub const BCS_REL_16:u8= 0x18;
-    pub const BEQ_REL:u8 =	 0x19;
-    pub const BEQ_REL_16:u8= 0x1a;
-    pub const BIT_ABS:u8 =	 0x1b;
-    pub const BIT_ZP:u8 =	 0x1c;
-    pub const BMI_REL:u8 =	 0x1d;
-    pub const BMI_REL_16:u8= 0x1e;
-    pub const BNE_REL:u8 =	 0x1f;
-    pub const BNE_REL_16:u8= 0x20;
-    pub const BPL_REL:u8 =	 0x21;
-    pub const BPL_REL_16:u8= 0x22;
-    pub const BRK_IMP:u8 =	 0x23;
-    pub const BVC_REL:u8 =	 0x24;
-    pub const BVC_REL_16:u8= 0x25;
-    pub const BVS_REL:u8 =	 0x26;
-    pub const BVS_REL_16:u8= 0x27;
-    pub const CLC_IMP:u8 =	 0x28;
-    pub const CLD_IMP:u8 =	 0x29;
-    pub const CLI_IMP:u8 =	 0x2a;
-    pub const CLV_IMP:u8 =	 0x2b;
-    pub const CMP_ABS:u8 =	 0x2c;
-    pub const CMP_ABSX:u8 =	 0x2d;
-    pub const CMP_ABSY:u8 =	 0x2e;
-    pub const CMP_IMM:u8 =	 0x2f;
-    pub const CMP_INDX:u8 =	 0x30;
-    pub const CMP_INDY:u8 =	 0x31;
-    pub const CMP_ZP:u8 =	 0x32;
-    pub const CMP_ZPX:u8 =	 0x33;
-    pub const CPX_ABS:u8 =	 0x34;
-    pub const CPX_IMM:u8 =	 0x35;
-    pub const CPX_ZP:u8 =	 0x36;
-    pub const CPY_ABS:u8 =	 0x37;
-    pub const CPY_IMM:u8 =	 0x38;
-    pub const CPY_ZP:u8 =	 0x39;
-    pub const DEC_ABS:u8 =	 0x3a;
-    pub const DEC_ABSX:u8 =	 0x3b;
-    pub const DEC_ZP:u8 =	 0x3c;
-    pub const DEC_ZPX:u8 =	 0x3d;
-    pub const DEX_IMP:u8 =	 0x3e;
-    pub const DEY_IMP:u8 =	 0x3f;
-    pub const EOR_ABS:u8 =	 0x40;
-    pub const EOR_ABSX:u8 =	 0x41;
-    pub const EOR_ABSY:u8 =	 0x42;
-    pub const EOR_IMM:u8 =	 0x43;
-    pub const EOR_INDX:u8 =	 0x44;
-    pub const EOR_INDY:u8 =	 0x45;
-    pub const EOR_ZP:u8 =	 0x46;
-    pub const EOR_ZPX:u8 =	 0x47;
-    pub const INC_ABS:u8 =	 0x48;
-    pub const INC_ABSX:u8 =	 0x49;
-    pub const INC_ZP:u8 =	 0x4a;
-    pub const INC_ZPX:u8 =	 0x4b;
-    pub const INX_IMP:u8 =	 0x4c;
-    pub const INY_IMP:u8 =	 0x4d;
-    pub const JMP_ABS:u8 =	 0x4e;
-    pub const JMP_IND:u8 =	 0x4f;
-    pub const JSR_ABS:u8 =	 0x50;
-    pub const LDA_ABS:u8 =	 0x51;
-    pub const LDA_ABSX:u8 =	 0x52;
-    pub const LDA_ABSY:u8 =	 0x53;
-    pub const LDA_IMM:u8 =	 0x54;
-    pub const LDA_INDX:u8 =	 0x55;
-    pub const LDA_INDY:u8 =	 0x56;
-    pub const LDA_ZP:u8 =	 0x57;
-    pub const LDA_ZPX:u8 =	 0x58;
-    pub const LDX_ABS:u8 =	 0x59;
-    pub const LDX_ABSY:u8 =	 0x5a;
-    pub const LDX_IMM:u8 =	 0x5b;
-    pub const LDX_ZP:u8 =	 0x5c;
-    pub const LDX_ZPY:u8 =	 0x5d;
-    pub const LDY_ABS:u8 =	 0x5e;
-    pub const LDY_ABSX:u8 =	 0x5f;
-    pub const LDY_IMM:u8 =	 0x60;
-    pub const LDY_ZP:u8 =	 0x61;
-    pub const LDY_ZPX:u8 =	 0x62;
-    pub const LSR_ACC:u8 =	 0x63;
-    pub const LSR_ABS:u8 =	 0x64;
-    pub const LSR_ABSX:u8 =	 0x65;
-    pub const LSR_ZP:u8 =	 0x66;
-    pub const LSR_ZPX:u8 =	 0x67;
-    pub const NOP_IMP:u8 =	 0x68;
-    pub const ORA_ABS:u8 =	 0x69;
-    pub const ORA_ABSX:u8 =	 0x6a;
-    pub const ORA_ABSY:u8 =	 0x6b;
-    pub const ORA_IMM:u8 =	 0x6c;
-    pub const ORA_INDX:u8 =	 0x6d;
-    pub const ORA_INDY:u8 =	 0x6e;
-    pub const ORA_ZP:u8 =	 0x6f;
-    pub const ORA_ZPX:u8 =	 0x70;
-    pub const PHA_IMP:u8 =	 0x71;
-    pub const PHP_IMP:u8 =	 0x72;
-    pub const PLA_IMP:u8 =	 0x73;
-    pub const PLP_IMP:u8 =	 0x74;
-    pub const ROL_ACC:u8 =	 0x75;
-    pub const ROL_ABS:u8 =	 0x76;
-    pub const ROL_ABSX:u8 =	 0x77;
-    pub const ROL_ZP:u8 =	 0x78;
-    pub const ROL_ZPX:u8 =	 0x79;
-    pub const ROR_ACC:u8 =	 0x7a;
-    pub const ROR_ABS:u8 =	 0x7b;
-    pub const ROR_ABSX:u8 =	 0x7c;
-    pub const ROR_ZP:u8 =	 0x7d;
-    pub const ROR_ZPX:u8 =	 0x7e;
-    pub const RTI_IMP:u8 =	 0x7f;
-    pub const RTS_IMP:u8 =	 0x80;
-    pub const SBC_ABS:u8 =	 0x81;
-    pub const SBC_ABSX:u8 =	 0x82;
-    pub const SBC_ABSY:u8 =	 0x83;
-    pub const SBC_IMM:u8 =	 0x84;
-    pub const SBC_INDX:u8 =	 0x85;
-    pub const SBC_INDY:u8 =	 0x86;
-    pub const SBC_ZP:u8 =	 0x87;
-    pub const SBC_ZPX:u8 =	 0x88;
-    pub const SEC_IMP:u8 =	 0x89;
-    pub const SED_IMP:u8 =	 0x8a;
-    pub const SEI_IMP:u8 =	 0x8b;
-    pub const STA_ABS:u8 =	 0x8c;
-    pub const STA_ABSX:u8 =	 0x8d;
-    pub const STA_ABSY:u8 =	 0x8e;
-    pub const STA_INDX:u8 =	 0x8f;
-    pub const STA_INDY:u8 =	 0x90;
-    pub const STA_ZP:u8 =	 0x91;
-    pub const STA_ZPX:u8 =	 0x92;
-    pub const STX_ABS:u8 =	 0x93;
-    pub const STX_ZP:u8 =	 0x94;
-    pub const STX_ZPY:u8 =	 0x95;
-    pub const STY_ABS:u8 =	 0x96;
-    pub const STY_ZP:u8 =	 0x97;
-    pub const STY_ZPX:u8 =	 0x98;
-    pub const TAX_IMP:u8 =	 0x99;
-    pub const TAY_IMP:u8 =	 0x9a;
-    pub const TSX_IMP:u8 =	 0x9b;
-    pub const TXA_IMP:u8 =	 0x9c;
-    pub const TXS_IMP:u8 =	 0x9d;
-    pub const TYA_IMP:u8 =	 0x9e;
+    pub const ADC_IMM:u8 =	 0x69;
+    pub const ADC_ABS:u8 =	 0x6d;
+    pub const ADC_ABSX:u8 =	 0x7d;
+    pub const ADC_ABSY:u8 =	 0x79;
+    pub const ADC_INDX:u8 =	 0x61;
+    pub const ADC_INDY:u8 =	 0x71;
+    pub const ADC_ZP:u8 =	 0x65;
+    pub const ADC_ZPX:u8 =	 0x75;
+    pub const AND_ABS:u8 =	 0x2d;
+    pub const AND_ABSX:u8 =	 0x3d;
+    pub const AND_ABSY:u8 =	 0x39;
+    pub const AND_IMM:u8 =	 0x29;
+    pub const AND_INDX:u8 =	 0x21;
+    pub const AND_INDY:u8 =	 0x31;
+    pub const AND_ZP:u8 =	 0x25;
+    pub const AND_ZPX:u8 =	 0x35;
+    pub const ASL_ACC:u8 =	 0x0a;
+    pub const ASL_ABS:u8 =	 0x0e;
+    pub const ASL_ABSX:u8 =	 0x1e;
+    pub const ASL_ZP:u8 =	 0x06;
+    pub const ASL_ZPX:u8 =	 0x16;
+    pub const BCC_REL:u8 =	 0x90;
+    pub const BCS_REL:u8 =	 0xb0;
+    pub const BEQ_REL:u8 =	 0xf0;
+    pub const BIT_ABS:u8 =	 0x2c;
+    pub const BIT_ZP:u8 =	 0x24;
+    pub const BMI_REL:u8 =	 0x30;
+    pub const BNE_REL:u8 =	 0xd0;
+    pub const BPL_REL:u8 =	 0x10;
+    pub const BRK_IMP:u8 =	 0x00;
+    pub const BVC_REL:u8 =	 0x50;
+    pub const BVS_REL:u8 =	 0x70;
+    pub const CLC_IMP:u8 =	 0x18;
+    pub const CLD_IMP:u8 =	 0xd8;
+    pub const CLI_IMP:u8 =	 0x58;
+    pub const CLV_IMP:u8 =	 0xb8;
+    pub const CMP_ABS:u8 =	 0xcd;
+    pub const CMP_ABSX:u8 =	 0xdd;
+    pub const CMP_ABSY:u8 =	 0xd9;
+    pub const CMP_IMM:u8 =	 0xc9;
+    pub const CMP_INDX:u8 =	 0xc1;
+    pub const CMP_INDY:u8 =	 0xd1;
+    pub const CMP_ZP:u8 =	 0xc5;
+    pub const CMP_ZPX:u8 =	 0xd5;
+    pub const CPX_ABS:u8 =	 0xec;
+    pub const CPX_IMM:u8 =	 0xe0;
+    pub const CPX_ZP:u8 =	 0xe4;
+    pub const CPY_ABS:u8 =	 0xcc;
+    pub const CPY_IMM:u8 =	 0xc0;
+    pub const CPY_ZP:u8 =	 0xc4;
+    pub const DEC_ABS:u8 =	 0xce;
+    pub const DEC_ABSX:u8 =	 0xde;
+    pub const DEC_ZP:u8 =	 0xc6;
+    pub const DEC_ZPX:u8 =	 0xd6;
+    pub const DEX_IMP:u8 =	 0xca;
+    pub const DEY_IMP:u8 =	 0x88;
+    pub const EOR_ABS:u8 =	 0x4d;
+    pub const EOR_ABSX:u8 =	 0x5d;
+    pub const EOR_ABSY:u8 =	 0x59;
+    pub const EOR_IMM:u8 =	 0x49;
+    pub const EOR_INDX:u8 =	 0x41;
+    pub const EOR_INDY:u8 =	 0x51;
+    pub const EOR_ZP:u8 =	 0x45;
+    pub const EOR_ZPX:u8 =	 0x55;
+    pub const INC_ABS:u8 =	 0xee;
+    pub const INC_ABSX:u8 =	 0xfe;
+    pub const INC_ZP:u8 =	 0xe6;
+    pub const INC_ZPX:u8 =	 0xf6;
+    pub const INX_IMP:u8 =	 0xe8;
+    pub const INY_IMP:u8 =	 0xc8;
+    pub const JMP_ABS:u8 =	 0x4c;
+    pub const JMP_IND:u8 =	 0x6c;
+    pub const JSR_ABS:u8 =	 0x20;
+    pub const LDA_ABS:u8 =	 0xad;
+    pub const LDA_ABSX:u8 =	 0xbd;
+    pub const LDA_ABSY:u8 =	 0xb9;
+    pub const LDA_IMM:u8 =	 0xa9;
+    pub const LDA_INDX:u8 =	 0xa1;
+    pub const LDA_INDY:u8 =	 0xb1;
+    pub const LDA_ZP:u8 =	 0xa5;
+    pub const LDA_ZPX:u8 =	 0xb5;
+    pub const LDX_ABS:u8 =	 0xae;
+    pub const LDX_ABSY:u8 =	 0xbe;
+    pub const LDX_IMM:u8 =	 0xa2;
+    pub const LDX_ZP:u8 =	 0xa6;
+    pub const LDX_ZPY:u8 =	 0xb6;
+    pub const LDY_ABS:u8 =	 0xac;
+    pub const LDY_ABSX:u8 =	 0xbc;
+    pub const LDY_IMM:u8 =	 0xa0;
+    pub const LDY_ZP:u8 =	 0xa4;
+    pub const LDY_ZPX:u8 =	 0xb4;
+    pub const LSR_ACC:u8 =	 0x4a;
+    pub const LSR_ABS:u8 =	 0x4e;
+    pub const LSR_ABSX:u8 =	 0x5e;
+    pub const LSR_ZP:u8 =	 0x46;
+    pub const LSR_ZPX:u8 =	 0x56;
+    pub const NOP_IMP:u8 =	 0xea;
+    pub const ORA_ABS:u8 =	 0x0d;
+    pub const ORA_ABSX:u8 =	 0x1d;
+    pub const ORA_ABSY:u8 =	 0x19;
+    pub const ORA_IMM:u8 =	 0x09;
+    pub const ORA_INDX:u8 =	 0x01;
+    pub const ORA_INDY:u8 =	 0x11;
+    pub const ORA_ZP:u8 =	 0x05;
+    pub const ORA_ZPX:u8 =	 0x15;
+    pub const PHA_IMP:u8 =	 0x48;
+    pub const PHP_IMP:u8 =	 0x08;
+    pub const PLA_IMP:u8 =	 0x68;
+    pub const PLP_IMP:u8 =	 0x28;
+    pub const ROL_ACC:u8 =	 0x2a;
+    pub const ROL_ABS:u8 =	 0x2e;
+    pub const ROL_ABSX:u8 =	 0x3e;
+    pub const ROL_ZP:u8 =	 0x26;
+    pub const ROL_ZPX:u8 =	 0x36;
+    pub const ROR_ACC:u8 =	 0x6a;
+    pub const ROR_ABS:u8 =	 0x6e;
+    pub const ROR_ABSX:u8 =	 0x7e;
+    pub const ROR_ZP:u8 =	 0x66;
+    pub const ROR_ZPX:u8 =	 0x76;
+    pub const RTI_IMP:u8 =	 0x40;
+    pub const RTS_IMP:u8 =	 0x60;
+    pub const SBC_ABS:u8 =	 0xed;
+    pub const SBC_ABSX:u8 =	 0xfd;
+    pub const SBC_ABSY:u8 =	 0xf9;
+    pub const SBC_IMM:u8 =	 0xe9;
+    pub const SBC_INDX:u8 =	 0xe1;
+    pub const SBC_INDY:u8 =	 0xf1;
+    pub const SBC_ZP:u8 =	 0xe5;
+    pub const SBC_ZPX:u8 =	 0xf5;
+    pub const SEC_IMP:u8 =	 0x38;
+    pub const SED_IMP:u8 =	 0xf8;
+    pub const SEI_IMP:u8 =	 0x78;
+    pub const STA_ABS:u8 =	 0x8d;
+    pub const STA_ABSX:u8 =	 0x9d;
+    pub const STA_ABSY:u8 =	 0x99;
+    pub const STA_INDX:u8 =	 0x81;
+    pub const STA_INDY:u8 =	 0x91;
+    pub const STA_ZP:u8 =	 0x85;
+    pub const STA_ZPX:u8 =	 0x95;
+    pub const STX_ABS:u8 =	 0x8e;
+    pub const STX_ZP:u8 =	 0x86;
+    pub const STX_ZPY:u8 =	 0x96;
+    pub const STY_ABS:u8 =	 0x8c;
+    pub const STY_ZP:u8 =	 0x84;
+    pub const STY_ZPX:u8 =	 0x94;
+    pub const TAX_IMP:u8 =	 0xaa;
+    pub const TAY_IMP:u8 =	 0xa8;
+    pub const TSX_IMP:u8 =	 0xba;
+    pub const TXA_IMP:u8 =	 0x8a;
+    pub const TXS_IMP:u8 =	 0x9a;
+    pub const TYA_IMP:u8 =	 0x98;
+}
+
+/// Undocumented NMOS opcodes, only executed when `Cpu::undocumented` is set
+///
+/// Source [NMOS 6510 Unintended Opcodes](https://csdb.dk/release/?id=198357)
+///
+/// NOP, JAM and USBC (SBC #) have several encodings, the constants are the usual ones.
+/// ANC has two, ANC_IMM_ALT is the second
+#[allow(dead_code)]
+impl Instruction
+{
+    pub const ALR_IMM:u8 =	 0x4b;
+    pub const ANC_IMM:u8 =	 0x0b;
+    pub const ANC_IMM_ALT:u8 =	 0x2b;
+    pub const ARR_IMM:u8 =	 0x6b;
+    pub const DCP_ABS:u8 =	 0xcf;
+    pub const DCP_ABSX:u8 =	 0xdf;
+    pub const DCP_ABSY:u8 =	 0xdb;
+    pub const DCP_INDX:u8 =	 0xc3;
+    pub const DCP_INDY:u8 =	 0xd3;
+    pub const DCP_ZP:u8 =	 0xc7;
+    pub const DCP_ZPX:u8 =	 0xd7;
+    pub const ISC_ABS:u8 =	 0xef;
+    pub const ISC_ABSX:u8 =	 0xff;
+    pub const ISC_ABSY:u8 =	 0xfb;
+    pub const ISC_INDX:u8 =	 0xe3;
+    pub const ISC_INDY:u8 =	 0xf3;
+    pub const ISC_ZP:u8 =	 0xe7;
+    pub const ISC_ZPX:u8 =	 0xf7;
+    pub const JAM_IMP:u8 =	 0x02;
+    pub const LAS_ABSY:u8 =	 0xbb;
+    pub const LAX_ABS:u8 =	 0xaf;
+    pub const LAX_ABSY:u8 =	 0xbf;
+    pub const LAX_INDX:u8 =	 0xa3;
+    pub const LAX_INDY:u8 =	 0xb3;
+    pub const LAX_ZP:u8 =	 0xa7;
+    pub const LAX_ZPY:u8 =	 0xb7;
+    pub const NOP_ABS:u8 =	 0x0c;
+    pub const NOP_ABSX:u8 =	 0x1c;
+    pub const NOP_IMM:u8 =	 0x80;
+    pub const NOP_ZP:u8 =	 0x04;
+    pub const NOP_ZPX:u8 =	 0x14;
+    pub const RLA_ABS:u8 =	 0x2f;
+    pub const RLA_ABSX:u8 =	 0x3f;
+    pub const RLA_ABSY:u8 =	 0x3b;
+    pub const RLA_INDX:u8 =	 0x23;
+    pub const RLA_INDY:u8 =	 0x33;
+    pub const RLA_ZP:u8 =	 0x27;
+    pub const RLA_ZPX:u8 =	 0x37;
+    pub const RRA_ABS:u8 =	 0x6f;
+    pub const RRA_ABSX:u8 =	 0x7f;
+    pub const RRA_ABSY:u8 =	 0x7b;
+    pub const RRA_INDX:u8 =	 0x63;
+    pub const RRA_INDY:u8 =	 0x73;
+    pub const RRA_ZP:u8 =	 0x67;
+    pub const RRA_ZPX:u8 =	 0x77;
+    pub const SAX_ABS:u8 =	 0x8f;
+    pub const SAX_INDX:u8 =	 0x83;
+    pub const SAX_ZP:u8 =	 0x87;
+    pub const SAX_ZPY:u8 =	 0x97;
+    pub const SBX_IMM:u8 =	 0xcb;
+    pub const SLO_ABS:u8 =	 0x0f;
+    pub const SLO_ABSX:u8 =	 0x1f;
+    pub const SLO_ABSY:u8 =	 0x1b;
+    pub const SLO_INDX:u8 =	 0x03;
+    pub const SLO_INDY:u8 =	 0x13;
+    pub const SLO_ZP:u8 =	 0x07;
+    pub const SLO_ZPX:u8 =	 0x17;
+    pub const SRE_ABS:u8 =	 0x4f;
+    pub const SRE_ABSX:u8 =	 0x5f;
+    pub const SRE_ABSY:u8 =	 0x5b;
+    pub const SRE_INDX:u8 =	 0x43;
+    pub const SRE_INDY:u8 =	 0x53;
+    pub const SRE_ZP:u8 =	 0x47;
+    pub const SRE_ZPX:u8 =	 0x57;
+    pub const USBC_IMM:u8 =	 0xeb;
+}
+
+/// Opcodes added by the 65C02
+#[allow(dead_code)]
+impl Instruction
+{
+    pub const ADC_ZPI:u8 =	 0x72;
+    pub const AND_ZPI:u8 =	 0x32;
+    pub const BIT_ABSX:u8 =	 0x3c;
+    pub const BIT_IMM:u8 =	 0x89;
+    pub const BIT_ZPX:u8 =	 0x34;
+    pub const BRA_REL:u8 =	 0x80;
+    pub const CMP_ZPI:u8 =	 0xd2;
+    pub const DEC_ACC:u8 =	 0x3a;
+    pub const EOR_ZPI:u8 =	 0x52;
+    pub const INC_ACC:u8 =	 0x1a;
+    pub const JMP_ABSINDX:u8 =	 0x7c;
+    pub const LDA_ZPI:u8 =	 0xb2;
+    pub const ORA_ZPI:u8 =	 0x12;
+    pub const PHX_IMP:u8 =	 0xda;
+    pub const PHY_IMP:u8 =	 0x5a;
+    pub const PLX_IMP:u8 =	 0xfa;
+    pub const PLY_IMP:u8 =	 0x7a;
+    pub const SBC_ZPI:u8 =	 0xf2;
+    pub const STA_ZPI:u8 =	 0x92;
+    pub const STZ_ABS:u8 =	 0x9c;
+    pub const STZ_ABSX:u8 =	 0x9e;
+    pub const STZ_ZP:u8 =	 0x64;
+    pub const STZ_ZPX:u8 =	 0x74;
+    pub const TRB_ABS:u8 =	 0x1c;
+    pub const TRB_ZP:u8 =	 0x14;
+    pub const TSB_ABS:u8 =	 0x0c;
+    pub const TSB_ZP:u8 =	 0x04;
+}
+
+/// Opcodes added by the WDC 65C02
+///
+/// The bit number of RMB, SMB, BBR and BBS is in bits 4-6 of the opcode
+#[allow(dead_code)]
+impl Instruction
+{
+    pub const BBR0_ZPREL:u8 =	 0x0f;
+    pub const BBR1_ZPREL:u8 =	 0x1f;
+    pub const BBR2_ZPREL:u8 =	 0x2f;
+    pub const BBR3_ZPREL:u8 =	 0x3f;
+    pub const BBR4_ZPREL:u8 =	 0x4f;
+    pub const BBR5_ZPREL:u8 =	 0x5f;
+    pub const BBR6_ZPREL:u8 =	 0x6f;
+    pub const BBR7_ZPREL:u8 =	 0x7f;
+    pub const BBS0_ZPREL:u8 =	 0x8f;
+    pub const BBS1_ZPREL:u8 =	 0x9f;
+    pub const BBS2_ZPREL:u8 =	 0xaf;
+    pub const BBS3_ZPREL:u8 =	 0xbf;
+    pub const BBS4_ZPREL:u8 =	 0xcf;
+    pub const BBS5_ZPREL:u8 =	 0xdf;
+    pub const BBS6_ZPREL:u8 =	 0xef;
+    pub const BBS7_ZPREL:u8 =	 0xff;
+    pub const RMB0_ZP:u8 =	 0x07;
+    pub const RMB1_ZP:u8 =	 0x17;
+    pub const RMB2_ZP:u8 =	 0x27;
+    pub const RMB3_ZP:u8 =	 0x37;
+    pub const RMB4_ZP:u8 =	 0x47;
+    pub const RMB5_ZP:u8 =	 0x57;
+    pub const RMB6_ZP:u8 =	 0x67;
+    pub const RMB7_ZP:u8 =	 0x77;
+    pub const SMB0_ZP:u8 =	 0x87;
+    pub const SMB1_ZP:u8 =	 0x97;
+    pub const SMB2_ZP:u8 =	 0xa7;
+    pub const SMB3_ZP:u8 =	 0xb7;
+    pub const SMB4_ZP:u8 =	 0xc7;
+    pub const SMB5_ZP:u8 =	 0xd7;
+    pub const SMB6_ZP:u8 =	 0xe7;
+    pub const SMB7_ZP:u8 =	 0xf7;
+    pub const STP_IMP:u8 =	 0xdb;
+    pub const WAI_IMP:u8 =	 0xcb;
+}
+
+/// Everything the assembler, the cpu and the disassembler know about an opcode
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Opcode
+{
+    /// Upper case mnemonic, "???" for undefined opcodes
+    pub mnemonic: &'static str,
+    pub mode: u32,
+    /// Length of the instruction, opcode included
+    pub bytes: u8,
+    /// Base cycle count
+    pub cycles: u8,
+    /// Extra cycle taken when the ABSX/ABSY/INDY address crosses a page
+    pub page_penalty: u8,
+    /// Status flags the instruction can change
+    pub flags: StatusFlags,
+    /// Undocumented NMOS opcode, or one of the NOPs the 65C02 puts in undefined slots.
+    /// The assembler only emits them for the NMOS 6502
+    pub undocumented: bool,
+}
+
+#[allow(dead_code)]
+impl Opcode
+{
+    /// Opcode that neither runs nor assembles
+    pub const UNDEFINED: Opcode = Opcode
+    {
+        mnemonic: "???",
+        mode: Mode::UNKNOWN,
+        bytes: 1,
+        cycles: 0,
+        page_penalty: 0,
+        flags: NONE,
+        undocumented: false,
+    };
+
+    const fn new(mnemonic: &'static str, mode: u32, cycles: u8, page_penalty: u8, flags: StatusFlags) -> Opcode
+    {
+        Opcode { mnemonic, mode, bytes: 1 + Mode::operand_len(mode), cycles, page_penalty, flags, undocumented: false }
+    }
+
+    const fn undocumented(self) -> Opcode
+    {
+        Opcode { undocumented: true, ..self }
+    }
+
+    pub fn is_defined(&self) -> bool
+    {
+        self.mode != Mode::UNKNOWN
+    }
+}
+
+// Flags changed by the instructions, B and the unused bit are never changed
+const NONE: StatusFlags = StatusFlags::from_bits(0);
+const C: StatusFlags = StatusFlags::CARRY;
+const D: StatusFlags = StatusFlags::DECIMAL;
+const I: StatusFlags = StatusFlags::INTERRUPT;
+const V: StatusFlags = StatusFlags::OVERFLOW;
+const Z: StatusFlags = StatusFlags::ZERO;
+const ID: StatusFlags = StatusFlags::from_bits(I.bits() | D.bits());
+const NZ: StatusFlags = StatusFlags::from_bits(StatusFlags::NEGATIVE.bits() | Z.bits());
+const NZC: StatusFlags = StatusFlags::from_bits(NZ.bits() | C.bits());
+const NVZ: StatusFlags = StatusFlags::from_bits(NZ.bits() | V.bits());
+const NVZC: StatusFlags = StatusFlags::from_bits(NZC.bits() | V.bits());
+const ALL: StatusFlags = StatusFlags::from_bits(NVZC.bits() | ID.bits());
+
+/// Opcode table of `variant`, indexed by the opcode byte
+pub fn opcodes(variant: Variant) -> &'static [Opcode; 256]
+{
+    match variant
+    {
+        Variant::Nmos => &NMOS_OPCODES,
+        Variant::Cmos => &CMOS_OPCODES,
+        Variant::Wdc => &WDC_OPCODES,
+    }
+}
+
+/// Opcode byte of `mnemonic` in `mode` on `variant`
+///
+/// Documented encodings come first, e.g NOP is $EA and not one of the undocumented
+/// NOPs, undocumented opcodes are only assembled for the NMOS 6502
+pub fn encode(variant: Variant, mnemonic: &str, mode: u32) -> Option<u8>
+{
+    let table = opcodes(variant);
+    let find = |undocumented: bool| table.iter().position(|op|
+    {
+        op.is_defined() && op.undocumented == undocumented && op.mnemonic == mnemonic && op.mode == mode
+    });
+
+    let position = match variant
+    {
+        Variant::Nmos => find(false).or_else(|| find(true)),
+        _ => find(false),
+    };
+
+    position.map(|opcode| opcode as u8)
+}
+
+/// Whether `variant` assembles `mnemonic` in at least one addressing mode
+pub fn has_mnemonic(variant: Variant, mnemonic: &str) -> bool
+{
+    opcodes(variant)
+        .iter()
+        .any(|op| op.is_defined() && op.mnemonic == mnemonic && (variant == Variant::Nmos || !op.undocumented))
+}
+
+/// Whether `mnemonic` is an instruction of any variant
+pub fn is_mnemonic(mnemonic: &str) -> bool
+{
+    [Variant::Nmos, Variant::Cmos, Variant::Wdc].iter()
+        .any(|&variant| opcodes(variant).iter().any(|op| op.is_defined() && op.mnemonic == mnemonic))
+}
+
+/// Opcodes of the NMOS 6502
+///
+/// The unstable undocumented opcodes (ANE, LXA, SHA, SHX, SHY, TAS) are left undefined,
+/// JAM takes no cycles since it never completes
+pub const NMOS_OPCODES: [Opcode; 256] = [
+    Opcode::new("BRK", Mode::IMP, 7, 0, I),                                 // 0x00
+    Opcode::new("ORA", Mode::INDX, 6, 0, NZ),                               // 0x01
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x02
+    Opcode::new("SLO", Mode::INDX, 8, 0, NZC).undocumented(),               // 0x03
+    Opcode::new("NOP", Mode::ZP, 3, 0, NONE).undocumented(),                // 0x04
+    Opcode::new("ORA", Mode::ZP, 3, 0, NZ),                                 // 0x05
+    Opcode::new("ASL", Mode::ZP, 5, 0, NZC),                                // 0x06
+    Opcode::new("SLO", Mode::ZP, 5, 0, NZC).undocumented(),                 // 0x07
+    Opcode::new("PHP", Mode::IMP, 3, 0, NONE),                              // 0x08
+    Opcode::new("ORA", Mode::IMM, 2, 0, NZ),                                // 0x09
+    Opcode::new("ASL", Mode::ACC, 2, 0, NZC),                               // 0x0a
+    Opcode::new("ANC", Mode::IMM, 2, 0, NZC).undocumented(),                // 0x0b
+    Opcode::new("NOP", Mode::ABS, 4, 0, NONE).undocumented(),               // 0x0c
+    Opcode::new("ORA", Mode::ABS, 4, 0, NZ),                                // 0x0d
+    Opcode::new("ASL", Mode::ABS, 6, 0, NZC),                               // 0x0e
+    Opcode::new("SLO", Mode::ABS, 6, 0, NZC).undocumented(),                // 0x0f
+    Opcode::new("BPL", Mode::REL, 2, 0, NONE),                              // 0x10
+    Opcode::new("ORA", Mode::INDY, 5, 1, NZ),                               // 0x11
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x12
+    Opcode::new("SLO", Mode::INDY, 8, 0, NZC).undocumented(),               // 0x13
+    Opcode::new("NOP", Mode::ZPX, 4, 0, NONE).undocumented(),               // 0x14
+    Opcode::new("ORA", Mode::ZPX, 4, 0, NZ),                                // 0x15
+    Opcode::new("ASL", Mode::ZPX, 6, 0, NZC),                               // 0x16
+    Opcode::new("SLO", Mode::ZPX, 6, 0, NZC).undocumented(),                // 0x17
+    Opcode::new("CLC", Mode::IMP, 2, 0, C),                                 // 0x18
+    Opcode::new("ORA", Mode::ABSY, 4, 1, NZ),                               // 0x19
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE).undocumented(),               // 0x1a
+    Opcode::new("SLO", Mode::ABSY, 7, 0, NZC).undocumented(),               // 0x1b
+    Opcode::new("NOP", Mode::ABSX, 4, 1, NONE).undocumented(),              // 0x1c
+    Opcode::new("ORA", Mode::ABSX, 4, 1, NZ),                               // 0x1d
+    Opcode::new("ASL", Mode::ABSX, 7, 0, NZC),                              // 0x1e
+    Opcode::new("SLO", Mode::ABSX, 7, 0, NZC).undocumented(),               // 0x1f
+    Opcode::new("JSR", Mode::ABS, 6, 0, NONE),                              // 0x20
+    Opcode::new("AND", Mode::INDX, 6, 0, NZ),                               // 0x21
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x22
+    Opcode::new("RLA", Mode::INDX, 8, 0, NZC).undocumented(),               // 0x23
+    Opcode::new("BIT", Mode::ZP, 3, 0, NVZ),                                // 0x24
+    Opcode::new("AND", Mode::ZP, 3, 0, NZ),                                 // 0x25
+    Opcode::new("ROL", Mode::ZP, 5, 0, NZC),                                // 0x26
+    Opcode::new("RLA", Mode::ZP, 5, 0, NZC).undocumented(),                 // 0x27
+    Opcode::new("PLP", Mode::IMP, 4, 0, ALL),                               // 0x28
+    Opcode::new("AND", Mode::IMM, 2, 0, NZ),                                // 0x29
+    Opcode::new("ROL", Mode::ACC, 2, 0, NZC),                               // 0x2a
+    Opcode::new("ANC", Mode::IMM, 2, 0, NZC).undocumented(),                // 0x2b
+    Opcode::new("BIT", Mode::ABS, 4, 0, NVZ),                               // 0x2c
+    Opcode::new("AND", Mode::ABS, 4, 0, NZ),                                // 0x2d
+    Opcode::new("ROL", Mode::ABS, 6, 0, NZC),                               // 0x2e
+    Opcode::new("RLA", Mode::ABS, 6, 0, NZC).undocumented(),                // 0x2f
+    Opcode::new("BMI", Mode::REL, 2, 0, NONE),                              // 0x30
+    Opcode::new("AND", Mode::INDY, 5, 1, NZ),                               // 0x31
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x32
+    Opcode::new("RLA", Mode::INDY, 8, 0, NZC).undocumented(),               // 0x33
+    Opcode::new("NOP", Mode::ZPX, 4, 0, NONE).undocumented(),               // 0x34
+    Opcode::new("AND", Mode::ZPX, 4, 0, NZ),                                // 0x35
+    Opcode::new("ROL", Mode::ZPX, 6, 0, NZC),                               // 0x36
+    Opcode::new("RLA", Mode::ZPX, 6, 0, NZC).undocumented(),                // 0x37
+    Opcode::new("SEC", Mode::IMP, 2, 0, C),                                 // 0x38
+    Opcode::new("AND", Mode::ABSY, 4, 1, NZ),                               // 0x39
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE).undocumented(),               // 0x3a
+    Opcode::new("RLA", Mode::ABSY, 7, 0, NZC).undocumented(),               // 0x3b
+    Opcode::new("NOP", Mode::ABSX, 4, 1, NONE).undocumented(),              // 0x3c
+    Opcode::new("AND", Mode::ABSX, 4, 1, NZ),                               // 0x3d
+    Opcode::new("ROL", Mode::ABSX, 7, 0, NZC),                              // 0x3e
+    Opcode::new("RLA", Mode::ABSX, 7, 0, NZC).undocumented(),               // 0x3f
+    Opcode::new("RTI", Mode::IMP, 6, 0, ALL),                               // 0x40
+    Opcode::new("EOR", Mode::INDX, 6, 0, NZ),                               // 0x41
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x42
+    Opcode::new("SRE", Mode::INDX, 8, 0, NZC).undocumented(),               // 0x43
+    Opcode::new("NOP", Mode::ZP, 3, 0, NONE).undocumented(),                // 0x44
+    Opcode::new("EOR", Mode::ZP, 3, 0, NZ),                                 // 0x45
+    Opcode::new("LSR", Mode::ZP, 5, 0, NZC),                                // 0x46
+    Opcode::new("SRE", Mode::ZP, 5, 0, NZC).undocumented(),                 // 0x47
+    Opcode::new("PHA", Mode::IMP, 3, 0, NONE),                              // 0x48
+    Opcode::new("EOR", Mode::IMM, 2, 0, NZ),                                // 0x49
+    Opcode::new("LSR", Mode::ACC, 2, 0, NZC),                               // 0x4a
+    Opcode::new("ALR", Mode::IMM, 2, 0, NZC).undocumented(),                // 0x4b
+    Opcode::new("JMP", Mode::ABS, 3, 0, NONE),                              // 0x4c
+    Opcode::new("EOR", Mode::ABS, 4, 0, NZ),                                // 0x4d
+    Opcode::new("LSR", Mode::ABS, 6, 0, NZC),                               // 0x4e
+    Opcode::new("SRE", Mode::ABS, 6, 0, NZC).undocumented(),                // 0x4f
+    Opcode::new("BVC", Mode::REL, 2, 0, NONE),                              // 0x50
+    Opcode::new("EOR", Mode::INDY, 5, 1, NZ),                               // 0x51
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x52
+    Opcode::new("SRE", Mode::INDY, 8, 0, NZC).undocumented(),               // 0x53
+    Opcode::new("NOP", Mode::ZPX, 4, 0, NONE).undocumented(),               // 0x54
+    Opcode::new("EOR", Mode::ZPX, 4, 0, NZ),                                // 0x55
+    Opcode::new("LSR", Mode::ZPX, 6, 0, NZC),                               // 0x56
+    Opcode::new("SRE", Mode::ZPX, 6, 0, NZC).undocumented(),                // 0x57
+    Opcode::new("CLI", Mode::IMP, 2, 0, I),                                 // 0x58
+    Opcode::new("EOR", Mode::ABSY, 4, 1, NZ),                               // 0x59
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE).undocumented(),               // 0x5a
+    Opcode::new("SRE", Mode::ABSY, 7, 0, NZC).undocumented(),               // 0x5b
+    Opcode::new("NOP", Mode::ABSX, 4, 1, NONE).undocumented(),              // 0x5c
+    Opcode::new("EOR", Mode::ABSX, 4, 1, NZ),                               // 0x5d
+    Opcode::new("LSR", Mode::ABSX, 7, 0, NZC),                              // 0x5e
+    Opcode::new("SRE", Mode::ABSX, 7, 0, NZC).undocumented(),               // 0x5f
+    Opcode::new("RTS", Mode::IMP, 6, 0, NONE),                              // 0x60
+    Opcode::new("ADC", Mode::INDX, 6, 0, NVZC),                             // 0x61
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x62
+    Opcode::new("RRA", Mode::INDX, 8, 0, NVZC).undocumented(),              // 0x63
+    Opcode::new("NOP", Mode::ZP, 3, 0, NONE).undocumented(),                // 0x64
+    Opcode::new("ADC", Mode::ZP, 3, 0, NVZC),                               // 0x65
+    Opcode::new("ROR", Mode::ZP, 5, 0, NZC),                                // 0x66
+    Opcode::new("RRA", Mode::ZP, 5, 0, NVZC).undocumented(),                // 0x67
+    Opcode::new("PLA", Mode::IMP, 4, 0, NZ),                                // 0x68
+    Opcode::new("ADC", Mode::IMM, 2, 0, NVZC),                              // 0x69
+    Opcode::new("ROR", Mode::ACC, 2, 0, NZC),                               // 0x6a
+    Opcode::new("ARR", Mode::IMM, 2, 0, NVZC).undocumented(),               // 0x6b
+    Opcode::new("JMP", Mode::IND, 5, 0, NONE),                              // 0x6c
+    Opcode::new("ADC", Mode::ABS, 4, 0, NVZC),                              // 0x6d
+    Opcode::new("ROR", Mode::ABS, 6, 0, NZC),                               // 0x6e
+    Opcode::new("RRA", Mode::ABS, 6, 0, NVZC).undocumented(),               // 0x6f
+    Opcode::new("BVS", Mode::REL, 2, 0, NONE),                              // 0x70
+    Opcode::new("ADC", Mode::INDY, 5, 1, NVZC),                             // 0x71
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x72
+    Opcode::new("RRA", Mode::INDY, 8, 0, NVZC).undocumented(),              // 0x73
+    Opcode::new("NOP", Mode::ZPX, 4, 0, NONE).undocumented(),               // 0x74
+    Opcode::new("ADC", Mode::ZPX, 4, 0, NVZC),                              // 0x75
+    Opcode::new("ROR", Mode::ZPX, 6, 0, NZC),                               // 0x76
+    Opcode::new("RRA", Mode::ZPX, 6, 0, NVZC).undocumented(),               // 0x77
+    Opcode::new("SEI", Mode::IMP, 2, 0, I),                                 // 0x78
+    Opcode::new("ADC", Mode::ABSY, 4, 1, NVZC),                             // 0x79
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE).undocumented(),               // 0x7a
+    Opcode::new("RRA", Mode::ABSY, 7, 0, NVZC).undocumented(),              // 0x7b
+    Opcode::new("NOP", Mode::ABSX, 4, 1, NONE).undocumented(),              // 0x7c
+    Opcode::new("ADC", Mode::ABSX, 4, 1, NVZC),                             // 0x7d
+    Opcode::new("ROR", Mode::ABSX, 7, 0, NZC),                              // 0x7e
+    Opcode::new("RRA", Mode::ABSX, 7, 0, NVZC).undocumented(),              // 0x7f
+    Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented(),               // 0x80
+    Opcode::new("STA", Mode::INDX, 6, 0, NONE),                             // 0x81
+    Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented(),               // 0x82
+    Opcode::new("SAX", Mode::INDX, 6, 0, NONE).undocumented(),              // 0x83
+    Opcode::new("STY", Mode::ZP, 3, 0, NONE),                               // 0x84
+    Opcode::new("STA", Mode::ZP, 3, 0, NONE),                               // 0x85
+    Opcode::new("STX", Mode::ZP, 3, 0, NONE),                               // 0x86
+    Opcode::new("SAX", Mode::ZP, 3, 0, NONE).undocumented(),                // 0x87
+    Opcode::new("DEY", Mode::IMP, 2, 0, NZ),                                // 0x88
+    Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented(),               // 0x89
+    Opcode::new("TXA", Mode::IMP, 2, 0, NZ),                                // 0x8a
+    Opcode::UNDEFINED,                                                      // 0x8b
+    Opcode::new("STY", Mode::ABS, 4, 0, NONE),                              // 0x8c
+    Opcode::new("STA", Mode::ABS, 4, 0, NONE),                              // 0x8d
+    Opcode::new("STX", Mode::ABS, 4, 0, NONE),                              // 0x8e
+    Opcode::new("SAX", Mode::ABS, 4, 0, NONE).undocumented(),               // 0x8f
+    Opcode::new("BCC", Mode::REL, 2, 0, NONE),                              // 0x90
+    Opcode::new("STA", Mode::INDY, 6, 0, NONE),                             // 0x91
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0x92
+    Opcode::UNDEFINED,                                                      // 0x93
+    Opcode::new("STY", Mode::ZPX, 4, 0, NONE),                              // 0x94
+    Opcode::new("STA", Mode::ZPX, 4, 0, NONE),                              // 0x95
+    Opcode::new("STX", Mode::ZPY, 4, 0, NONE),                              // 0x96
+    Opcode::new("SAX", Mode::ZPY, 4, 0, NONE).undocumented(),               // 0x97
+    Opcode::new("TYA", Mode::IMP, 2, 0, NZ),                                // 0x98
+    Opcode::new("STA", Mode::ABSY, 5, 0, NONE),                             // 0x99
+    Opcode::new("TXS", Mode::IMP, 2, 0, NONE),                              // 0x9a
+    Opcode::UNDEFINED,                                                      // 0x9b
+    Opcode::UNDEFINED,                                                      // 0x9c
+    Opcode::new("STA", Mode::ABSX, 5, 0, NONE),                             // 0x9d
+    Opcode::UNDEFINED,                                                      // 0x9e
+    Opcode::UNDEFINED,                                                      // 0x9f
+    Opcode::new("LDY", Mode::IMM, 2, 0, NZ),                                // 0xa0
+    Opcode::new("LDA", Mode::INDX, 6, 0, NZ),                               // 0xa1
+    Opcode::new("LDX", Mode::IMM, 2, 0, NZ),                                // 0xa2
+    Opcode::new("LAX", Mode::INDX, 6, 0, NZ).undocumented(),                // 0xa3
+    Opcode::new("LDY", Mode::ZP, 3, 0, NZ),                                 // 0xa4
+    Opcode::new("LDA", Mode::ZP, 3, 0, NZ),                                 // 0xa5
+    Opcode::new("LDX", Mode::ZP, 3, 0, NZ),                                 // 0xa6
+    Opcode::new("LAX", Mode::ZP, 3, 0, NZ).undocumented(),                  // 0xa7
+    Opcode::new("TAY", Mode::IMP, 2, 0, NZ),                                // 0xa8
+    Opcode::new("LDA", Mode::IMM, 2, 0, NZ),                                // 0xa9
+    Opcode::new("TAX", Mode::IMP, 2, 0, NZ),                                // 0xaa
+    Opcode::UNDEFINED,                                                      // 0xab
+    Opcode::new("LDY", Mode::ABS, 4, 0, NZ),                                // 0xac
+    Opcode::new("LDA", Mode::ABS, 4, 0, NZ),                                // 0xad
+    Opcode::new("LDX", Mode::ABS, 4, 0, NZ),                                // 0xae
+    Opcode::new("LAX", Mode::ABS, 4, 0, NZ).undocumented(),                 // 0xaf
+    Opcode::new("BCS", Mode::REL, 2, 0, NONE),                              // 0xb0
+    Opcode::new("LDA", Mode::INDY, 5, 1, NZ),                               // 0xb1
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0xb2
+    Opcode::new("LAX", Mode::INDY, 5, 1, NZ).undocumented(),                // 0xb3
+    Opcode::new("LDY", Mode::ZPX, 4, 0, NZ),                                // 0xb4
+    Opcode::new("LDA", Mode::ZPX, 4, 0, NZ),                                // 0xb5
+    Opcode::new("LDX", Mode::ZPY, 4, 0, NZ),                                // 0xb6
+    Opcode::new("LAX", Mode::ZPY, 4, 0, NZ).undocumented(),                 // 0xb7
+    Opcode::new("CLV", Mode::IMP, 2, 0, V),                                 // 0xb8
+    Opcode::new("LDA", Mode::ABSY, 4, 1, NZ),                               // 0xb9
+    Opcode::new("TSX", Mode::IMP, 2, 0, NZ),                                // 0xba
+    Opcode::new("LAS", Mode::ABSY, 4, 1, NZ).undocumented(),                // 0xbb
+    Opcode::new("LDY", Mode::ABSX, 4, 1, NZ),                               // 0xbc
+    Opcode::new("LDA", Mode::ABSX, 4, 1, NZ),                               // 0xbd
+    Opcode::new("LDX", Mode::ABSY, 4, 1, NZ),                               // 0xbe
+    Opcode::new("LAX", Mode::ABSY, 4, 1, NZ).undocumented(),                // 0xbf
+    Opcode::new("CPY", Mode::IMM, 2, 0, NZC),                               // 0xc0
+    Opcode::new("CMP", Mode::INDX, 6, 0, NZC),                              // 0xc1
+    Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented(),               // 0xc2
+    Opcode::new("DCP", Mode::INDX, 8, 0, NZC).undocumented(),               // 0xc3
+    Opcode::new("CPY", Mode::ZP, 3, 0, NZC),                                // 0xc4
+    Opcode::new("CMP", Mode::ZP, 3, 0, NZC),                                // 0xc5
+    Opcode::new("DEC", Mode::ZP, 5, 0, NZ),                                 // 0xc6
+    Opcode::new("DCP", Mode::ZP, 5, 0, NZC).undocumented(),                 // 0xc7
+    Opcode::new("INY", Mode::IMP, 2, 0, NZ),                                // 0xc8
+    Opcode::new("CMP", Mode::IMM, 2, 0, NZC),                               // 0xc9
+    Opcode::new("DEX", Mode::IMP, 2, 0, NZ),                                // 0xca
+    Opcode::new("SBX", Mode::IMM, 2, 0, NZC).undocumented(),                // 0xcb
+    Opcode::new("CPY", Mode::ABS, 4, 0, NZC),                               // 0xcc
+    Opcode::new("CMP", Mode::ABS, 4, 0, NZC),                               // 0xcd
+    Opcode::new("DEC", Mode::ABS, 6, 0, NZ),                                // 0xce
+    Opcode::new("DCP", Mode::ABS, 6, 0, NZC).undocumented(),                // 0xcf
+    Opcode::new("BNE", Mode::REL, 2, 0, NONE),                              // 0xd0
+    Opcode::new("CMP", Mode::INDY, 5, 1, NZC),                              // 0xd1
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0xd2
+    Opcode::new("DCP", Mode::INDY, 8, 0, NZC).undocumented(),               // 0xd3
+    Opcode::new("NOP", Mode::ZPX, 4, 0, NONE).undocumented(),               // 0xd4
+    Opcode::new("CMP", Mode::ZPX, 4, 0, NZC),                               // 0xd5
+    Opcode::new("DEC", Mode::ZPX, 6, 0, NZ),                                // 0xd6
+    Opcode::new("DCP", Mode::ZPX, 6, 0, NZC).undocumented(),                // 0xd7
+    Opcode::new("CLD", Mode::IMP, 2, 0, D),                                 // 0xd8
+    Opcode::new("CMP", Mode::ABSY, 4, 1, NZC),                              // 0xd9
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE).undocumented(),               // 0xda
+    Opcode::new("DCP", Mode::ABSY, 7, 0, NZC).undocumented(),               // 0xdb
+    Opcode::new("NOP", Mode::ABSX, 4, 1, NONE).undocumented(),              // 0xdc
+    Opcode::new("CMP", Mode::ABSX, 4, 1, NZC),                              // 0xdd
+    Opcode::new("DEC", Mode::ABSX, 7, 0, NZ),                               // 0xde
+    Opcode::new("DCP", Mode::ABSX, 7, 0, NZC).undocumented(),               // 0xdf
+    Opcode::new("CPX", Mode::IMM, 2, 0, NZC),                               // 0xe0
+    Opcode::new("SBC", Mode::INDX, 6, 0, NVZC),                             // 0xe1
+    Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented(),               // 0xe2
+    Opcode::new("ISC", Mode::INDX, 8, 0, NVZC).undocumented(),              // 0xe3
+    Opcode::new("CPX", Mode::ZP, 3, 0, NZC),                                // 0xe4
+    Opcode::new("SBC", Mode::ZP, 3, 0, NVZC),                               // 0xe5
+    Opcode::new("INC", Mode::ZP, 5, 0, NZ),                                 // 0xe6
+    Opcode::new("ISC", Mode::ZP, 5, 0, NVZC).undocumented(),                // 0xe7
+    Opcode::new("INX", Mode::IMP, 2, 0, NZ),                                // 0xe8
+    Opcode::new("SBC", Mode::IMM, 2, 0, NVZC),                              // 0xe9
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE),                              // 0xea
+    Opcode::new("SBC", Mode::IMM, 2, 0, NVZC).undocumented(),               // 0xeb
+    Opcode::new("CPX", Mode::ABS, 4, 0, NZC),                               // 0xec
+    Opcode::new("SBC", Mode::ABS, 4, 0, NVZC),                              // 0xed
+    Opcode::new("INC", Mode::ABS, 6, 0, NZ),                                // 0xee
+    Opcode::new("ISC", Mode::ABS, 6, 0, NVZC).undocumented(),               // 0xef
+    Opcode::new("BEQ", Mode::REL, 2, 0, NONE),                              // 0xf0
+    Opcode::new("SBC", Mode::INDY, 5, 1, NVZC),                             // 0xf1
+    Opcode::new("JAM", Mode::IMP, 0, 0, NONE).undocumented(),               // 0xf2
+    Opcode::new("ISC", Mode::INDY, 8, 0, NVZC).undocumented(),              // 0xf3
+    Opcode::new("NOP", Mode::ZPX, 4, 0, NONE).undocumented(),               // 0xf4
+    Opcode::new("SBC", Mode::ZPX, 4, 0, NVZC),                              // 0xf5
+    Opcode::new("INC", Mode::ZPX, 6, 0, NZ),                                // 0xf6
+    Opcode::new("ISC", Mode::ZPX, 6, 0, NVZC).undocumented(),               // 0xf7
+    Opcode::new("SED", Mode::IMP, 2, 0, D),                                 // 0xf8
+    Opcode::new("SBC", Mode::ABSY, 4, 1, NVZC),                             // 0xf9
+    Opcode::new("NOP", Mode::IMP, 2, 0, NONE).undocumented(),               // 0xfa
+    Opcode::new("ISC", Mode::ABSY, 7, 0, NVZC).undocumented(),              // 0xfb
+    Opcode::new("NOP", Mode::ABSX, 4, 1, NONE).undocumented(),              // 0xfc
+    Opcode::new("SBC", Mode::ABSX, 4, 1, NVZC),                             // 0xfd
+    Opcode::new("INC", Mode::ABSX, 7, 0, NZ),                               // 0xfe
+    Opcode::new("ISC", Mode::ABSX, 7, 0, NVZC).undocumented(),              // 0xff
+];
+
+/// Opcodes of the 65C02
+///
+/// Decimal mode ADC and SBC take one more cycle, undefined opcodes are NOPs of 1 to 3 bytes
+pub const CMOS_OPCODES: [Opcode; 256] = with_changes(with_short_nops(NMOS_OPCODES), &CMOS_CHANGES);
+
+/// Opcodes of the WDC 65C02, the 65C02 plus the bit instructions, WAI and STP
+pub const WDC_OPCODES: [Opcode; 256] = with_changes(CMOS_OPCODES, &WDC_CHANGES);
+
+// Opcodes of the 65C02 that differ from the NMOS 6502, besides the 1 byte NOPs
+const CMOS_CHANGES: [(u8, Opcode); 40] = [
+    (0x00, Opcode::new("BRK", Mode::IMP, 7, 0, ID)),
+    (0x02, Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented()),
+    (0x04, Opcode::new("TSB", Mode::ZP, 5, 0, Z)),
+    (0x0c, Opcode::new("TSB", Mode::ABS, 6, 0, Z)),
+    (0x12, Opcode::new("ORA", Mode::ZPI, 5, 0, NZ)),
+    (0x14, Opcode::new("TRB", Mode::ZP, 5, 0, Z)),
+    (0x1a, Opcode::new("INC", Mode::ACC, 2, 0, NZ)),
+    (0x1c, Opcode::new("TRB", Mode::ABS, 6, 0, Z)),
+    (0x1e, Opcode::new("ASL", Mode::ABSX, 6, 1, NZC)),
+    (0x22, Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented()),
+    (0x32, Opcode::new("AND", Mode::ZPI, 5, 0, NZ)),
+    (0x34, Opcode::new("BIT", Mode::ZPX, 4, 0, NVZ)),
+    (0x3a, Opcode::new("DEC", Mode::ACC, 2, 0, NZ)),
+    (0x3c, Opcode::new("BIT", Mode::ABSX, 4, 1, NVZ)),
+    (0x3e, Opcode::new("ROL", Mode::ABSX, 6, 1, NZC)),
+    (0x42, Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented()),
+    (0x52, Opcode::new("EOR", Mode::ZPI, 5, 0, NZ)),
+    (0x5a, Opcode::new("PHY", Mode::IMP, 3, 0, NONE)),
+    (0x5c, Opcode::new("NOP", Mode::ABS, 8, 0, NONE).undocumented()),
+    (0x5e, Opcode::new("LSR", Mode::ABSX, 6, 1, NZC)),
+    (0x62, Opcode::new("NOP", Mode::IMM, 2, 0, NONE).undocumented()),
+    (0x64, Opcode::new("STZ", Mode::ZP, 3, 0, NONE)),
+    (0x6c, Opcode::new("JMP", Mode::IND, 6, 0, NONE)),
+    (0x72, Opcode::new("ADC", Mode::ZPI, 5, 0, NVZC)),
+    (0x74, Opcode::new("STZ", Mode::ZPX, 4, 0, NONE)),
+    (0x7a, Opcode::new("PLY", Mode::IMP, 4, 0, NZ)),
+    (0x7c, Opcode::new("JMP", Mode::ABSINDX, 6, 0, NONE)),
+    (0x7e, Opcode::new("ROR", Mode::ABSX, 6, 1, NZC)),
+    (0x80, Opcode::new("BRA", Mode::REL, 2, 0, NONE)),
+    (0x89, Opcode::new("BIT", Mode::IMM, 2, 0, Z)),
+    (0x92, Opcode::new("STA", Mode::ZPI, 5, 0, NONE)),
+    (0x9c, Opcode::new("STZ", Mode::ABS, 4, 0, NONE)),
+    (0x9e, Opcode::new("STZ", Mode::ABSX, 5, 0, NONE)),
+    (0xb2, Opcode::new("LDA", Mode::ZPI, 5, 0, NZ)),
+    (0xd2, Opcode::new("CMP", Mode::ZPI, 5, 0, NZC)),
+    (0xda, Opcode::new("PHX", Mode::IMP, 3, 0, NONE)),
+    (0xdc, Opcode::new("NOP", Mode::ABS, 4, 0, NONE).undocumented()),
+    (0xf2, Opcode::new("SBC", Mode::ZPI, 5, 0, NVZC)),
+    (0xfa, Opcode::new("PLX", Mode::IMP, 4, 0, NZ)),
+    (0xfc, Opcode::new("NOP", Mode::ABS, 4, 0, NONE).undocumented()),
+];
+
+// Opcodes of the WDC 65C02 that differ from the 65C02
+const WDC_CHANGES: [(u8, Opcode); 34] = [
+    (0x07, Opcode::new("RMB0", Mode::ZP, 5, 0, NONE)),
+    (0x0f, Opcode::new("BBR0", Mode::ZPREL, 5, 0, NONE)),
+    (0x17, Opcode::new("RMB1", Mode::ZP, 5, 0, NONE)),
+    (0x1f, Opcode::new("BBR1", Mode::ZPREL, 5, 0, NONE)),
+    (0x27, Opcode::new("RMB2", Mode::ZP, 5, 0, NONE)),
+    (0x2f, Opcode::new("BBR2", Mode::ZPREL, 5, 0, NONE)),
+    (0x37, Opcode::new("RMB3", Mode::ZP, 5, 0, NONE)),
+    (0x3f, Opcode::new("BBR3", Mode::ZPREL, 5, 0, NONE)),
+    (0x47, Opcode::new("RMB4", Mode::ZP, 5, 0, NONE)),
+    (0x4f, Opcode::new("BBR4", Mode::ZPREL, 5, 0, NONE)),
+    (0x57, Opcode::new("RMB5", Mode::ZP, 5, 0, NONE)),
+    (0x5f, Opcode::new("BBR5", Mode::ZPREL, 5, 0, NONE)),
+    (0x67, Opcode::new("RMB6", Mode::ZP, 5, 0, NONE)),
+    (0x6f, Opcode::new("BBR6", Mode::ZPREL, 5, 0, NONE)),
+    (0x77, Opcode::new("RMB7", Mode::ZP, 5, 0, NONE)),
+    (0x7f, Opcode::new("BBR7", Mode::ZPREL, 5, 0, NONE)),
+    (0x87, Opcode::new("SMB0", Mode::ZP, 5, 0, NONE)),
+    (0x8f, Opcode::new("BBS0", Mode::ZPREL, 5, 0, NONE)),
+    (0x97, Opcode::new("SMB1", Mode::ZP, 5, 0, NONE)),
+    (0x9f, Opcode::new("BBS1", Mode::ZPREL, 5, 0, NONE)),
+    (0xa7, Opcode::new("SMB2", Mode::ZP, 5, 0, NONE)),
+    (0xaf, Opcode::new("BBS2", Mode::ZPREL, 5, 0, NONE)),
+    (0xb7, Opcode::new("SMB3", Mode::ZP, 5, 0, NONE)),
+    (0xbf, Opcode::new("BBS3", Mode::ZPREL, 5, 0, NONE)),
+    (0xc7, Opcode::new("SMB4", Mode::ZP, 5, 0, NONE)),
+    (0xcb, Opcode::new("WAI", Mode::IMP, 3, 0, NONE)),
+    (0xcf, Opcode::new("BBS4", Mode::ZPREL, 5, 0, NONE)),
+    (0xd7, Opcode::new("SMB5", Mode::ZP, 5, 0, NONE)),
+    (0xdb, Opcode::new("STP", Mode::IMP, 3, 0, NONE)),
+    (0xdf, Opcode::new("BBS5", Mode::ZPREL, 5, 0, NONE)),
+    (0xe7, Opcode::new("SMB6", Mode::ZP, 5, 0, NONE)),
+    (0xef, Opcode::new("BBS6", Mode::ZPREL, 5, 0, NONE)),
+    (0xf7, Opcode::new("SMB7", Mode::ZP, 5, 0, NONE)),
+    (0xff, Opcode::new("BBS7", Mode::ZPREL, 5, 0, NONE)),
+];
+
+/// `base` with the opcodes in `changes` replaced
+const fn with_changes(base: [Opcode; 256], changes: &[(u8, Opcode)]) -> [Opcode; 256]
+{
+    let mut table = base;
+    let mut i = 0;
+    while i < changes.len()
+    {
+        let (opcode, op) = changes[i];
+        table[opcode as usize] = op;
+        i += 1;
+    }
+    table
+}
+
+/// `base` with $x3, $x7, $xB and $xF turned into 1 byte NOPs, as on the 65C02
+const fn with_short_nops(base: [Opcode; 256]) -> [Opcode; 256]
+{
+    let mut table = base;
+    let mut opcode = 0x03;
+    while opcode < 256
+    {
+        table[opcode] = Opcode::new("NOP", Mode::IMP, 1, 0, NONE).undocumented();
+        opcode += 4;
+    }
+    table
 }
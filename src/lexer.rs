@@ -1,7 +1,10 @@
 // TODO : Is this the correct way of including the file?
 use crate::isa::*;
-use std::collections::HashMap;
-use std::num::ParseIntError;
+use crate::mapper::{MapperKind, MAX_BANKS};
+use crate::diagnostic::{Diagnostic, Severity};
+use std::collections::{HashMap, HashSet};
+use std::num::{IntErrorKind, ParseIntError};
+use std::path::Path;
 
 
 #[allow(dead_code)]
@@ -29,6 +32,11 @@ pub enum TokenType
     REGX,
     REGY,
     REGA,
+    DIRECTIVE,
+    /// Text between double quotes, without them
+    STRING,
+    /// Operand with operators, `table+1` or `<msg`, evaluated once the labels are placed
+    EXPRESSION,
     EOF,
 
 }
@@ -40,13 +48,50 @@ pub struct Token
     pub ttype : TokenType ,
     pub tstring: String,
     pub line_no : u32,
+    /// Column of the first character, starting at 1
+    pub column : u32,
 }
 
+#[derive(Default)]
 pub struct Lexer
 {
     pub tokens : Vec<Token>,
     pub current_token: usize,
+    /// Addresses of the labels by qualified name, `main`, `main.loop` for a local label,
+    /// `draw.loop` for a label of `.proc draw` and `:1`, `:2`... for the anonymous ones
     pub labels : HashMap<String, u16>,
+    /// Values named by `NAME = value` and `define NAME value`
+    pub constants : HashMap<String, u16>,
+    /// Instruction set accepted by the assembler, changed by `.cpu 6502`, `.cpu 65c02` and `.cpu w65c02`
+    pub cpu : Variant,
+    /// Mapper the banks are assembled for, changed by `.mapper latch`, `.mapper fixed` and `.mapper window`
+    pub mapper : MapperKind,
+    /// Code of each `.bank`, assembled for the window of the bank
+    pub banks : Vec<Vec<u8>>,
+    /// Name of the source in diagnostics, set by the caller
+    pub file : String,
+    /// Text given to tokenize, diagnostics quote its lines
+    pub source : String,
+    /// Problems found by parse, in the order they were found
+    pub diagnostics : Vec<Diagnostic>,
+    /// Address of the first byte of the code returned by parse, the lowest `.org` or $0600
+    pub origin : u16,
+    /// Address of the statement being assembled, the value of `*` in expressions
+    pub pc : u32,
+    /// Labels the label pass has not placed yet, the values that decide where code goes can't use them
+    pub unplaced : HashSet<String>,
+    /// Tokens that name no label, they are read as address 0 and the branches to them are
+    /// not checked, the unknown name is the error
+    pub unknown : HashSet<usize>,
+}
+
+/// Code assembled at one address, `.org` and `.bank` start a new one
+struct Chunk
+{
+    address: u32,
+    /// Directive that placed the code, errors about the whole chunk point at it
+    token: usize,
+    code: Vec<u8>,
 }
 
 #[allow(dead_code)]
@@ -98,75 +143,136 @@ impl Lexer
     {
         self.current_token += steps;
     }
-    pub fn get_operand_u8(&self) -> Result<u8, ParseIntError>
+    /// Why `text` written in `radix` could not be read as a value of `size`
+    fn number_error(e: &ParseIntError, text: &str, radix: u32, size: &str) -> String
     {
-        if let Some(nt) = self.next()
+        let (prefix, name) = match radix
         {
-            if nt.ttype == TT::DOLLAR
-            {
-                if let Some(nt2) = self.nextx(2)
-                {
-                    return u8::from_str_radix(&nt2.tstring, 16);
-                }
+            16 => ("$", "hex"),
+            2 => ("%", "binary"),
+            _ => ("", "decimal"),
+        };
+
+        match e.kind()
+        {
+            IntErrorKind::Empty => "Expected a number".to_string(),
+            IntErrorKind::PosOverflow => format!("{}{} does not fit in {}", prefix, text, size),
+            _ => format!("Invalid {} number {:?}", name, text),
+        }
+    }
+
+    /// `value` in hex, or in decimal when it is negative
+    fn format_value(value: i32) -> String
+    {
+        if value < 0 { value.to_string() } else { format!("${:x}", value) }
+    }
+
+    /// Digits and radix of the operand after the current token
+    fn operand_digits(&self) -> Result<(&str, u32), String>
+    {
+        let nt = match self.next()
+        {
+            Some(nt) => nt,
+            None => return Err("Expected an operand".to_string()),
+        };
+
+        let radix = match nt.ttype
+        {
+            TT::DOLLAR => 16,
+            TT::PERCENT => 2,
+            TT::LABEL_OPERAND => return Ok((&nt.tstring, 16)),
+            _ => return Ok((&nt.tstring, 10)),
+        };
+
+        match self.nextx(2)
+        {
+            Some(nt2) if nt2.line_no == nt.line_no && nt2.ttype != TT::EOF => Ok((&nt2.tstring, radix)),
+            _ => Err(format!("Expected a number after {}", nt.tstring)),
+        }
+    }
 
-                panic!("INVALID hex value after {} at {}", nt.tstring, nt.line_no);
+    /// Value of the expression `text`, the names in it are labels
+    fn evaluate(&self, text: &str) -> Result<i32, String>
+    {
+        crate::expr::evaluate(text, &|name|
+        {
+            if name == "*"
+            {
+                return Ok(self.pc as i32);
             }
-            if nt.ttype == TT::PERCENT
+            if let Some(&value) = self.constants.get(name)
             {
-                if let Some(nt2) = self.nextx(2)
-                {
-                    return u8::from_str_radix(&nt2.tstring, 2);
-                }
-
-                panic!("INVALID bin value after {} at {}", nt.tstring, nt.line_no);
+                return Ok(i32::from(value));
             }
-
-            if nt.ttype == TT::LABEL_OPERAND
+            if self.unplaced.contains(name)
             {
-                let val = u16::from_str_radix(&nt.tstring, 16).unwrap();
-                if val > 255
-                {
-                    return u8::from_str_radix("invalidstring", 16);
-                }
+                return Err(format!("{} must be defined before it is used here", name));
+            }
 
-                return u8::from_str_radix(&nt.tstring, 16);
+            match self.labels.get(name)
+            {
+                Some(&value) => Ok(i32::from(value)),
+                None => Err(format!("Unknown label {:?}", name)),
             }
+        })
+    }
 
-            return u8::from_str_radix(&nt.tstring, 10);
+    /// Whether the expression `text` is one byte: its < or >, or a value up to $ff that doesn't
+    /// use labels or `*`, which are addresses. Decides between zero page and absolute addressing
+    fn is_byte_expression(&self, text: &str) -> bool
+    {
+        if (text.starts_with('<') && !text.starts_with("<<")) || (text.starts_with('>') && !text.starts_with(">>"))
+        {
+            return true;
         }
 
-        return u8::from_str_radix("raise err", 10);
+        let constant = |name: &str| self.constants.get(name).map(|&value| i32::from(value)).ok_or_else(String::new);
+        crate::expr::evaluate(text, &constant).is_ok_and(|value| (0..=0xff).contains(&value))
     }
 
-    pub fn get_operand_u16(&self) -> Result<u16, ParseIntError>
+    pub fn get_operand_u8(&self) -> Result<u8, String>
     {
-        if let Some(nt) = self.next()
+        if let Some(nt) = self.next().filter(|nt| nt.ttype == TT::EXPRESSION)
         {
-            if nt.ttype == TT::DOLLAR
+            let value = self.evaluate(&nt.tstring)?;
+            if !(-0x80..=0xff).contains(&value)
             {
-                if let Some(nt2) = self.nextx(2)
-                {
-                    return u16::from_str_radix(&nt2.tstring, 16);
-                }
+                return Err(format!("{} is {}, it does not fit in one byte", nt.tstring, Lexer::format_value(value)));
             }
-            if nt.ttype == TT::PERCENT
+            return Ok(value as u8);
+        }
+
+        let (text, radix) = self.operand_digits()?;
+
+        // Labels are addresses, only the ones in the zero page fit
+        if self.next().is_some_and(|nt| nt.ttype == TT::LABEL_OPERAND)
+        {
+            let address = u16::from_str_radix(text, 16).map_err(|e| Lexer::number_error(&e, text, 16, "two bytes"))?;
+            if address > 0xff
             {
-                if let Some(nt2) = self.nextx(2)
-                {
-                    return u16::from_str_radix(&nt2.tstring, 2);
-                }
+                return Err(format!("Address ${:04x} is not in the zero page", address));
             }
+            return Ok(address as u8);
+        }
 
-            if nt.ttype == TT::LABEL_OPERAND
+        u8::from_str_radix(text, radix).map_err(|e| Lexer::number_error(&e, text, radix, "one byte"))
+    }
+
+    pub fn get_operand_u16(&self) -> Result<u16, String>
+    {
+        if let Some(nt) = self.next().filter(|nt| nt.ttype == TT::EXPRESSION)
+        {
+            let value = self.evaluate(&nt.tstring)?;
+            if !(-0x8000..=0xffff).contains(&value)
             {
-                return u16::from_str_radix(&nt.tstring, 16);
+                return Err(format!("{} is {}, it does not fit in two bytes", nt.tstring, Lexer::format_value(value)));
             }
-
-            return u16::from_str_radix(&nt.tstring, 10);
+            return Ok(value as u16);
         }
 
-        return u16::from_str_radix("raise err", 10);
+        let (text, radix) = self.operand_digits()?;
 
+        u16::from_str_radix(text, radix).map_err(|e| Lexer::number_error(&e, text, radix, "two bytes"))
     }
 
     pub fn next_mode(&self) -> u32
@@ -177,7 +283,12 @@ impl Lexer
             {
                 return Mode::IMP;
             }
-            if nt.ttype == TT::LABEL
+            if nt.ttype == TT::LABEL || nt.ttype == TT::DIRECTIVE || nt.ttype == TT::INSTRUCTION
+            {
+                return Mode::IMP;
+            }
+            // Operands are on the line of their instruction
+            if self.current().is_some_and(|t| t.line_no != nt.line_no)
             {
                 return Mode::IMP;
             }
@@ -190,71 +301,60 @@ impl Lexer
             {
                 return Mode::ACC;
             }
+            // INC A, the lone A is still an unknown token or was read as a number
+            if (nt.ttype == TT::UNKNOWN || nt.ttype == TT::NUMBER)
+                && nt.tstring.eq_ignore_ascii_case("a")
+                && !self.labels.contains_key(&nt.tstring)
+            {
+                return Mode::ACC;
+            }
 
             if nt.ttype == TT::BRACKETOPEN
             {
-                if let Some(nt2) = self.nextx(2)
+                let is_jmp = self.current().is_some_and(|t| t.tstring == "JMP");
+
+                // Find the closing bracket to tell ($nn,X), ($nn),Y, ($nn) and ($nnnn) apart
+                for offset in 2..7
                 {
-                    // println!("Type: {:?}, String : {:?}, Line No : {}", nt2.ttype, nt2.tstring, nt2.line_no);
-                    if nt2.ttype == TT::UNKNOWN || nt2.ttype == TT::NUMBER
+                    if let Some(t) = self.nextx(offset)
                     {
-                        if let Some(nt3) = self.nextx(3)
+                        if t.ttype != TT::BRACKETCLOSE
                         {
-                            if nt3.ttype == TT::BRACKETCLOSE
-                            {
-                                return Mode::INDY;
-                            }
-                            else
-                            {
-                                if let Some(nt4) = self.nextx(4)
-                                {
-                                    if nt4.ttype == TT::BRACKETCLOSE
-                                    {
-                                        return Mode::IND;
-                                    }
-                                    else if nt4.ttype == TT::REGX
-                                    {
-                                        return Mode::INDX;
-                                    }
-
-                                    return Mode::UNKNOWN;
-                                }
-                            }
+                            continue;
                         }
-                    }
-                    else if nt2.ttype == TT::DOLLAR || nt2.ttype == TT::PERCENT
-                    {
-                        if let Some(nt4) = self.nextx(4)
+
+                        if self.nextx(offset - 1).is_some_and(|pt| pt.ttype == TT::REGX)
                         {
-                            if nt4.ttype == TT::BRACKETCLOSE
+                            // JMP ($nnnn,X) takes a 16-bit operand
+                            if is_jmp
                             {
-                                return Mode::INDY;
+                                return Mode::ABSINDX;
                             }
-                            else
-                            {
-                                if let Some(nt5) = self.nextx(5)
-                                {
-                                    if nt5.ttype == TT::BRACKETCLOSE
-                                    {
-                                        return Mode::IND;
-                                    }
-                                    else if nt5.ttype == TT::REGX
-                                    {
-                                        return Mode::INDX;
-                                    }
+                            return Mode::INDX;
+                        }
 
-                                    return Mode::UNKNOWN;
-                                }
-                            }
+                        if self.nextx(offset + 1).is_some_and(|nt| nt.ttype == TT::COMMA)
+                        {
+                            return Mode::INDY;
+                        }
+
+                        if !is_jmp && self.is_zp_pointer()
+                        {
+                            return Mode::ZPI;
                         }
 
+                        return Mode::IND;
                     }
-                    return Mode::UNKNOWN;
                 }
+
                 return Mode::UNKNOWN;
             }
             if let Some(t) = self.current()
             {
+                if Lexer::is_bit_branch(&t.tstring)
+                {
+                    return Mode::ZPREL;
+                }
                 if t.tstring == "BCC"
                     || t.tstring == "BCS"
                         || t.tstring == "BEQ"
@@ -263,11 +363,31 @@ impl Lexer
                         || t.tstring == "BPL"
                         || t.tstring == "BVC"
                         || t.tstring == "BVS"
+                        || t.tstring == "BRA"
                         {
                             return Mode::REL;
                         }
             }
+            if nt.ttype == TT::EXPRESSION
+            {
+                let byte = self.is_byte_expression(&nt.tstring);
+
+                if self.nextx(2).is_some_and(|nt2| nt2.ttype == TT::COMMA)
+                {
+                    return match self.nextx(3).map(|nt3| &nt3.ttype)
+                    {
+                        Some(TT::REGX) => if byte { Mode::ZPX } else { Mode::ABSX },
+                        Some(TT::REGY) => if byte { Mode::ZPY } else { Mode::ABSY },
+                        _ => Mode::UNKNOWN,
+                    };
+                }
+
+                return if byte { Mode::ZP } else { Mode::ABS };
+            }
+
+            // Labels are always absolute, before they are resolved they are still unknown tokens
             if nt.ttype == TT::LABEL_OPERAND
+                || (nt.ttype == TT::UNKNOWN && self.labels.contains_key(&nt.tstring))
             {
                 if nt.ttype == TT::BRACKETOPEN
                 {
@@ -308,7 +428,7 @@ impl Lexer
             {
                 if let Some(nt2) = self.nextx(2)
                 {
-                    if nt2.tstring.len() <= 2 && nt2.ttype == TT::UNKNOWN
+                    if nt2.tstring.len() <= 2 && (nt2.ttype == TT::UNKNOWN || nt2.ttype == TT::NUMBER)
                     {
                         if let Some(nt3) = self.nextx(3)
                         {
@@ -425,890 +545,1538 @@ impl Lexer
                     return Mode::ABS;
                 }
             }
-            if nt.ttype == TT::NUMBER
+            // $ or % followed by something that is not a number, get_operand_u16 tells what is wrong
+            if nt.ttype == TT::DOLLAR || nt.ttype == TT::PERCENT
             {
-                let op = u16::from_str_radix(&nt.tstring, 10).unwrap();
-                if op < 256
+                let radix = if nt.ttype == TT::DOLLAR { 16 } else { 2 };
+
+                return match self.nextx(2).map(|nt2| u16::from_str_radix(&nt2.tstring, radix))
                 {
-                    return Mode::ZP;
-                }
+                    Some(Ok(op)) if op < 256 => Mode::ZP,
+                    Some(Ok(_)) => Mode::ABS,
+                    _ => Mode::UNKNOWN,
+                };
+            }
+            return Mode::IMP;
+        }
 
-                return Mode::ABS;
+        Mode::IMP
+    }
 
+    /// Whether the operand of ($nn) is a zero page address, the same way next_mode tells ZP and ABS apart
+    fn is_zp_pointer(&self) -> bool
+    {
+        if let Some(nt) = self.nextx(2)
+        {
+            if nt.ttype == TT::DOLLAR || nt.ttype == TT::PERCENT
+            {
+                return self.nextx(3).is_some_and(|nt2| nt2.tstring.len() <= 2);
             }
-
-            if nt.ttype == TT::DOLLAR
+            if nt.ttype == TT::EXPRESSION
             {
-                if let Some(nt2) = self.nextx(2)
-                {
-                    let op = u16::from_str_radix(&nt2.tstring, 16).unwrap();
-                    if op < 256
-                    {
-                        return Mode::ZP;
-                    }
+                return self.is_byte_expression(&nt.tstring);
+            }
 
-                    return Mode::ABS;
-                }
-                return Mode::UNKNOWN;
+            return nt.tstring.len() <= 3 && (nt.ttype == TT::UNKNOWN || nt.ttype == TT::NUMBER);
+        }
 
-            }
+        false
+    }
 
-            if nt.ttype == TT::PERCENT
-            {
-                if let Some(nt2) = self.nextx(2)
-                {
-                    let op = u16::from_str_radix(&nt2.tstring, 2);
+    /// Whether the instruction is one of BBR0-7 or BBS0-7
+    fn is_bit_branch(mnemonic: &str) -> bool
+    {
+        mnemonic.len() == 4 && (mnemonic.starts_with("BBR") || mnemonic.starts_with("BBS"))
+    }
 
-                    if op.unwrap() < 256
-                    {
-                        return Mode::ZP;
-                    }
+    /// Signed offset from the instruction ending at `next_pc` to a branch target
+    fn branch_offset(target: u16, next_pc: i32) -> Result<u8, String>
+    {
+        let offset = i32::from(target) - next_pc;
 
-                    return Mode::ABS;
+        if !(-128..=127).contains(&offset)
+        {
+            return Err(format!("Branch target ${:04x} out of range, {} bytes away", target, offset));
+        }
 
-                }
+        Ok(offset as u8)
+    }
 
-                return Mode::UNKNOWN;
+    /// Report an error at the token `index`, an error already reported at the same place is not repeated
+    fn error(&mut self, index: usize, message: String)
+    {
+        let token = &self.tokens[index.min(self.tokens.len() - 1)];
+        let (line, column) = (token.line_no, token.column);
+
+        let diagnostic = Diagnostic { severity: Severity::Error, file: self.file.clone(), line, column, message };
+
+        // Both passes read the same lines and find some errors twice
+        if !self.diagnostics.contains(&diagnostic)
+        {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    /// Report an error at the operand of the current token, or at the token when the line ends there
+    fn operand_error(&mut self, message: String)
+    {
+        let line_no = self.tokens[self.current_token].line_no;
+        let index = match self.next()
+        {
+            Some(nt) if nt.line_no == line_no && nt.ttype != TT::EOF => self.current_token + 1,
+            _ => self.current_token,
+        };
 
+        self.error(index, message);
+    }
+
+    /// Whether parse reported an error
+    pub fn has_errors(&self) -> bool
+    {
+        self.diagnostics.iter().any(|d| d.severity == Severity::Error)
+    }
+
+    /// Whether the token is a mnemonic of the opcode tables, DCB or KIL, written in upper or lower case
+    fn is_instruction(token: &str) -> bool
+    {
+        let upper = token.to_uppercase();
+        if token != upper && token != token.to_lowercase()
+        {
+            return false;
+        }
+
+        upper == "DCB" || upper == "KIL" || is_mnemonic(&upper)
+    }
+
+    /// Opcode of `mnemonic` in `mode` on `variant`, the A of instructions working on
+    /// the accumulator can be left out
+    fn encode(variant: Variant, mnemonic: &str, mode: u32) -> Option<u8>
+    {
+        crate::isa::encode(variant, mnemonic, mode).or_else(|| match mode
+        {
+            Mode::IMP => crate::isa::encode(variant, mnemonic, Mode::ACC),
+            _ => None,
+        })
+    }
+
+    /// Number of bytes emitted by the DCB, `.byte` or `.word` at the current token, `size`
+    /// per comma separated value and one per character of the strings
+    fn data_len(&self, size: u32) -> u32
+    {
+        let mut len = 0;
+        let mut offset = 1;
+
+        while let Some(t) = self.nextx(offset)
+        {
+            if t.ttype == TT::DOLLAR || t.ttype == TT::PERCENT
+            {
+                offset += 1;
+            }
+
+            len += if t.ttype == TT::STRING { t.tstring.len() as u32 } else { size };
+            offset += 1;
+
+            match self.nextx(offset)
+            {
+                Some(nt) if nt.ttype == TT::COMMA => offset += 1,
+                _ => break,
             }
-            return Mode::IMP;
         }
 
-        return Mode::IMP;
+        len
     }
 
-    pub fn parse(&mut self) -> Vec<u8>
+    /// Emit the values of the DCB, `.byte` or `.word` at the current token, little-endian
+    ///
+    /// Values that can't be read are reported and emitted as zeros so data_len stays right
+    fn data(&mut self, size: u32, code: &mut Vec<u8>)
     {
-        let mut instruction_strings = vec!["ADC", "AND", "ASL", "BCC", "BCS", "BEQ", "BIT", "BMI", "BNE", "BPL", "BRK", "BVC","BVS", "CLC", "CLD", "CLI", "CLV", "CMP", "CPX", "CPY","DCB", "DEC", "DEX", "DEY", "EOR", "INC", "INX", "INY", "JMP", "JSR", "LDA", "LDX", "LDY", "LSR", "NOP", "ORA", "PHA", "PHP", "PLA", "PLP", "ROL", "ROR", "RTI", "RTS", "SBC", "SEC", "SED", "SEI", "STA", "STX", "STY", "TAX", "TAY", "TSX", "TXA", "TXS", "TYA","adc","and","asl","bcc","bcs","beq","bit","bmi","bne","bpl","brk","bvc","bvs","clc","cld","cli","clv","cmp","cpx","cpy","dcb", "dec","dex","dey","eor","inc","inx","iny","jmp","jsr","lda","ldx","ldy","lsr","nop","ora","pha","php","pla","plp","rol","ror","rti","rts","sbc","sec","sed","sei","sta","stx","sty","tax","tay","tsx","txa","txs","tya"];
-        instruction_strings.sort_unstable();
+        let line_no = self.tokens[self.current_token].line_no;
 
-        // First pass : Update unknown tokens and read labels
-        loop 
+        loop
         {
-            let t = self.current();
+            let (ttype, text) = match self.next()
+            {
+                Some(nt) if nt.line_no == line_no && nt.ttype != TT::EOF => (&nt.ttype, nt.tstring.clone()),
+                _ =>
+                {
+                    self.operand_error("Expected a value".to_string());
+                    return;
+                },
+            };
 
-            if let Some(t) = t
+            if *ttype == TT::STRING
             {
-                if t.ttype == TT::UNKNOWN
+                if size != 1
                 {
-                    // If instruction
-                    if instruction_strings.contains(&&t.tstring[..])
-                    { 
-                        self.tokens[self.current_token].ttype = TT::INSTRUCTION;
-                        self.tokens[self.current_token].tstring =
-                            self.tokens[self.current_token].tstring.to_uppercase();
-                    }
-                    // If label
-                    else if let Some(nt) = self.next()
-                    {
-                        if nt.ttype == TT::COLON
-                        {
-                            // Check for already defined labels
-                            match self.labels.get(&t.tstring)
-                            {
-                                Some(_) =>
-                                {
-                                    println!("Label {:?} at line {} already defined", t.tstring, t.line_no);
-                                    panic!();
-                                },
-                                None =>
-                                {
-                                    self.tokens[self.current_token].ttype = TT::LABEL;
-                                    self.labels.insert(self.tokens[self.current_token].tstring.clone(), 0);
-                                    continue;
-                                }
-                            }
-                        }
-                    }
+                    self.operand_error("Strings can only be used with .byte".to_string());
                 }
-
+                code.extend(text.bytes());
                 self.step();
             }
             else
             {
-                break;
+                let value = match size
+                {
+                    1 => self.get_operand_u8().map(u16::from),
+                    _ => self.get_operand_u16(),
+                };
+                let value = value.unwrap_or_else(|e|
+                {
+                    self.operand_error(e);
+                    0
+                });
+
+                code.extend(&value.to_le_bytes()[..size as usize]);
+
+                if self.next().is_some_and(|nt| nt.ttype == TT::DOLLAR || nt.ttype == TT::PERCENT)
+                {
+                    self.step();
+                }
+                self.step();
+            }
+
+            match self.next()
+            {
+                Some(nt) if nt.ttype == TT::COMMA && nt.line_no == line_no => self.step(),
+                Some(nt) if nt.line_no == line_no && nt.ttype != TT::EOF =>
+                {
+                    self.operand_error("Expected a comma between the values".to_string());
+                    return;
+                },
+                _ => return,
             }
         }
+    }
 
-        // Add labels to labels table
-        self.current_token = 0;
-        let mut mem_index = 0;
-        loop 
+    /// Values of the `.org`, `.res`, `.align` or `.fill` at the current token, one number or
+    /// up to `max` comma separated numbers
+    ///
+    /// The values must be numbers, they decide where the code that follows goes. None after
+    /// reporting an error
+    fn directive_values(&mut self, max: usize) -> Option<Vec<u16>>
+    {
+        let start = self.current_token;
+        let line_no = self.tokens[start].line_no;
+        let mut values = vec![];
+
+        let result = loop
         {
-            if let Some(t) = self.current()
+            match self.get_operand_u16()
             {
-                if t.ttype == TT::LABEL
+                Ok(value) => values.push(value),
+                Err(e) =>
                 {
-                    // TODO(James) : This seems messed up
-                    self.labels.insert(self.tokens[self.current_token].tstring.clone(), mem_index);
-                    self.step();
-                    continue;
-                }
+                    self.operand_error(e);
+                    break None;
+                },
+            }
 
-                if t.ttype == TT::INSTRUCTION
+            // Move to the number, a comma and another number may follow it
+            self.step();
+            if self.current().is_some_and(|t| t.ttype == TT::DOLLAR || t.ttype == TT::PERCENT)
+            {
+                self.step();
+            }
+
+            match self.next()
+            {
+                Some(nt) if nt.ttype == TT::COMMA && nt.line_no == line_no && values.len() < max => self.step(),
+                Some(nt) if nt.line_no == line_no && nt.ttype != TT::EOF =>
                 {
-                    mem_index += 1;
+                    let message = format!("{} takes at most {} value(s)", self.tokens[start].tstring, max);
+                    self.error(start, message);
+                    break None;
+                },
+                _ => break Some(values),
+            }
+        };
 
-                    let mode = self.next_mode();
+        self.current_token = start;
+        result
+    }
 
-                    if t.tstring == "JSR"
-                    {
-                        mem_index += 2;
-                        self.step();
-                        continue;
-                    }
+    /// Fill byte given after the count of `.res`, `.align` or `.fill`, 0 when there is none
+    fn fill_value(&mut self, values: &[u16]) -> u8
+    {
+        match values.get(1)
+        {
+            Some(&value) if value > 0xff =>
+            {
+                let message = format!("${:x} does not fit in one byte", value);
+                self.operand_error(message);
+                0
+            },
+            Some(&value) => value as u8,
+            None => 0,
+        }
+    }
 
-                    match mode 
-                    {
-
-                        Mode::ABS => mem_index+=2,
-                        Mode::ABSX => mem_index+=2,
-                        Mode::ABSY => mem_index+=2,
-                        Mode::IMM => mem_index+=1,
-                        Mode::IMP => {},
-                        Mode::IND => mem_index += 2,
-                        Mode::INDX => mem_index += 1,
-                        Mode::INDY => mem_index += 1,
-                        Mode::REL => mem_index += 1,
-                        Mode::ZP => mem_index += 1,
-                        Mode::ZPX => mem_index += 1,
-                        Mode::ZPY => mem_index += 1,
-                        _ =>
-                        {
-                            if t.tstring != "DCB"
-                            {
-                                panic!("Unknown addressing mode")
-                            }
-                        },
-                    }
+    /// Number of bytes `.align` adds at `pc`, None after reporting an error
+    fn align_len(&mut self, pc: u32) -> Option<(u32, u8)>
+    {
+        let values = self.directive_values(2)?;
+        let fill = self.fill_value(&values);
 
-                    self.step();
-                    continue;
-                }
+        match u32::from(values[0])
+        {
+            0 =>
+            {
+                self.operand_error("Can't align to a multiple of 0".to_string());
+                None
+            },
+            alignment => Some(((alignment - pc % alignment) % alignment, fill)),
+        }
+    }
 
-                if t.ttype == TT::UNKNOWN
-                {
-                    match self.labels.get(&t.tstring)
-                    {
-                        None =>
-                        {
-                            let mut valid = true;
-                            for tc in t.tstring.chars()
-                            {
-                                if tc < '0'
-                                {
-                                    valid = false;
-                                    break;
-                                }
-                                else if tc > '9' && tc < 'A'
-                                {
-                                    valid = false;
-                                    break;
-                                }
-                                else if tc > 'f'
-                                {
-                                    valid = false;
-                                    break;
-                                }
-                                else if tc < 'a' && tc > 'F'
-                                {
-                                    valid = false;
-                                    break;
-                                }
-                            }
+    /// Contents of the file named by the `.incbin` at the current token, read relative
+    /// to the source file. None after reporting an error
+    fn incbin(&mut self) -> Option<Vec<u8>>
+    {
+        let name = match self.next()
+        {
+            Some(nt) if nt.ttype == TT::STRING => nt.tstring.clone(),
+            _ =>
+            {
+                self.operand_error("Expected a file name in double quotes after .incbin".to_string());
+                return None;
+            },
+        };
 
-                            if valid
-                            {
-                                // println!("Type: {:?}, String : {:?}, Line No : {}", nt.ttype, nt.tstring, nt.line_no);
-                                self.tokens[self.current_token].ttype = TT::NUMBER;
-                            }
+        let path = Path::new(&self.file).parent().unwrap_or_else(|| Path::new("")).join(&name);
+        match std::fs::read(&path)
+        {
+            Ok(data) => Some(data),
+            Err(e) =>
+            {
+                self.operand_error(format!("Unable to read {} : {}", path.display(), e));
+                None
+            },
+        }
+    }
 
-                        },
-                        Some(_) => {}
-                    }
+    /// Lay out the chunks of a segment from `base`, gaps between them are filled with zeros
+    ///
+    /// The segment holds `size` bytes, `overflow` is the error for code that goes past them
+    fn link(&mut self, chunks: &[Chunk], base: u32, size: usize, overflow: &str) -> Vec<u8>
+    {
+        let mut image: Vec<u8> = vec![];
+        let mut order: Vec<&Chunk> = chunks.iter().filter(|chunk| !chunk.code.is_empty()).collect();
+        order.sort_by_key(|chunk| chunk.address);
 
-                    self.step();
-                    continue;
-                }
+        for chunk in order
+        {
+            if chunk.address < base
+            {
+                self.error(chunk.token, format!("Code at ${:04x} is below the start of its bank at ${:04x}", chunk.address, base));
+                continue;
             }
-            else
+
+            let start = (chunk.address - base) as usize;
+            let end = start + chunk.code.len();
+            if start < image.len()
             {
-                break;
+                self.error(chunk.token, format!("Code at ${:04x} overlaps the code placed before it", chunk.address));
+                continue;
+            }
+            if end > size
+            {
+                self.error(chunk.token, overflow.to_string());
+                continue;
             }
 
+            image.resize(start, 0);
+            image.extend_from_slice(&chunk.code);
+        }
+
+        image
+    }
+
+    /// Bank number and window address of the `.bank` directive at the current token,
+    /// written `.bank 2` or `.bank 2 $c000`, the window defaults to the mapper's
+    ///
+    /// None after reporting an error
+    fn bank_directive(&mut self) -> Option<(usize, u16)>
+    {
+        let start = self.current_token;
+        let line_no = self.tokens[start].line_no;
+
+        let bank = match self.get_operand_u16()
+        {
+            Ok(bank) => usize::from(bank),
+            Err(e) =>
+            {
+                self.operand_error(format!("Expected a bank number after .bank, {}", e));
+                return None;
+            },
+        };
+
+        // Move to the bank number, an address may follow it on the same line
+        self.step();
+        if self.current().is_some_and(|t| t.ttype == TT::DOLLAR || t.ttype == TT::PERCENT)
+        {
             self.step();
-            continue;
         }
 
+        let window = if self.next().is_some_and(|nt| nt.line_no == line_no && nt.ttype != TT::EOF)
+        {
+            match self.get_operand_u16()
+            {
+                Ok(window) => window,
+                Err(e) =>
+                {
+                    self.operand_error(format!("Expected the address of bank {}, {}", bank, e));
+                    self.current_token = start;
+                    return None;
+                },
+            }
+        }
+        else
+        {
+            self.mapper.window()
+        };
+
+        self.current_token = start;
+        Some((bank, window))
+    }
+
+    /// Why bank `bank` cannot be assembled for `window` with the selected mapper, None when it can
+    fn bank_error(&self, bank: usize, window: u16) -> Option<String>
+    {
+        if bank >= MAX_BANKS
+        {
+            return Some(format!("Bank {} is out of range, mappers select banks 0 to {}", bank, MAX_BANKS - 1));
+        }
+
+        let slots = self.mapper.slots();
+        if !slots.contains(&window)
+        {
+            let starts: Vec<String> = slots.iter().map(|slot| format!("${:04x}", slot)).collect();
+            return Some(format!("${:04x} is not the start of a slot of the mapper, banks are mapped at {}", window, starts.join(" or ")));
+        }
+
+        None
+    }
+
+    /// Whether `name` can name a label or a constant, a letter or _ followed by letters,
+    /// digits and _, and not a register or an instruction
+    fn is_name(name: &str) -> bool
+    {
+        let mut chars = name.chars();
+        let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+            && chars.all(|c| c.is_alphanumeric() || c == '_');
+
+        valid && !["a", "x", "y"].contains(&&name.to_lowercase()[..]) && !Lexer::is_instruction(name)
+    }
+
+    /// Read the `NAME = value` and `define NAME value` lines into `constants` and remove
+    /// them, the value is a number or a constant defined above
+    fn read_constants(&mut self)
+    {
         self.current_token = 0;
 
-        // Replace labels with correct index
-        loop
+        while let Some(t) = self.current()
         {
-            if let Some(t) = self.current()
+            let define = t.ttype == TT::UNKNOWN && t.tstring.eq_ignore_ascii_case("define");
+            let assign = t.ttype == TT::UNKNOWN && self.next().is_some_and(|nt| nt.ttype == TT::EQUAL && nt.line_no == t.line_no);
+
+            if !define && !assign
             {
-                // Replace labels with values
-                if t.ttype == TT::UNKNOWN
+                self.step();
+                continue;
+            }
+
+            let start = self.current_token;
+            let line_no = t.line_no;
+            if define
+            {
+                self.step();
+            }
+
+            let name = match self.current()
+            {
+                Some(nt) if nt.ttype == TT::UNKNOWN && nt.line_no == line_no => nt.tstring.clone(),
+                _ =>
                 {
-                    match self.labels.get(&t.tstring)
-                    {
-                        Some(&value) => { 
-                            self.tokens[self.current_token].ttype = TT::LABEL_OPERAND;
-                            // println!("Label replace: {} set to value :{} line_no {}", self.tokens[self.current_token].tstring, value, self.tokens[self.current_token].line_no);
-                            self.tokens[self.current_token].tstring = format!("{:x}",value + 0x600);
-                        },
-                        None =>
-                        {
-                            println!("Unknown token {:?} at line {}", t.tstring, t.line_no);
-                            panic!();
-                        },
-                    }
-                    self.step();
+                    self.error(start, "Expected a name after define".to_string());
+                    self.current_token = start;
+                    self.discard_line();
                     continue;
-                }
+                },
+            };
+
+            // The value follows the name of define and the = of assignments
+            if assign
+            {
                 self.step();
-                continue;
             }
-            else 
+
+            let end = self.line_end(self.current_token);
+            let value = match self.next()
             {
-                break;
+                Some(nt) if nt.line_no != line_no || nt.ttype == TT::EOF => Err(format!("Expected a value for {}", name)),
+                _ =>
+                {
+                    let text = self.text(self.current_token + 1, end);
+                    crate::expr::evaluate(&text, &|name| match self.constants.get(name)
+                    {
+                        Some(&value) => Ok(i32::from(value)),
+                        None => Err(format!("{:?} is not a constant defined above", name)),
+                    })
+                    .and_then(|value| match value
+                    {
+                        -0x8000..=0xffff => Ok(value as u16),
+                        _ => Err(format!("{} is {}, it does not fit in two bytes", text, Lexer::format_value(value))),
+                    })
+                },
+            };
+
+            let name_index = if define { start + 1 } else { start };
+            match value
+            {
+                _ if !Lexer::is_name(&name) => self.error(name_index, format!("{:?} can't be used as a name", name)),
+                _ if self.constants.contains_key(&name) => self.error(name_index, format!("Constant {:?} already defined", name)),
+                Ok(value) =>
+                {
+                    self.constants.insert(name, value);
+                },
+                Err(e) => self.operand_error(e),
             }
+
+            self.current_token = start;
+            self.discard_line();
         }
+
         self.current_token = 0;
+    }
 
-        let mut hex_code: Vec<u8> = vec![];
+    /// Index of the token after the last one of the line of the token `index`
+    fn line_end(&self, index: usize) -> usize
+    {
+        let line_no = self.tokens[index].line_no;
 
-        // Second pass : Create the instructions vector
-        loop 
+        self.tokens[index..]
+            .iter()
+            .position(|t| t.line_no != line_no || t.ttype == TT::EOF)
+            .map_or(self.tokens.len(), |offset| index + offset)
+    }
+
+    /// Source of the tokens `start..end` without the spaces between them
+    fn text(&self, start: usize, end: usize) -> String
+    {
+        self.tokens[start..end]
+            .iter()
+            .map(|t| if t.ttype == TT::STRING { format!("{:?}", t.tstring) } else { t.tstring.clone() })
+            .collect()
+    }
+
+    /// Whether the operand tokens `start..end` are more than a number or a label, they are
+    /// then read as an expression
+    fn is_expression(&self, start: usize, end: usize) -> bool
+    {
+        // :+ and :- are anonymous labels
+        let has_operator = |t: &Token| t.ttype != TT::STRING
+            && !t.tstring.starts_with(':')
+            && t.tstring.contains(|c| "+-*/&|^~<>()'".contains(c));
+
+        match &self.tokens[start..end]
         {
-            let t = self.current();
+            [] => false,
+            [t] => has_operator(t),
+            [prefix, t] if prefix.ttype == TT::DOLLAR || prefix.ttype == TT::PERCENT => has_operator(t),
+            _ => true,
+        }
+    }
 
-            if let Some(t) = t
+    /// Replace the tokens `start..end` by one EXPRESSION token if they are an expression,
+    /// returns the number of tokens removed
+    fn merge_expression(&mut self, start: usize, end: usize) -> usize
+    {
+        if !self.is_expression(start, end)
+        {
+            return 0;
+        }
+
+        let (line_no, column) = (self.tokens[start].line_no, self.tokens[start].column);
+        let tstring = self.text(start, end);
+        self.tokens.splice(start..end, [Token { ttype: TT::EXPRESSION, tstring, line_no, column }]);
+
+        end - start - 1
+    }
+
+    /// Index of the first comma outside of parentheses in the tokens `start..end`, or `end`
+    fn field_end(&self, start: usize, end: usize) -> usize
+    {
+        let mut depth = 0;
+
+        for index in start..end
+        {
+            match self.tokens[index].ttype
             {
-                if t.ttype == TT::INSTRUCTION
+                TT::BRACKETOPEN => depth += 1,
+                TT::BRACKETCLOSE => depth -= 1,
+                TT::COMMA if depth <= 0 => return index,
+                _ => {},
+            }
+        }
+
+        end
+    }
+
+    /// Index of the bracket closing the one at `open`, if it is before `end`
+    fn closing_bracket(&self, open: usize, end: usize) -> Option<usize>
+    {
+        let mut depth = 0;
+
+        for index in open..end
+        {
+            match self.tokens[index].ttype
+            {
+                TT::BRACKETOPEN => depth += 1,
+                TT::BRACKETCLOSE if depth == 1 => return Some(index),
+                TT::BRACKETCLOSE => depth -= 1,
+                _ => {},
+            }
+        }
+
+        None
+    }
+
+    /// Turn the operands with operators into EXPRESSION tokens, the addressing modes and
+    /// the passes then see one token like for a number or a label
+    ///
+    /// The brackets of an instruction operand are indirect addressing when they end the
+    /// operand or are followed by a comma, `(ptr+1),Y`, and part of the expression otherwise
+    fn merge_expressions(&mut self)
+    {
+        let mut index = 0;
+
+        while index < self.tokens.len()
+        {
+            let t = &self.tokens[index];
+            let data = (t.ttype == TT::INSTRUCTION && t.tstring == "DCB")
+                || (t.ttype == TT::DIRECTIVE && !Lexer::is_scope_directive(&t.tstring)
+                    && ![".bank", ".cpu", ".mapper", ".incbin"].contains(&&t.tstring[..]));
+            let instruction = t.ttype == TT::INSTRUCTION && !data;
+
+            if !data && !instruction
+            {
+                index += 1;
+                continue;
+            }
+
+            let mut line_end = self.line_end(index);
+            let mut start = index + 1;
+
+            while start < line_end
+            {
+                // The # of an immediate stays in front of the expression
+                if instruction && self.tokens[start].ttype == TT::HASH
                 {
-                    let mode = self.next_mode();
-                    // println!("Instruction: {} Mode: {} ", t.tstring, mode);
-                    if t.tstring == "ADC"
-                    {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::ADC_IMM),
-                            Mode::ZP => hex_code.push(Instruction::ADC_ZP),
-                            Mode::ZPY => hex_code.push(Instruction::ADC_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::ADC_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::ADC_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::ADC_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::ADC_INDX),
-                            Mode::INDY => hex_code.push(Instruction::ADC_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "AND"
-                    {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::AND_IMM),
-                            Mode::ZP => hex_code.push(Instruction::AND_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::AND_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::AND_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::AND_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::AND_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::AND_INDX),
-                            Mode::INDY => hex_code.push(Instruction::AND_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "ASL"
-                    {
-                        match mode
-                        {
-                            Mode::ACC => hex_code.push(Instruction::ASL_ACC),
-                            Mode::IMP => hex_code.push(Instruction::ASL_ACC),
-                            Mode::ZP => hex_code.push(Instruction::ASL_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::ASL_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::ASL_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::ASL_ABSX),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BCC"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BCC_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BCS"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BCS_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BEQ"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BEQ_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BIT"
-                    {
-                        match mode
-                        {
-                            Mode::ZP => hex_code.push(Instruction::BIT_ZP),
-                            Mode::ABS => hex_code.push(Instruction::BIT_ABS),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BMI"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BMI_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BNE"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BNE_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BPL"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BPL_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BRK"
-                    {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::BRK_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BVC"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BVC_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "BVS"
-                    {
-                        match mode
-                        {
-                            Mode::REL => hex_code.push(Instruction::BVS_REL),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "CLC"
+                    start += 1;
+                    continue;
+                }
+
+                let mut end = self.field_end(start, line_end);
+
+                if instruction && self.tokens[start].ttype == TT::BRACKETOPEN
+                {
+                    let close = self.closing_bracket(start, line_end);
+
+                    if let Some(close) = close.filter(|&close| close + 1 == line_end || self.tokens[close + 1].ttype == TT::COMMA)
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::CLC_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        // The X of (zp,X) follows a comma inside the brackets
+                        let inner_end = self.field_end(start + 1, close);
+                        let removed = self.merge_expression(start + 1, inner_end);
+                        line_end -= removed;
+                        start = close - removed + 1;
+                        continue;
                     }
-                    else if t.tstring == "CLD"
+                }
+
+                let removed = self.merge_expression(start, end);
+                line_end -= removed;
+                end -= removed;
+                start = end + 1;
+            }
+
+            index = line_end;
+        }
+    }
+
+    /// Whether the directive opens or closes a scope
+    fn is_scope_directive(directive: &str) -> bool
+    {
+        matches!(directive, ".proc" | ".endproc" | ".scope" | ".endscope")
+    }
+
+    /// Whether the label belongs to the last global label, `@loop` or `.loop`
+    fn is_local(name: &str) -> bool
+    {
+        name.starts_with('@') || name.starts_with('.')
+    }
+
+    /// Qualified name of the label `name` refers to when used in `scope` after the global label `global`
+    ///
+    /// Local labels belong to `global`. Other names are looked up in `scope`, then in the
+    /// scopes around it and at the top level. Names that aren't labels are kept
+    fn qualify(&self, name: &str, scope: &str, global: &str) -> String
+    {
+        if Lexer::is_local(name)
+        {
+            let qualified = format!("{}.{}", global, &name[1..]);
+            return if self.labels.contains_key(&qualified) { qualified } else { name.to_string() };
+        }
+
+        let mut scope = scope;
+        while !scope.is_empty()
+        {
+            let qualified = format!("{}.{}", scope, name);
+            if self.labels.contains_key(&qualified)
+            {
+                return qualified;
+            }
+            scope = scope.rfind('.').map_or("", |end| &scope[..end]);
+        }
+
+        name.to_string()
+    }
+
+    /// Anonymous label `reference` points at, `:+` is the next one and `:--` the one before
+    /// the last, `defined` anonymous labels come before the reference
+    fn anonymous(&self, reference: &str, defined: usize) -> Option<String>
+    {
+        let count = reference.len() - 1;
+        let number = if reference.starts_with(":+") { defined + count } else { (defined + 1).checked_sub(count)? };
+
+        Some(format!(":{}", number)).filter(|name| self.labels.contains_key(name))
+    }
+
+    /// Add the label at `index` to `labels` under its qualified name
+    ///
+    /// The first definition is kept, the index of the others is added to `duplicates`
+    fn define_label(&mut self, index: usize, name: String, duplicates: &mut Vec<usize>)
+    {
+        if self.labels.contains_key(&name)
+        {
+            self.error(index, format!("Label {:?} already defined", name));
+            duplicates.push(index);
+            return;
+        }
+
+        self.tokens[index].tstring = name.clone();
+        self.labels.insert(name, 0);
+    }
+
+    /// Give the labels their qualified names and point the names of the operands at them
+    ///
+    /// `.proc name` and `.scope name` open a scope closed by `.endproc` and `.endscope`, its
+    /// labels are named `name.label` and `.proc` is also a label. Local labels, `@loop` or
+    /// `.loop`, are named after the last global label, `main.loop`, so they can be reused
+    /// after the next one. Anonymous labels, a lone `:`, are numbered `:1`, `:2`...
+    fn scope_labels(&mut self)
+    {
+        // Open scopes, the directive that opened them and their qualified name
+        let mut scopes: Vec<(usize, String)> = vec![];
+        // Last global label, the scope of local labels
+        let mut global = String::new();
+        let mut anonymous = 0;
+        // Names used by operands with the scope, global label and anonymous label count where they are
+        let mut uses: Vec<(usize, String, String, usize)> = vec![];
+        let mut duplicates: Vec<usize> = vec![];
+
+        let mut index = 0;
+        while index < self.tokens.len()
+        {
+            let scope = scopes.last().map_or(String::new(), |(_, name)| name.clone());
+            let t = &self.tokens[index];
+
+            match t.ttype
+            {
+                TT::DIRECTIVE if t.tstring == ".proc" || t.tstring == ".scope" =>
+                {
+                    let directive = t.tstring.clone();
+                    let name = self.tokens
+                        .get(index + 1)
+                        .filter(|nt| nt.ttype == TT::OPERAND && nt.line_no == t.line_no && Lexer::is_name(&nt.tstring))
+                        .map(|nt| nt.tstring.clone());
+
+                    let name = match name
                     {
-                        match mode
+                        Some(name) => name,
+                        None =>
                         {
-                            Mode::IMP => hex_code.push(Instruction::CLD_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "CLI"
+                            self.error(index, format!("Expected a name after {}", directive));
+                            index += 1;
+                            continue;
+                        },
+                    };
+
+                    let qualified = if scope.is_empty() { name } else { format!("{}.{}", scope, name) };
+                    if directive == ".proc"
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::CLI_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        self.tokens[index + 1].ttype = TT::LABEL;
+                        self.define_label(index + 1, qualified.clone(), &mut duplicates);
                     }
-                    else if t.tstring == "CLV"
+
+                    global = qualified.clone();
+                    scopes.push((index, qualified));
+                    index += 2;
+                    continue;
+                },
+                TT::DIRECTIVE if t.tstring == ".endproc" || t.tstring == ".endscope" =>
+                {
+                    let opening = if t.tstring == ".endproc" { ".proc" } else { ".scope" };
+
+                    match scopes.last()
                     {
-                        match mode
+                        Some(&(start, _)) if self.tokens[start].tstring == opening =>
                         {
-                            Mode::IMP => hex_code.push(Instruction::CLV_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "CMP"
-                    {
-                        match mode
+                            scopes.pop();
+                            global = scopes.last().map_or(String::new(), |(_, name)| name.clone());
+                        },
+                        _ =>
                         {
-                            Mode::IMM => hex_code.push(Instruction::CMP_IMM),
-                            Mode::ZP => hex_code.push(Instruction::CMP_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::CMP_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::CMP_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::CMP_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::CMP_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::CMP_INDX),
-                            Mode::INDY => hex_code.push(Instruction::CMP_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                            let message = format!("{} without {}", t.tstring, opening);
+                            self.error(index, message);
+                        },
                     }
-                    else if t.tstring == "CPX"
+                },
+                TT::LABEL if t.tstring == ":" =>
+                {
+                    anonymous += 1;
+                    self.define_label(index, format!(":{}", anonymous), &mut duplicates);
+                },
+                TT::LABEL if Lexer::is_local(&t.tstring) =>
+                {
+                    if global.is_empty()
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::CPX_IMM),
-                            Mode::ZP => hex_code.push(Instruction::CPX_ZP),
-                            Mode::ABS => hex_code.push(Instruction::CPX_ABS),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        let message = format!("Local label {:?} must follow a global label", t.tstring);
+                        self.error(index, message);
+                        duplicates.push(index);
                     }
-                    else if t.tstring == "CPY"
+                    else
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::CPY_IMM),
-                            Mode::ZP => hex_code.push(Instruction::CPY_ZP),
-                            Mode::ABS => hex_code.push(Instruction::CPY_ABS),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        let qualified = format!("{}.{}", global, &t.tstring[1..]);
+                        self.define_label(index, qualified, &mut duplicates);
                     }
-                    else if t.tstring == "DCB"
-                    {
-                        while let Some(nt) = self.next()
-                        {
-                            if nt.ttype == TT::COMMA
-                            {
-                                self.step();
-                                continue;
-                            }
-                            else if nt.ttype == TT::NUMBER
-                                || nt.ttype == TT::DOLLAR
-                                || nt.ttype == TT::PERCENT
-                                {
-                                    match self.get_operand_u8()
-                                    {
-                                        Ok(operand) =>
-                                        {
-                                            hex_code.push(operand);
-                                        },
-                                        Err(e) =>
-                                        {
-                                            // TODO: This should do error matching for
-                                            // IntErrorKind::PosOverflow to see if it might be a u16
-                                            println!("Error : {}", e);
-                                            panic!();
+                },
+                TT::LABEL =>
+                {
+                    let qualified = if scope.is_empty() { t.tstring.clone() } else { format!("{}.{}", scope, t.tstring) };
+                    global = qualified.clone();
+                    self.define_label(index, qualified, &mut duplicates);
+                },
+                // The digits after $ and % are never names
+                TT::UNKNOWN | TT::EXPRESSION
+                    if index == 0 || !matches!(self.tokens[index - 1].ttype, TT::DOLLAR | TT::PERCENT) =>
+                {
+                    uses.push((index, scope, global.clone(), anonymous));
+                },
+                _ => {},
+            }
 
-                                        }
+            index += 1;
+        }
 
-                                    }
+        for (start, _) in scopes.into_iter().rev()
+        {
+            let closing = if self.tokens[start].tstring == ".proc" { ".endproc" } else { ".endscope" };
+            self.error(start, format!("Missing {} for this {}", closing, self.tokens[start].tstring));
+        }
 
-                                    self.step();
-                                    self.step();
-                                    continue;
-                                }
-                            else if nt.ttype != TT::COMMA
-                                || nt.ttype != TT::DOLLAR
-                                || nt.ttype != TT::PERCENT
-                                || nt.ttype != TT::NUMBER
-                                {
-                                    break;
-                                }
-                            self.step();
-                            continue;
-                        }
-                        continue;
-                    }
-                    else if t.tstring == "DEC"
-                    {
-                        match mode
-                        {
-                            Mode::ZP => hex_code.push(Instruction::DEC_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::DEC_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::DEC_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::DEC_ABSX),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "DEX"
-                    {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::DEX_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "DEY"
+        for (index, scope, global, defined) in uses
+        {
+            let text = &self.tokens[index].tstring;
+
+            let name = if self.tokens[index].ttype == TT::EXPRESSION
+            {
+                crate::expr::rename(text, &|name| self.qualify(name, &scope, &global))
+            }
+            else if text.starts_with(':')
+            {
+                match self.anonymous(text, defined)
+                {
+                    Some(name) => name,
+                    None =>
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::DEY_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "EOR"
+                        let message = format!("No anonymous label for {}", text);
+                        self.error(index, message);
+                        continue;
+                    },
+                }
+            }
+            else
+            {
+                self.qualify(text, &scope, &global)
+            };
+
+            self.tokens[index].tstring = name;
+        }
+
+        for index in duplicates.into_iter().rev()
+        {
+            self.tokens.remove(index);
+        }
+    }
+
+    /// Replace the constant at the current token with its value, written with 2 hex digits
+    /// in the zero page and 4 above so the addressing mode follows the value
+    fn replace_constant(&mut self, value: u16)
+    {
+        let (line_no, column) = (self.tokens[self.current_token].line_no, self.tokens[self.current_token].column);
+        let digits = if value <= 0xff { format!("{:02x}", value) } else { format!("{:04x}", value) };
+
+        self.tokens[self.current_token] = Token { ttype: TT::DOLLAR, tstring: "$".to_string(), line_no, column };
+        self.tokens.insert(self.current_token + 1, Token { ttype: TT::UNKNOWN, tstring: digits, line_no, column });
+    }
+
+    /// Whether the current token starts a statement, it is the first of its line or follows a label
+    fn at_statement(&self) -> bool
+    {
+        let t = &self.tokens[self.current_token];
+
+        match self.current_token.checked_sub(1).map(|index| &self.tokens[index])
+        {
+            None => true,
+            Some(pt) => pt.line_no != t.line_no || pt.ttype == TT::COLON || (pt.ttype == TT::LABEL && pt.tstring == ":"),
+        }
+    }
+
+    /// Remove the current token and the rest of its line, for lines that can't be assembled
+    fn discard_line(&mut self)
+    {
+        let end = self.line_end(self.current_token);
+        self.tokens.drain(self.current_token..end);
+    }
+
+    /// Move to the last token of the current line
+    fn skip_line(&mut self)
+    {
+        if let Some(line_no) = self.current().map(|t| t.line_no)
+        {
+            while self.next().is_some_and(|nt| nt.line_no == line_no && nt.ttype != TT::EOF)
+            {
+                self.step();
+            }
+        }
+    }
+
+    /// Labels with their qualified names and addresses, sorted by address then name
+    ///
+    /// Anonymous labels are left out, their `:1`, `:2`... names mean nothing outside the source
+    pub fn symbols(&self) -> Vec<(String, u16)>
+    {
+        let mut symbols: Vec<(String, u16)> = self.labels.iter()
+            .filter(|(name, _)| !name.starts_with(':'))
+            .map(|(name, &address)| (name.clone(), address))
+            .collect();
+        symbols.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+
+        symbols
+    }
+
+    /// Text of a `.sym` file, one `$address name` line per symbol
+    pub fn symbol_file(&self) -> String
+    {
+        self.symbols().iter().map(|(name, address)| format!("${:04x} {}\n", address, name)).collect()
+    }
+
+    /// Banks back to back, each padded to the bank size of the mapper, the layout
+    /// of a cartridge image
+    pub fn bank_image(&self) -> Vec<u8>
+    {
+        let bank_size = self.mapper.bank_size();
+        let mut image = vec![0; self.banks.len() * bank_size];
+
+        for (i, bank) in self.banks.iter().enumerate()
+        {
+            image[i * bank_size..i * bank_size + bank.len()].copy_from_slice(bank);
+        }
+
+        image
+    }
+
+    /// Assemble the tokens, returns the code placed at `origin` (its lowest address, $0600
+    /// without .org) and fills `banks`
+    ///
+    /// Assembly goes on after an error so that all of them are found, the
+    /// diagnostics are returned when there is at least one error
+    pub fn parse(&mut self) -> Result<Vec<u8>, Vec<Diagnostic>>
+    {
+        let directive_strings = [
+            ".align", ".bank", ".byte", ".cpu", ".ds", ".endproc", ".endscope", ".fill", ".incbin", ".mapper", ".org",
+            ".proc", ".res", ".scope", ".word",
+        ];
+
+        self.read_constants();
+
+        // First pass : Update unknown tokens, replace constants and read labels
+        loop 
+        {
+            let t = self.current();
+
+            if let Some(t) = t
+            {
+                // A label with the name of a constant is reported below, a colon on the next line is an anonymous label
+                let is_label = self.next().is_some_and(|nt| nt.ttype == TT::COLON && nt.line_no == t.line_no);
+                if t.ttype == TT::UNKNOWN && self.constants.contains_key(&t.tstring) && !is_label
+                {
+                    let value = self.constants[&t.tstring];
+                    self.replace_constant(value);
+                    self.stepx(2);
+                    continue;
+                }
+
+                // A colon alone is an anonymous label
+                if t.ttype == TT::COLON && self.at_statement()
+                {
+                    self.tokens[self.current_token].ttype = TT::LABEL;
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::UNKNOWN
+                {
+                    // If directive, the names after .cpu, .mapper, .proc and .scope are its argument.
+                    // Elsewhere .name is a local label
+                    if t.tstring.starts_with('.') && !is_label && self.at_statement()
                     {
-                        match mode
+                        if !directive_strings.contains(&&t.tstring.to_lowercase()[..])
                         {
-                            Mode::IMM => hex_code.push(Instruction::EOR_IMM),
-                            Mode::ZP => hex_code.push(Instruction::EOR_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::EOR_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::EOR_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::EOR_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::EOR_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::EOR_INDX),
-                            Mode::INDY => hex_code.push(Instruction::EOR_INDY),
-                            _ => panic!("Unknown addressing mode"),
+                            let message = format!("Unknown directive {:?}", t.tstring);
+                            self.error(self.current_token, message);
+                            self.discard_line();
+                            continue;
                         }
-                    }
-                    else if t.tstring == "INC"
-                    {
-                        match mode
+
+                        self.tokens[self.current_token].ttype = TT::DIRECTIVE;
+                        self.tokens[self.current_token].tstring =
+                            self.tokens[self.current_token].tstring.to_lowercase();
+
+                        let named = matches!(&self.tokens[self.current_token].tstring[..], ".cpu" | ".mapper" | ".proc" | ".scope");
+                        if named && self.next().is_some_and(|nt| nt.ttype == TT::UNKNOWN)
                         {
-                            Mode::ZP => hex_code.push(Instruction::INC_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::INC_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::INC_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::INC_ABSX),
-                            _ => panic!("Unknown addressing mode"),
+                            self.tokens[self.current_token + 1].ttype = TT::OPERAND;
                         }
                     }
-                    else if t.tstring == "INX"
-                    {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::INX_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                    // If instruction
+                    else if Lexer::is_instruction(&t.tstring)
+                    { 
+                        self.tokens[self.current_token].ttype = TT::INSTRUCTION;
+                        self.tokens[self.current_token].tstring =
+                            self.tokens[self.current_token].tstring.to_uppercase();
                     }
-                    else if t.tstring == "INY"
+                    // If label
+                    else if is_label
                     {
-                        match mode
+                        // Labels are read by scope_labels once their scope is known
+                        if self.constants.contains_key(&t.tstring)
                         {
-                            Mode::IMP => hex_code.push(Instruction::INY_IMP),
-                            _ => panic!("Unknown addressing mode"),
+                            let message = format!("{:?} is already defined as a constant", t.tstring);
+                            self.error(self.current_token, message);
+                            self.tokens.remove(self.current_token);
+                            continue;
                         }
+
+                        self.tokens[self.current_token].ttype = TT::LABEL;
+                        continue;
                     }
-                    else if t.tstring == "JMP"
+                }
+
+                self.step();
+            }
+            else
+            {
+                break;
+            }
+        }
+
+        self.merge_expressions();
+        self.scope_labels();
+
+        // Add labels to labels table
+        self.current_token = 0;
+        self.unplaced = self.labels.keys().cloned().collect();
+        // Location counter, code before the first .org or .bank is placed at $0600, banks at their window
+        let mut pc: u32 = 0x600;
+        let mut segment: Option<usize> = None;
+        // Where each bank stopped, reopening it continues there
+        let mut bank_pcs: Vec<u32> = vec![];
+        // Files spliced in by .incbin, by the token of the directive
+        let mut included: HashMap<usize, Vec<u8>> = HashMap::new();
+        loop 
+        {
+            // `*` is the address of the statement
+            if self.current().is_some_and(|t| t.ttype == TT::INSTRUCTION || t.ttype == TT::DIRECTIVE)
+            {
+                self.pc = pc;
+            }
+
+            if let Some(t) = self.current()
+            {
+                if t.ttype == TT::LABEL
+                {
+                    // TODO(James) : This seems messed up
+                    let name = self.tokens[self.current_token].tstring.clone();
+                    self.unplaced.remove(&name);
+                    self.labels.insert(name, pc as u16);
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".org"
+                {
+                    if let Some(values) = self.directive_values(1)
                     {
-                        match mode
-                        {
-                            Mode::ABS => hex_code.push(Instruction::JMP_ABS),
-                            Mode::IND => hex_code.push(Instruction::JMP_IND),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        pc = u32::from(values[0]);
                     }
-                    else if t.tstring == "JSR"
+
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && (t.tstring == ".byte" || t.tstring == ".word")
+                {
+                    pc += self.data_len(if t.tstring == ".byte" { 1 } else { 2 });
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && (t.tstring == ".res" || t.tstring == ".ds" || t.tstring == ".fill")
+                {
+                    if let Some(values) = self.directive_values(2)
                     {
-                        match mode
-                        {
-                            Mode::ABS => hex_code.push(Instruction::JSR_ABS),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        pc += u32::from(values[0]);
                     }
-                    else if t.tstring == "LDA"
+
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".align"
+                {
+                    if let Some((len, _)) = self.align_len(pc)
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::LDA_IMM),
-                            Mode::ZP => hex_code.push(Instruction::LDA_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::LDA_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::LDA_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::LDA_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::LDA_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::LDA_INDX),
-                            Mode::INDY => hex_code.push(Instruction::LDA_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        pc += len;
                     }
-                    else if t.tstring == "LDX"
+
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".incbin"
+                {
+                    if let Some(data) = self.incbin()
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::LDX_IMM),
-                            Mode::ZP => hex_code.push(Instruction::LDX_ZP),
-                            Mode::ZPY => hex_code.push(Instruction::LDX_ZPY),
-                            Mode::ABS => hex_code.push(Instruction::LDX_ABS),
-                            Mode::ABSY => hex_code.push(Instruction::LDX_ABSY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        pc += data.len() as u32;
+                        included.insert(self.current_token, data);
                     }
-                    else if t.tstring == "LDY"
+
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".mapper"
+                {
+                    let kind = self.next().map(|nt| nt.tstring.to_lowercase());
+
+                    match kind.as_deref()
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::LDY_IMM),
-                            Mode::ZP => hex_code.push(Instruction::LDY_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::LDY_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::LDY_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::LDY_ABSX),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        Some("latch") => self.mapper = MapperKind::Latch,
+                        Some("fixed") => self.mapper = MapperKind::Fixed,
+                        Some("window") => self.mapper = MapperKind::Window,
+                        _ => self.operand_error("Expected latch, fixed or window after .mapper".to_string()),
                     }
-                    else if t.tstring == "LSR"
+
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".bank"
+                {
+                    let (bank, window) = match self.bank_directive()
                     {
-                        match mode
+                        Some(bank) => bank,
+                        None =>
                         {
-                            Mode::IMP => hex_code.push(Instruction::LSR_ACC),
-                            Mode::ACC => hex_code.push(Instruction::LSR_ACC),
-                            Mode::ZP => hex_code.push(Instruction::LSR_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::LSR_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::LSR_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::LSR_ABSX),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "NOP"
+                            self.step();
+                            continue;
+                        },
+                    };
+
+                    // Banks can be reopened, they continue where they stopped
+                    if let Some(current) = segment
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::NOP_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        bank_pcs[current] = pc;
                     }
-                    else if t.tstring == "ORA"
+                    if bank_pcs.len() <= bank
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::ORA_IMM),
-                            Mode::ZP => hex_code.push(Instruction::ORA_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::ORA_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::ORA_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::ORA_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::ORA_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::ORA_INDX),
-                            Mode::INDY => hex_code.push(Instruction::ORA_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        bank_pcs.resize(bank + 1, u32::MAX);
                     }
-                    else if t.tstring == "PHA"
+                    if bank_pcs[bank] == u32::MAX
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::PHA_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        bank_pcs[bank] = u32::from(window);
                     }
-                    else if t.tstring == "PHP"
+
+                    pc = bank_pcs[bank];
+                    segment = Some(bank);
+
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::INSTRUCTION
+                {
+                    if t.tstring == "DCB"
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::PHP_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        pc += self.data_len(1);
+                        self.step();
+                        continue;
                     }
-                    else if t.tstring == "PLA"
+
+                    let mode = self.next_mode();
+
+                    // Reported by the second pass, the instruction takes no space
+                    if mode == Mode::UNKNOWN
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::PLA_IMP),
-                            _ => panic!("Unknown addressing mode {} {}", mode, t.line_no),
-                        }
+                        self.step();
+                        continue;
                     }
-                    else if t.tstring == "PLP"
+
+                    pc += 1;
+
+                    if t.tstring == "JSR"
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::PLP_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        pc += 2;
+                        self.step();
+                        continue;
                     }
-                    else if t.tstring == "ROL"
+
+                    pc += u32::from(Mode::operand_len(mode));
+
+                    self.step();
+                    continue;
+                }
+
+                // Unknown tokens made of hex digits that aren't labels are numbers
+                if t.ttype == TT::UNKNOWN
+                {
+                    if !self.labels.contains_key(&t.tstring) && t.tstring.chars().all(|tc| tc.is_ascii_hexdigit())
                     {
-                        match mode
-                        {
-                            Mode::ACC => hex_code.push(Instruction::ROL_ACC),
-                            Mode::IMP => hex_code.push(Instruction::ROL_ACC),
-                            Mode::ZP => hex_code.push(Instruction::ROL_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::ROL_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::ROL_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::ROL_ABSX),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        self.tokens[self.current_token].ttype = TT::NUMBER;
                     }
-                    else if t.tstring == "ROR"
+
+                    self.step();
+                    continue;
+                }
+            }
+            else
+            {
+                break;
+            }
+
+            self.step();
+            continue;
+        }
+
+        self.current_token = 0;
+
+        // Replace labels with correct index
+        self.unknown.clear();
+        while let Some(t) = self.current()
+        {
+            // Replace labels with values
+            if t.ttype == TT::UNKNOWN
+            {
+                match self.labels.get(&t.tstring)
+                {
+                    Some(&value) => { 
+                        self.tokens[self.current_token].ttype = TT::LABEL_OPERAND;
+                        self.tokens[self.current_token].tstring = format!("{:x}",value);
+                    },
+                    None =>
+                    {
+                        let message = format!("Unknown token {:?}", t.tstring);
+                        self.error(self.current_token, message);
+
+                        // Read as address 0 so the line is still assembled
+                        self.unknown.insert(self.current_token);
+                        self.tokens[self.current_token].ttype = TT::LABEL_OPERAND;
+                        self.tokens[self.current_token].tstring = "0".to_string();
+                    },
+                }
+            }
+            self.step();
+        }
+        self.current_token = 0;
+
+        // Code of the chunk being assembled, its address and the directive that placed it
+        let mut hex_code: Vec<u8> = vec![];
+        let mut origin: i32 = 0x600;
+        let mut chunk_token = 0;
+        // Finished chunks of the segment being assembled
+        let mut chunks: Vec<Chunk> = vec![];
+        let mut segment: Option<usize> = None;
+        let mut main_chunks: Vec<Chunk> = vec![];
+        // Window and chunks of each bank
+        let mut banks: Vec<Option<(u32, Vec<Chunk>)>> = vec![];
+
+        self.unplaced.clear();
+
+        // Second pass : Create the instructions vector
+        loop 
+        {
+            if self.current().is_some_and(|t| t.ttype == TT::INSTRUCTION || t.ttype == TT::DIRECTIVE)
+            {
+                self.pc = (origin + hex_code.len() as i32) as u32;
+            }
+
+            let t = self.current();
+
+            if let Some(t) = t
+            {
+                if t.ttype == TT::DIRECTIVE && Lexer::is_scope_directive(&t.tstring)
+                {
+                    // Read by scope_labels
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".mapper"
+                {
+                    // Read by the label pass, banks are sized for it
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".bank"
+                {
+                    let (bank, window) = match self.bank_directive()
                     {
-                        match mode
+                        Some(bank) => bank,
+                        None =>
                         {
-                            Mode::ACC => hex_code.push(Instruction::ROR_ACC),
-                            Mode::IMP => hex_code.push(Instruction::ROR_ACC),
-                            Mode::ZP => hex_code.push(Instruction::ROR_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::ROR_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::ROR_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::ROR_ABSX),
-                            _ => panic!("Unknown addressing mode {} {}", mode, t.line_no),
-                        }
-                    }
-                    else if t.tstring == "RTI"
+                            self.skip_line();
+                            self.step();
+                            continue;
+                        },
+                    };
+
+                    // Reported here only, the label pass reads the same directive
+                    if let Some(message) = self.bank_error(bank, window)
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::RTI_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        self.error(self.current_token, message);
                     }
-                    else if t.tstring == "RTS"
+
+                    chunks.push(Chunk { address: origin as u32, token: chunk_token, code: std::mem::take(&mut hex_code) });
+                    match segment
                     {
-                        match mode
+                        Some(current) => if let Some((_, bank_chunks)) = &mut banks[current]
                         {
-                            Mode::IMP => hex_code.push(Instruction::RTS_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                            *bank_chunks = std::mem::take(&mut chunks);
+                        },
+                        None => main_chunks = std::mem::take(&mut chunks),
                     }
-                    else if t.tstring == "SBC"
+                    if banks.len() <= bank
                     {
-                        match mode
-                        {
-                            Mode::IMM => hex_code.push(Instruction::SBC_IMM),
-                            Mode::ZP => hex_code.push(Instruction::SBC_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::SBC_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::SBC_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::SBC_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::SBC_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::SBC_INDX),
-                            Mode::INDY => hex_code.push(Instruction::SBC_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        banks.resize_with(bank + 1, || None);
                     }
-                    else if t.tstring == "SEC"
+
+                    // Reopened banks continue after their last chunk
+                    let (window, bank_chunks) = banks[bank].get_or_insert((u32::from(window), vec![]));
+                    chunks = std::mem::take(bank_chunks);
+                    origin = chunks.last().map_or(*window, |chunk| chunk.address + chunk.code.len() as u32) as i32;
+                    chunk_token = self.current_token;
+                    segment = Some(bank);
+
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".org"
+                {
+                    if let Some(values) = self.directive_values(1)
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::SEC_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        chunks.push(Chunk { address: origin as u32, token: chunk_token, code: std::mem::take(&mut hex_code) });
+                        origin = i32::from(values[0]);
+                        chunk_token = self.current_token;
                     }
-                    else if t.tstring == "SED"
+
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && (t.tstring == ".byte" || t.tstring == ".word")
+                {
+                    self.data(if t.tstring == ".byte" { 1 } else { 2 }, &mut hex_code);
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && (t.tstring == ".res" || t.tstring == ".ds" || t.tstring == ".fill")
+                {
+                    if let Some(values) = self.directive_values(2)
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::SED_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        let fill = self.fill_value(&values);
+                        hex_code.resize(hex_code.len() + usize::from(values[0]), fill);
                     }
-                    else if t.tstring == "SEI"
+
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".align"
+                {
+                    let pc = origin as u32 + hex_code.len() as u32;
+                    if let Some((len, fill)) = self.align_len(pc)
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::SEI_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        hex_code.resize(hex_code.len() + len as usize, fill);
                     }
-                    else if t.tstring == "STA"
+
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE && t.tstring == ".incbin"
+                {
+                    if let Some(data) = included.remove(&self.current_token)
                     {
-                        match mode
-                        {
-                            Mode::ZP => hex_code.push(Instruction::STA_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::STA_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::STA_ABS),
-                            Mode::ABSX => hex_code.push(Instruction::STA_ABSX),
-                            Mode::ABSY => hex_code.push(Instruction::STA_ABSY),
-                            Mode::INDX => hex_code.push(Instruction::STA_INDX),
-                            Mode::INDY => hex_code.push(Instruction::STA_INDY),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        hex_code.extend(data);
                     }
-                    else if t.tstring == "STX"
+
+                    self.skip_line();
+                    self.step();
+                    continue;
+                }
+
+                if t.ttype == TT::DIRECTIVE
+                {
+                    let target = self.next().map(|nt| nt.tstring.to_lowercase());
+
+                    match target.as_deref()
                     {
-                        match mode
+                        Some("6502") => self.cpu = Variant::Nmos,
+                        Some("65c02") => self.cpu = Variant::Cmos,
+                        Some("w65c02") => self.cpu = Variant::Wdc,
+                        _ =>
                         {
-                            Mode::ZP => hex_code.push(Instruction::STX_ZP),
-                            Mode::ZPY => hex_code.push(Instruction::STX_ZPY),
-                            Mode::ABS => hex_code.push(Instruction::STX_ABS),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                            self.operand_error("Expected 6502, 65c02 or w65c02 after .cpu".to_string());
+                            self.skip_line();
+                            self.step();
+                            continue;
+                        },
                     }
-                    else if t.tstring == "STY"
+
+                    self.stepx(2);
+                    continue;
+                }
+
+                if t.ttype == TT::INSTRUCTION
+                {
+                    let mode = self.next_mode();
+
+                    if t.tstring == "DCB"
                     {
-                        match mode
-                        {
-                            Mode::ZP => hex_code.push(Instruction::STY_ZP),
-                            Mode::ZPX => hex_code.push(Instruction::STY_ZPX),
-                            Mode::ABS => hex_code.push(Instruction::STY_ABS),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        self.data(1, &mut hex_code);
+                        self.skip_line();
+                        self.step();
+                        continue;
                     }
-                    else if t.tstring == "TAX"
+
+                    if mode == Mode::UNKNOWN
                     {
-                        match mode
+                        // A $ or % without its digits is the actual mistake
+                        let message = match self.next()
                         {
-                            Mode::IMP => hex_code.push(Instruction::TAX_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                            Some(nt) if nt.ttype == TT::DOLLAR || nt.ttype == TT::PERCENT => self.get_operand_u16().err(),
+                            _ => None,
+                        };
+                        self.operand_error(message.unwrap_or_else(|| "Unknown addressing mode".to_string()));
+                        self.skip_line();
+                        self.step();
+                        continue;
                     }
-                    else if t.tstring == "TAY"
+
+                    // KIL is another name for JAM
+                    let mnemonic = if t.tstring == "KIL" { "JAM" } else { &t.tstring[..] };
+                    // When the cpu has the instruction only the addressing mode is missing, LDA ($10) on the 6502
+                    let missing = if crate::isa::has_mnemonic(self.cpu, mnemonic)
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::TAY_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                        format!("{} {} addressing", t.tstring, Mode::syntax(mode))
                     }
-                    else if t.tstring == "TSX"
+                    else
                     {
-                        match mode
-                        {
-                            Mode::IMP => hex_code.push(Instruction::TSX_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "TXA"
+                        t.tstring.clone()
+                    };
+
+                    let error = match Lexer::encode(self.cpu, mnemonic, mode)
                     {
-                        match mode
+                        Some(opcode) =>
                         {
-                            Mode::IMP => hex_code.push(Instruction::TXA_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "TXS"
-                    {
-                        match mode
+                            hex_code.push(opcode);
+                            None
+                        },
+                        None if self.cpu == Variant::Nmos && Lexer::encode(Variant::Cmos, mnemonic, mode).is_some() =>
                         {
-                            Mode::IMP => hex_code.push(Instruction::TXS_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
-                    }
-                    else if t.tstring == "TYA"
-                    {
-                        match mode
+                            Some(format!("{} is only available on the 65C02, select it with .cpu 65c02", missing))
+                        },
+                        None if self.cpu != Variant::Wdc && Lexer::encode(Variant::Wdc, mnemonic, mode).is_some() =>
                         {
-                            Mode::IMP => hex_code.push(Instruction::TYA_IMP),
-                            _ => panic!("Unknown addressing mode"),
-                        }
+                            Some(format!("{} is only available on the WDC 65C02, select it with .cpu w65c02", missing))
+                        },
+                        None if self.cpu != Variant::Nmos && Lexer::encode(Variant::Nmos, mnemonic, mode).is_some() =>
+                        {
+                            Some(format!("{} is an undocumented NMOS opcode, not available on the 65C02", missing))
+                        },
+                        None => Some(format!("{} does not support this addressing mode", t.tstring)),
+                    };
+
+                    // The operand is still assembled so the labels that follow keep their addresses
+                    if let Some(message) = error
+                    {
+                        self.error(self.current_token, message);
+                        hex_code.push(0);
                     }
 
                     // Get operand
-                    if mode != Mode::IMP
+                    if mode != Mode::IMP && mode != Mode::ACC
                     {
                         match mode 
                         {
-                            Mode::ABS | Mode::ABSX | Mode::ABSY | Mode::IND => 
+                            Mode::ABS | Mode::ABSX | Mode::ABSY | Mode::IND | Mode::ABSINDX => 
                             {
+                                if mode == Mode::IND || mode == Mode::ABSINDX
+                                {
+                                    // Skip the opening bracket
+                                    self.step();
+                                }
+
                                 match self.get_operand_u16()
                                 {
                                     Ok(operand) =>
                                     {
                                         let lsb:u8 = operand as u8;
                                         let hsb:u8 =  (operand>>8) as u8;
-                                        // println!("Operand : {} {:#4b} {:#4b} {:#4x}", operand, operand as u8, (operand>>8) as u8, lsb);
-                                        hex_code.push(hsb);
                                         hex_code.push(lsb);
+                                        hex_code.push(hsb);
                                     },
                                     Err(e) =>
                                     {
-                                        println!("Error : {}", e);
-                                        panic!();
+                                        self.operand_error(e);
+                                        hex_code.extend([0, 0]);
                                     }
 
                                 }
                             },
-                            Mode::IMM | Mode::INDX | Mode::INDY =>
+                            Mode::IMM | Mode::INDX | Mode::INDY | Mode::ZPI =>
                             {
                                 self.step();
 
@@ -1320,13 +2088,42 @@ impl Lexer
                                     },
                                     Err(e) =>
                                     {
-                                        println!("Error : {}", e);
-                                        panic!();
+                                        self.operand_error(e);
+                                        hex_code.push(0);
                                     }
 
                                 }
                             },
                             Mode::REL =>
+                            {
+                                match self.get_operand_u16()
+                                {
+                                    Ok(target) =>
+                                    {
+                                        // Offset is relative to the address of the next instruction
+                                        let next_pc = origin + hex_code.len() as i32 + 1;
+                                        match Lexer::branch_offset(target, next_pc)
+                                        {
+                                            Ok(offset) => hex_code.push(offset),
+                                            Err(e) =>
+                                            {
+                                                if !self.unknown.contains(&(self.current_token + 1))
+                                                {
+                                                    self.operand_error(e);
+                                                }
+                                                hex_code.push(0);
+                                            }
+                                        }
+                                    },
+                                    Err(e) =>
+                                    {
+                                        self.operand_error(e);
+                                        hex_code.push(0);
+                                    }
+
+                                }
+                            },
+                            Mode::ZPREL =>
                             {
                                 match self.get_operand_u8()
                                 {
@@ -1334,25 +2131,50 @@ impl Lexer
                                     {
                                         hex_code.push(operand);
                                     },
-                                    Err(_e) =>
+                                    Err(e) =>
+                                    {
+                                        self.operand_error(e);
+                                        hex_code.push(0);
+                                    }
+                                }
+
+                                // Move to the comma, the branch target follows it
+                                let comma = (1..4).find(|&offset| self.nextx(offset).is_some_and(|nt| nt.ttype == TT::COMMA));
+                                match comma
+                                {
+                                    Some(offset) => self.stepx(offset),
+                                    None =>
+                                    {
+                                        self.operand_error("Expected a branch target after the zero page operand".to_string());
+                                        hex_code.push(0);
+                                        self.skip_line();
+                                        self.step();
+                                        continue;
+                                    }
+                                }
+
+                                match self.get_operand_u16()
+                                {
+                                    Ok(target) =>
                                     {
-                                        // REL should not use a 16-bit value
-                                        // So convert it to 8-bit, use lower bytes
-                                        // This is neccessary since we add 0x600 to every label
-                                        match self.get_operand_u16()
+                                        let next_pc = origin + hex_code.len() as i32 + 1;
+                                        match Lexer::branch_offset(target, next_pc)
                                         {
-                                            Ok(operand) =>
-                                            {
-                                                let lsb:u8 = operand as u8;
-                                                hex_code.push(lsb);
-                                            },
+                                            Ok(offset) => hex_code.push(offset),
                                             Err(e) =>
                                             {
-                                                println!("Error : {}", e);
-                                                panic!();
+                                                if !self.unknown.contains(&(self.current_token + 1))
+                                                {
+                                                    self.operand_error(e);
+                                                }
+                                                hex_code.push(0);
                                             }
-
                                         }
+                                    },
+                                    Err(e) =>
+                                    {
+                                        self.operand_error(e);
+                                        hex_code.push(0);
                                     }
 
                                 }
@@ -1367,13 +2189,13 @@ impl Lexer
                                     },
                                     Err(e) =>
                                     {
-                                        println!("Error : {}", e);
-                                        panic!();
+                                        self.operand_error(e);
+                                        hex_code.push(0);
                                     }
 
                                 }
                             },
-                            _ => panic!("Unknown addressing mode"),
+                            _ => {},
                         }
                     }
                 }
@@ -1386,13 +2208,74 @@ impl Lexer
             }
         }
 
-        hex_code
+        chunks.push(Chunk { address: origin as u32, token: chunk_token, code: hex_code });
+        match segment
+        {
+            Some(current) => if let Some((_, bank_chunks)) = &mut banks[current]
+            {
+                *bank_chunks = chunks;
+            },
+            None => main_chunks = chunks,
+        }
+
+        // The program starts at its lowest address, the banks at their window
+        let base = main_chunks.iter().filter(|chunk| !chunk.code.is_empty()).map(|chunk| chunk.address).min().unwrap_or(0x600);
+        self.origin = base as u16;
+        let hex_code = self.link(&main_chunks, base, (0x10000 - base) as usize, "Code runs past $ffff");
+
+        let bank_size = self.mapper.bank_size();
+        self.banks = vec![];
+        for (bank, placed) in banks.iter().enumerate()
+        {
+            let code = match placed
+            {
+                Some((window, bank_chunks)) =>
+                {
+                    let overflow = format!("Bank {} is larger than the {} byte banks of the mapper", bank, bank_size);
+                    self.link(bank_chunks, *window, bank_size, &overflow)
+                },
+                None => vec![],
+            };
+            self.banks.push(code);
+        }
+
+        // Each bank must have code, and only the last one can be assembled for the fixed slot
+        let last = banks.len().saturating_sub(1);
+        for (bank, placed) in banks.iter().enumerate()
+        {
+            if let Some((window, bank_chunks)) = placed
+            {
+                let token = bank_chunks.first().map_or(0, |chunk| chunk.token);
+
+                if bank_chunks.iter().all(|chunk| chunk.code.is_empty())
+                {
+                    self.error(token, format!("Bank {} has no code", bank));
+                }
+                else if self.mapper.fixed_slot() == Some(*window as u16) && bank != last
+                {
+                    let message = format!("Only the last bank, bank {}, is mapped at ${:04x}, bank {} can only be switched in at ${:04x}",
+                        last, window, bank, self.mapper.window());
+                    self.error(token, message);
+                }
+            }
+        }
+
+        if self.has_errors()
+        {
+            // The passes find errors out of order, report them the way they appear in the source
+            self.diagnostics.sort_by_key(|d| (d.line, d.column));
+            return Err(self.diagnostics.clone());
+        }
 
+        Ok(hex_code)
     }
 
     pub fn tokenize(&mut self, code: String)
     {
         let mut line_no = 1;
+        // Column of the character being read and of the first of unknown_chars
+        let mut column = 0;
+        let mut unknown_column = 0;
 
         // TODO : Figure this out
         // unknown_chars.len() had some borrow/copy problems that I don't know how to fix
@@ -1402,8 +2285,12 @@ impl Lexer
         let mut unknown_chars : Vec<char> = Vec::new();//String::new() ;
         let mut is_comment : bool = false;
 
-        for c in code.chars()
+        let mut chars = code.chars().peekable();
+
+        while let Some(c) = chars.next()
         {
+            column += 1;
+
             if !is_comment
             {
                 if c == ';'
@@ -1412,13 +2299,87 @@ impl Lexer
                     continue;
                 }
 
+                // Characters are numbers, 'a' is 97, they can be a space or ;
+                if c == '\''
+                {
+                    if unknown_chars_size > 0
+                    {
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
+                        self.tokens.push(t);
+                        self.step();
+                        unknown_chars.clear();
+                        unknown_chars_size = 0;
+                    }
+
+                    let start = column;
+                    let value = chars.next_if(|&nc| nc != '\n');
+                    let closed = value.is_some() && chars.next_if_eq(&'\'').is_some();
+                    column += if closed { 2 } else { value.iter().count() as u32 };
+
+                    if !closed
+                    {
+                        // The rest of a literal like 'ab' is part of the same mistake
+                        while chars.next_if(|&nc| !nc.is_whitespace() && nc != ',' && nc != ';').is_some()
+                        {
+                            column += 1;
+                        }
+
+                        let message = "Expected a character between single quotes".to_string();
+                        self.diagnostics.push(Diagnostic { severity: Severity::Error, file: self.file.clone(), line: line_no, column: start, message });
+                    }
+
+                    let tstring = value.map_or(0, u32::from).to_string();
+                    let t = Token { ttype: TT::NUMBER, tstring, line_no, column:start };
+                    self.tokens.push(t);
+                    self.step();
+                    continue;
+                }
+
+                // Strings end at the closing quote or the end of the line, they can hold ; and spaces
+                if c == '"'
+                {
+                    if unknown_chars_size > 0
+                    {
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
+                        self.tokens.push(t);
+                        self.step();
+                        unknown_chars.clear();
+                        unknown_chars_size = 0;
+                    }
+
+                    let start = column;
+                    let mut text = String::new();
+                    let mut closed = false;
+                    while let Some(nc) = chars.next_if(|&nc| nc != '\n')
+                    {
+                        column += 1;
+                        if nc == '"'
+                        {
+                            closed = true;
+                            break;
+                        }
+                        text.push(nc);
+                    }
+
+                    if !closed
+                    {
+                        let message = "Missing closing quote".to_string();
+                        self.diagnostics.push(Diagnostic { severity: Severity::Error, file: self.file.clone(), line: line_no, column: start, message });
+                    }
+
+                    let t = Token { ttype: TT::STRING, tstring:text, line_no, column:start };
+                    self.tokens.push(t);
+                    self.step();
+                    continue;
+                }
+
                 if c == ' '
                 {
                     // If unknown characters is not empty, then add the token
                     // This check should appear after every known token
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
@@ -1437,14 +2398,17 @@ impl Lexer
 
                     // THINKABOUT : Do we really need to record whitespaces?
                     //
-                    // let t = Token { ttype: TT::WHITE, tstring:" ".to_string(), line_no:line_no};
+                    // let t = Token { ttype: TT::WHITE, tstring:" ".to_string(), line_no};
                     // self.tokens.push(t);
                     // self.step();
 
                     continue;
                 }
 
-                if c == 'X' || c == 'x'
+                // A register only if it is not the start of a label, e.g BBS0 $12,xloop
+                let ends_word = !chars.peek().is_some_and(|nc| nc.is_alphanumeric() || *nc == '_');
+
+                if (c == 'X' || c == 'x') && ends_word
                 {
                     let pt = self.previous();
 
@@ -1453,7 +2417,7 @@ impl Lexer
                         if pt.ttype == TT::WHITE
                             || pt.ttype == TT::COMMA
                             {
-                                let t = Token { ttype: TT::REGX, tstring:"X".to_string(), line_no:line_no};
+                                let t = Token { ttype: TT::REGX, tstring:"X".to_string(), line_no, column };
                                 self.tokens.push(t);
                                 self.step();
                                 continue;
@@ -1461,25 +2425,24 @@ impl Lexer
                     }
                 }
 
-                // if c == '='
-                // {
-                //     if unknown_chars_size > 0
-                //     {
-                //         let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
-                //         self.tokens.push(t);
-                //         self.step();
-                //         unknown_chars.clear();
-                //         unknown_chars_size = 0;
-                //     }
-
-                //     let pt = self.previous();
-                //     let t = Token { ttype: TT::EQUAL, tstring:"=".to_string(), line_no:line_no };
-                //     self.tokens.push(t);
-                //     self.step();
-                //     continue;
-                // }
-
-                if c == 'Y' || c == 'y'
+                if c == '='
+                {
+                    if unknown_chars_size > 0
+                    {
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
+                        self.tokens.push(t);
+                        self.step();
+                        unknown_chars.clear();
+                        unknown_chars_size = 0;
+                    }
+
+                    let t = Token { ttype: TT::EQUAL, tstring:"=".to_string(), line_no, column };
+                    self.tokens.push(t);
+                    self.step();
+                    continue;
+                }
+
+                if (c == 'Y' || c == 'y') && ends_word
                 {
                     let pt = self.previous();
 
@@ -1488,7 +2451,7 @@ impl Lexer
                         if pt.ttype == TT::WHITE
                             || pt.ttype == TT::COMMA
                             {
-                                let t = Token { ttype: TT::REGY, tstring:"Y".to_string(), line_no:line_no};
+                                let t = Token { ttype: TT::REGY, tstring:"Y".to_string(), line_no, column };
                                 self.tokens.push(t);
                                 self.step();
                                 continue;
@@ -1500,14 +2463,14 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::HASH, tstring:"#".to_string(), line_no:line_no};
+                    let t = Token { ttype: TT::HASH, tstring:"#".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1517,14 +2480,30 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::COLON, tstring:":".to_string(), line_no:line_no};
+                    // :+ and :- refer to the next and previous anonymous label, :++ and :-- to the ones after
+                    if let Some(sign) = chars.next_if(|&nc| nc == '+' || nc == '-')
+                    {
+                        let mut tstring = format!(":{}", sign);
+                        while chars.next_if_eq(&sign).is_some()
+                        {
+                            tstring.push(sign);
+                        }
+
+                        let t = Token { ttype: TT::UNKNOWN, tstring, line_no, column };
+                        column += t.tstring.len() as u32 - 1;
+                        self.tokens.push(t);
+                        self.step();
+                        continue;
+                    }
+
+                    let t = Token { ttype: TT::COLON, tstring:":".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1534,14 +2513,14 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::DOLLAR, tstring:"$".to_string(), line_no:line_no};
+                    let t = Token { ttype: TT::DOLLAR, tstring:"$".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1551,14 +2530,14 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::PERCENT, tstring:"%".to_string(), line_no:line_no};
+                    let t = Token { ttype: TT::PERCENT, tstring:"%".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1568,14 +2547,14 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::COMMA, tstring:",".to_string(), line_no:line_no};
+                    let t = Token { ttype: TT::COMMA, tstring:",".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1585,14 +2564,14 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::BRACKETOPEN, tstring:"(".to_string(), line_no:line_no};
+                    let t = Token { ttype: TT::BRACKETOPEN, tstring:"(".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1602,14 +2581,14 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(t);
                         self.step();
                         unknown_chars.clear();
                         unknown_chars_size = 0;
                     }
 
-                    let t = Token { ttype: TT::BRACKETCLOSE, tstring:")".to_string(), line_no:line_no};
+                    let t = Token { ttype: TT::BRACKETCLOSE, tstring:")".to_string(), line_no, column };
                     self.tokens.push(t);
                     self.step();
                     continue;
@@ -1622,7 +2601,7 @@ impl Lexer
                 {
                     if unknown_chars_size > 0
                     {
-                        let token = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+                        let token = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
                         self.tokens.push(token);
                         self.step();
                         unknown_chars.clear();
@@ -1636,6 +2615,7 @@ impl Lexer
                         if pt.ttype == TT::NL
                         {
                             line_no += 1;
+                            column = 0;
                             continue;
                         }
                     }
@@ -1644,10 +2624,15 @@ impl Lexer
                     // self.tokens.push(t);
                     // self.step();
                     line_no += 1;
+                    column = 0;
 
                     continue;
                 }
 
+                if unknown_chars_size == 0
+                {
+                    unknown_column = column;
+                }
                 unknown_chars.push(c);
                 unknown_chars_size += 1;
 
@@ -1657,23 +2642,25 @@ impl Lexer
                 if c == '\n'
                 {
                     line_no += 1;
+                    column = 0;
                     is_comment = false;
                 }
             }
         }
         if unknown_chars_size > 0
         {
-            let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no:line_no};
+            let t = Token { ttype: TT::UNKNOWN, tstring:unknown_chars.iter().collect(), line_no, column:unknown_column};
             self.tokens.push(t);
             self.step();
             unknown_chars.clear();
         }
 
 
-        let t = Token { ttype: TT::EOF, tstring:"".to_string(), line_no:line_no};
+        let t = Token { ttype: TT::EOF, tstring:"".to_string(), line_no, column };
         self.tokens.push(t);
         self.step();
         self.current_token = 0;
+        self.source = code;
     }
 
     pub fn print_tokens(&self)
@@ -1686,7 +2673,11 @@ impl Lexer
 
     pub fn source_from_tokens(&self)
     {
-        let mut current_line = self.tokens.first().unwrap().line_no;
+        let mut current_line = match self.tokens.first()
+        {
+            Some(first) => first.line_no,
+            None => return,
+        };
         for t in &self.tokens
         {
             if t.line_no > current_line
@@ -1699,3 +2690,384 @@ impl Lexer
         }
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    /// Assemble `source`, panics with the diagnostics when it does not assemble
+    fn assembled(source: &str) -> (Lexer, Vec<u8>)
+    {
+        let mut lexer = Lexer { file: "test.asm".to_string(), ..Default::default() };
+        lexer.tokenize(source.to_string());
+
+        match lexer.parse()
+        {
+            Ok(code) => (lexer, code),
+            Err(diagnostics) =>
+            {
+                let rendered: Vec<String> = diagnostics.iter().map(|d| d.render(&lexer.source)).collect();
+                panic!("Source does not assemble\n{}", rendered.join("\n"));
+            },
+        }
+    }
+
+    fn code(source: &str) -> Vec<u8>
+    {
+        assembled(source).1
+    }
+
+    /// Messages of the errors found in `source`, panics if it assembles
+    fn errors(source: &str) -> Vec<String>
+    {
+        let mut lexer = Lexer { file: "test.asm".to_string(), ..Default::default() };
+        lexer.tokenize(source.to_string());
+
+        match lexer.parse()
+        {
+            Ok(_) => panic!("Source assembles"),
+            Err(diagnostics) => diagnostics.into_iter().map(|d| d.message).collect(),
+        }
+    }
+
+    #[test]
+    fn branch_offsets_are_signed()
+    {
+        let code = code("
+loop: dex
+      bne loop
+      beq done
+      nop
+done: brk
+");
+        assert_eq!(code, [0xca, 0xd0, 0xfd, 0xf0, 0x01, 0xea, 0x00]);
+    }
+
+    #[test]
+    fn branch_across_a_page()
+    {
+        // The loop starts on $06fe and the branch is on $0700
+        let code = code("
+      .org $06fe
+loop: dex
+      nop
+      bne loop
+");
+        assert_eq!(code, [0xca, 0xea, 0xd0, 0xfc]);
+    }
+
+    #[test]
+    fn branch_at_the_limits_of_its_range()
+    {
+        let forward = code("beq end\n.res 127\nend: brk");
+        assert_eq!(&forward[..2], [0xf0, 0x7f]);
+
+        let backward = code("start: .res 126\nbne start");
+        assert_eq!(&backward[126..], [0xd0, 0x80]);
+    }
+
+    #[test]
+    fn branch_out_of_range()
+    {
+        assert_eq!(errors("beq end\n.res 128\nend: brk"), ["Branch target $0682 out of range, 128 bytes away"]);
+        assert_eq!(errors("start: .res 127\nbne start"), ["Branch target $0600 out of range, -129 bytes away"]);
+    }
+
+    #[test]
+    fn undocumented_opcodes_assemble_for_the_nmos_6502()
+    {
+        assert_eq!(code("lax $10\nsax $10\ndcp $1234,x\nanc #$80\njam"), [0xa7, 0x10, 0x87, 0x10, 0xdf, 0x34, 0x12, 0x0b, 0x80, 0x02]);
+    }
+
+    #[test]
+    fn documented_encodings_come_first()
+    {
+        // NOP is $ea and SBC #imm $e9, not one of their undocumented copies
+        assert_eq!(code("nop\nsbc #1"), [0xea, 0xe9, 0x01]);
+    }
+
+    #[test]
+    fn undocumented_opcodes_are_rejected_on_the_65c02()
+    {
+        let errors = errors(".cpu 65c02\nlax $10");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("undocumented NMOS opcode"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn cmos_instructions_assemble_after_cpu_65c02()
+    {
+        let code = code("
+      .cpu 65c02
+loop: bra loop
+      stz $10
+      phx
+      ply
+      tsb $1234
+      inc a
+      bit #$40
+      lda ($10)
+      jmp ($1234,x)
+");
+        assert_eq!(code, [0x80, 0xfe, 0x64, 0x10, 0xda, 0x7a, 0x0c, 0x34, 0x12, 0x1a, 0x89, 0x40, 0xb2, 0x10, 0x7c, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn cmos_instructions_are_rejected_on_the_nmos_6502()
+    {
+        let errors = errors("stz $10\nlda ($10)");
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.contains("only available on the 65C02")), "{:?}", errors);
+    }
+
+    #[test]
+    fn wdc_instructions_assemble_after_cpu_w65c02()
+    {
+        let code = code("
+      .cpu w65c02
+loop: rmb3 $10
+      smb0 $10
+      bbr0 $10,loop
+      bbs7 $10,done
+      wai
+done: stp
+");
+        assert_eq!(code, [0x37, 0x10, 0x87, 0x10, 0x0f, 0x10, 0xf9, 0xff, 0x10, 0x01, 0xcb, 0xdb]);
+    }
+
+    #[test]
+    fn wdc_instructions_are_rejected_on_the_65c02()
+    {
+        let errors = errors(".cpu 65c02\nwai\nsmb0 $10");
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().all(|e| e.contains("only available on the WDC 65C02")), "{:?}", errors);
+    }
+
+    #[test]
+    fn implied_instruction_before_a_bit_instruction()
+    {
+        // SMB0 on the next line is not the operand of NOP
+        assert_eq!(code(".cpu w65c02\nnop\nsmb0 $10"), [0xea, 0x87, 0x10]);
+    }
+
+    #[test]
+    fn banks_are_assembled_into_a_cartridge_image()
+    {
+        let (lexer, code) = assembled("
+      .mapper fixed
+      jsr far
+      brk
+      .bank 0
+far:  rts
+      .bank 1 $c000
+      .byte 1
+");
+        assert_eq!(code, [0x20, 0x00, 0x80, 0x00]);
+        assert_eq!(lexer.mapper, MapperKind::Fixed);
+        assert_eq!(lexer.banks, [vec![0x60], vec![0x01]]);
+
+        let image = lexer.bank_image();
+        assert_eq!(image.len(), 2 * 0x4000);
+        assert_eq!((image[0], image[0x4000]), (0x60, 0x01));
+    }
+
+    #[test]
+    fn banks_must_fit_the_mapper()
+    {
+        assert_eq!(errors(".mapper latch\n.bank 0 $c000\nnop").len(), 1);
+        assert_eq!(errors(".bank 256\nnop"), ["Bank 256 is out of range, mappers select banks 0 to 255"]);
+    }
+
+    #[test]
+    fn org_places_code_and_fills_the_gaps()
+    {
+        let (lexer, code) = assembled("
+      .org $0610
+      brk
+      .org $0600
+      nop
+");
+        assert_eq!(lexer.origin, 0x0600);
+        assert_eq!(code.len(), 0x11);
+        assert_eq!((code[0x00], code[0x01], code[0x10]), (0xea, 0x00, 0x00));
+        assert!(code[1..0x10].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn org_can_not_overlap_code()
+    {
+        assert_eq!(errors(".org $0600\nnop\nnop\n.org $0601\nbrk"), ["Code at $0601 overlaps the code placed before it"]);
+    }
+
+    #[test]
+    fn byte_and_word_take_lists()
+    {
+        let code = code("
+msg:  .byte 1, $02, \"hi\", <msg, >msg
+      .word $1234, msg
+");
+        assert_eq!(code, [0x01, 0x02, b'h', b'i', 0x00, 0x06, 0x34, 0x12, 0x00, 0x06]);
+    }
+
+    #[test]
+    fn reserve_align_and_fill()
+    {
+        let code = code("
+      .byte 1
+      .res 2
+      .ds 1, $ff
+      .align 8, $ee
+      .fill 3, $aa
+end:  .word end
+");
+        assert_eq!(code, [0x01, 0x00, 0x00, 0xff, 0xee, 0xee, 0xee, 0xee, 0xaa, 0xaa, 0xaa, 0x0b, 0x06]);
+    }
+
+    #[test]
+    fn incbin_reads_next_to_the_source()
+    {
+        let dir = std::env::temp_dir().join(format!("e6502-incbin-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("data.bin"), b"abc").unwrap();
+
+        let mut lexer = Lexer { file: dir.join("test.asm").to_string_lossy().into_owned(), ..Default::default() };
+        lexer.tokenize("nop\n.incbin \"data.bin\"\nbrk".to_string());
+        let code = lexer.parse();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(code, Ok(vec![0xea, b'a', b'b', b'c', 0x00]));
+        assert_eq!(errors(".incbin \"missing.bin\"").len(), 1);
+    }
+
+    #[test]
+    fn constants_in_both_styles()
+    {
+        let code = code("
+SCREEN = $0200
+define COLOR $05
+      lda #COLOR
+      sta SCREEN
+");
+        assert_eq!(code, [0xa9, 0x05, 0x8d, 0x00, 0x02]);
+    }
+
+    #[test]
+    fn constants_pick_zero_page_from_their_value()
+    {
+        let code = code("
+PTR = $10
+BASE = $0700
+      .org BASE
+      lda PTR
+      sta PTR+1,x
+      lda BASE
+      .byte PTR
+");
+        assert_eq!(code, [0xa5, 0x10, 0x95, 0x11, 0xad, 0x00, 0x07, 0x10]);
+    }
+
+    #[test]
+    fn constants_can_not_be_redefined()
+    {
+        assert_eq!(errors("A1 = 1\nA1 = 2"), ["Constant \"A1\" already defined"]);
+        assert_eq!(errors("define X1\nlda X1")[0], "Expected a value for X1");
+    }
+
+    #[test]
+    fn pointer_setup_and_forward_references()
+    {
+        let code = code("
+      lda #<msg
+      sta $00
+      lda #>msg
+      sta $01
+      lda table+1,x
+      lda zp
+zp = $10
+table: .byte end-table
+msg:  .byte 'h', 'i'+1
+end:
+");
+        assert_eq!(code, [
+            0xa9, 0x0e, 0x85, 0x00, 0xa9, 0x06, 0x85, 0x01, 0xbd, 0x0e, 0x06, 0xa5, 0x10,
+            0x03, b'h', b'j',
+        ]);
+    }
+
+    #[test]
+    fn star_is_the_current_address()
+    {
+        assert_eq!(code("nop\njmp *\nbne *+2\n.word * - 2"), [0xea, 0x4c, 0x01, 0x06, 0xd0, 0x00, 0x04, 0x06]);
+    }
+
+    #[test]
+    fn local_labels_are_scoped_to_the_global_label()
+    {
+        let code = code("
+main: ldx #2
+@loop: dex
+      bne @loop
+draw: ldy #2
+.loop: dey
+      bne .loop
+      jmp main.loop
+");
+        assert_eq!(code, [0xa2, 0x02, 0xca, 0xd0, 0xfd, 0xa0, 0x02, 0x88, 0xd0, 0xfd, 0x4c, 0x02, 0x06]);
+        assert_eq!(errors("@x: nop"), ["Local label \"@x\" must follow a global label"]);
+    }
+
+    #[test]
+    fn anonymous_labels_count_from_the_reference()
+    {
+        let code = code("
+:     inx
+      beq :+
+      bne :-
+      beq :++
+:     nop
+:     brk
+");
+        assert_eq!(code, [0xe8, 0xf0, 0x04, 0xd0, 0xfb, 0xf0, 0x01, 0xea, 0x00]);
+        assert_eq!(errors("bne :+")[0], "No anonymous label for :+");
+    }
+
+    #[test]
+    fn proc_qualifies_the_labels_inside()
+    {
+        let code = code("
+      jsr sub.inner
+.proc sub
+inner: rts
+.endproc
+");
+        assert_eq!(code, [0x20, 0x03, 0x06, 0x60]);
+    }
+
+    #[test]
+    fn duplicate_labels_are_errors()
+    {
+        assert_eq!(errors("a: nop\na: nop"), ["Label \"a\" already defined"]);
+    }
+
+    #[test]
+    fn symbols_use_qualified_names()
+    {
+        let (lexer, _) = assembled("
+main: nop
+@loop: bne @loop
+:     nop
+.proc sub
+inner: rts
+.endproc
+");
+        let symbols = lexer.symbols();
+        assert_eq!(symbols, [
+            ("main".to_string(), 0x0600),
+            ("main.loop".to_string(), 0x0601),
+            ("sub".to_string(), 0x0604),
+            ("sub.inner".to_string(), 0x0604),
+        ]);
+        assert_eq!(lexer.symbol_file(), "$0600 main\n$0601 main.loop\n$0604 sub\n$0604 sub.inner\n");
+    }
+}
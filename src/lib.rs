@@ -0,0 +1,17 @@
+//! The 6502 core shared by the binaries and the benchmark: the instruction set, the
+//! assembler, the cpu with its bus, mappers and save states, and the disassembler.
+//! Nothing here depends on SDL, so `cargo test` runs without it
+
+pub mod isa;
+pub mod lexer;
+pub mod cpu;
+pub mod bus;
+pub mod flags;
+pub mod mapper;
+pub mod state;
+pub mod decode;
+pub mod diagnostic;
+pub mod expr;
+pub mod selftest;
+pub mod disasm;
+pub mod scheduler;
@@ -2,45 +2,99 @@ extern crate sdl2;
 
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::{FullscreenType, Window};
 
-mod isa;
-mod lexer;
-mod cpu;
+mod display;
+
+use e6502::{bus, cpu, disasm, isa, lexer, mapper, scheduler, selftest, state};
 
 // use std::env;
 // use std::io::Write;
 // use std::fs;
 use std::env;
 use std::fs;
+use std::path::Path;
 use rand::Rng;
+use rand::rngs::ThreadRng;
+use bus::Bus;
+use scheduler::{Scheduler, Speed};
+use display::{Display, Scaling};
+
+/// Address of the random number port, a new random byte is read on every access
+const RANDOM_PORT: u16 = 0xfe;
 
-use std::collections::HashMap;
+/// Save state written by F5 and read by F9 when --load-state is not given
+const STATE_FILE: &str = "e6502.state";
 
-#[inline(always)]
-fn get_pixel_color(value : u8) -> Color
+/// Machine memory, a memory map with a random number generator mapped at RANDOM_PORT
+struct Machine
 {
-    match value & 0b00001111
+    map: bus::MemoryMap,
+    rng: ThreadRng,
+}
+
+impl Bus for Machine
+{
+    fn read(&mut self, address: u16) -> u8
+    {
+        if address == RANDOM_PORT
+        {
+            return self.rng.gen();
+        }
+
+        self.map.read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8)
+    {
+        self.map.write(address, value);
+    }
+
+    fn peek(&self, address: u16) -> u8
+    {
+        self.map.peek(address)
+    }
+
+    fn poke(&mut self, address: u16, value: u8)
+    {
+        self.map.poke(address, value);
+    }
+
+    fn cacheable(&self, address: u16) -> bool
+    {
+        address != RANDOM_PORT && self.map.cacheable(address)
+    }
+
+    fn is_device_register(&self, address: u16) -> bool
+    {
+        self.map.is_device_register(address)
+    }
+
+    fn save_devices(&self, out: &mut Vec<u8>)
     {
+        self.map.save_devices(out);
+    }
 
-        0 =>  { return Color::RGB(0, 0, 0); },        // Black
-        1 =>  { return Color::RGB(255, 255, 255); },  // White
-        2 =>  { return Color::RGB(255, 0, 0); },      // Red
-        3 =>  { return Color::RGB(0, 255, 255); },    // Cyan
-        4 =>  { return Color::RGB(128, 0, 128); },    // Purple
-        5 =>  { return Color::RGB(0, 255, 0); },      // Green
-        6 =>  { return Color::RGB(0, 0, 255); },      // Blue
-        7 =>  { return Color::RGB(255, 255, 0); },    // Yellow
-        8 =>  { return Color::RGB(255, 165, 0); },    // Orange
-        9 =>  { return Color::RGB(165, 42, 42); },    // Brown
-        10 => { return Color::RGB(255, 119, 119); },  // Light Red
-        11 => { return Color::RGB(169,169, 169); },   // Dark Grey 
-        12 => { return Color::RGB(128, 128, 128); },  // Grey
-        13 => { return Color::RGB(144, 238, 144); },  // Light green
-        14 => { return Color::RGB(173, 216, 230); },  // Light blue
-        15 => { return Color::RGB(211, 211, 211); },  // Light grey 
-        _ =>  { return Color::RGB(0, 0, 0); }
+    fn load_devices(&mut self, input: &mut state::Reader) -> Result<(), state::StateError>
+    {
+        self.map.load_devices(input)
+    }
+
+    // The random number generator has no state worth restoring
+    fn save_state(&self, out: &mut Vec<u8>)
+    {
+        self.map.save_state(out);
+    }
+
+    fn check_state(&self, input: &mut state::Reader) -> Result<(), state::StateError>
+    {
+        self.map.check_state(input)
+    }
+
+    fn load_state(&mut self, input: &mut state::Reader) -> Result<(), state::StateError>
+    {
+        self.map.load_state(input)
     }
 }
 
@@ -55,49 +109,327 @@ fn usage()
         println!("                 e.g e6502 compile hello.asm");
         println!("   run <bin>     runs the machine code specified");
         println!("                 e.g e6502 run hello.bin");
+        println!("                 banks are loaded from hello.rom when it exists");
         println!("   cr <src>      compile and run");
+        println!("   disasm <bin>  lists the instructions of a binary");
+        println!("                 e.g e6502 disasm hello.bin --cpu 65c02");
+        println!("   selftest      checks the ALU instructions and their flags against");
+        println!("                 reference results for every input");
+        println!("\nOptions for run and disasm:");
+        println!("   --cpu <name>         instruction set of the binary, 6502 (default), 65c02 or w65c02");
+        println!("   --origin <address>   address the binary is loaded at, defaults to $0600");
+        println!("\nOptions for run:");
+        println!("   --mapper <name>      mapper of the .rom cartridge image, latch, fixed (default)");
+        println!("                        or window, the same names as the .mapper directive");
+        println!("\nOptions for run and cr:");
+        println!("   --load-state <file>  start from a save state, F5/F9 save/load it again");
+        println!("                        (defaults to {}) while running", STATE_FILE);
+        println!("   --break <address>    stop before executing the instruction at address,");
+        println!("                        can be repeated e.g --break $0610");
+        println!("   --history <n>        record the last n instructions so Left and R can step back,");
+        println!("                        off by default as it slows emulation down");
+        println!("   --speed <hz|max>     clock of the cpu in Hz (defaults to 1000000), max runs");
+        println!("                        as fast as possible, the screen is drawn 60 times a second");
+        println!("   --scale <mode>       integer (default) scales the screen by whole multiples,");
+        println!("                        smooth fills the window");
+        println!("   --fullscreen         start in fullscreen");
+        println!("   --rom                load the program as ROM, writes to it are reported");
+        println!("                        and ignored");
+        println!("\nKeys while running:");
+        println!("   Space  pause/continue      Right  step one instruction");
+        println!("   Left   step back           R      reverse continue to a breakpoint");
+        println!("   F5     save state          F9     load state");
+        println!("   F11    toggle fullscreen   S      toggle integer/smooth scaling");
+}
+
+/// Command line options of run and cr
+#[derive(Default)]
+struct Options
+{
+    /// Save state to start from, F5/F9 also use it
+    load_state: Option<String>,
+    breakpoints: Vec<u16>,
+    /// Number of instructions that can be stepped back, 0 when the history is off
+    history: usize,
+    speed: Speed,
+    scaling: Scaling,
+    fullscreen: bool,
+    /// Load the program as ROM, writes to it are reported and ignored
+    rom: bool,
 }
 
-fn compile(src_path: &str, should_write: bool) -> Vec<u8>
+/// Remove the flag `name` from `args`, returns whether it was there
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool
 {
-    let mut lex = lexer::Lexer 
+    match args.iter().position(|arg| arg == name)
     {
-        tokens:Vec::new(),
-        current_token:0,
-        labels:HashMap::new()
-    };
+        Some(i) =>
+        {
+            args.remove(i);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Remove `name` and the value following it from `args`, returns the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String>
+{
+    let i = args.iter().position(|arg| arg == name)?;
+    if i + 1 >= args.len()
+    {
+        usage();
+        std::process::exit(0);
+    }
+
+    let value = args.remove(i + 1);
+    args.remove(i);
+    Some(value)
+}
 
-    let source = fs::read_to_string(src_path).expect("Error occured while reading file");
+/// Parse a hex address written as $0610, 0x0610 or 0610
+fn parse_address(arg: &str) -> Option<u16>
+{
+    let digits = arg.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).ok()
+}
+
+/// Instruction set named by --cpu, the same names as the .cpu directive
+fn parse_variant(name: &str) -> Option<isa::Variant>
+{
+    match name.to_lowercase().as_str()
+    {
+        "6502" => Some(isa::Variant::Nmos),
+        "65c02" => Some(isa::Variant::Cmos),
+        "w65c02" => Some(isa::Variant::Wdc),
+        _ => None,
+    }
+}
+
+/// Mapper named by --mapper, the same names as the .mapper directive
+fn parse_mapper(name: &str) -> Option<mapper::MapperKind>
+{
+    match name.to_lowercase().as_str()
+    {
+        "latch" => Some(mapper::MapperKind::Latch),
+        "fixed" => Some(mapper::MapperKind::Fixed),
+        "window" => Some(mapper::MapperKind::Window),
+        _ => None,
+    }
+}
+
+/// Cartridge image next to the binary at `bin_path`, e.g hello.rom for hello.bin
+///
+/// None when there is no image, an error when it can't be used with `kind`
+fn read_cartridge(bin_path: &str, kind: mapper::MapperKind) -> Result<Option<(mapper::MapperKind, Vec<u8>)>, String>
+{
+    let rom_path = Path::new(bin_path).with_extension("rom");
+    if !rom_path.exists()
+    {
+        return Ok(None);
+    }
+
+    let image = fs::read(&rom_path).map_err(|e| format!("Unable to read {} : {}", rom_path.display(), e))?;
+    let banks = image.len() / kind.bank_size();
+    if image.is_empty() || !image.len().is_multiple_of(kind.bank_size()) || banks > mapper::MAX_BANKS
+    {
+        return Err(format!("{} is {} bytes, the {:?} mapper needs 1 to {} banks of {} bytes",
+            rom_path.display(), image.len(), kind, mapper::MAX_BANKS, kind.bank_size()));
+    }
+
+    Ok(Some((kind, image)))
+}
+
+/// Speed given to --speed, a clock in Hz or max
+fn parse_speed(arg: &str) -> Option<Speed>
+{
+    if arg.eq_ignore_ascii_case("max")
+    {
+        return Some(Speed::Max);
+    }
+
+    arg.parse().ok().filter(|&hz| hz > 0).map(Speed::Clock)
+}
+
+/// Disassembly of the instruction at pc
+fn next_instruction<B: Bus>(cpu: &cpu::Cpu<B>) -> String
+{
+    disasm::disassemble(cpu.variant, cpu.pc, |address| cpu.bus.peek(address)).0
+}
+
+/// Output of the assembler
+struct Program
+{
+    /// Address the code is loaded at
+    origin: u16,
+    code: Vec<u8>,
+    variant: isa::Variant,
+    /// Mapper and cartridge image when the source uses .bank
+    cartridge: Option<(mapper::MapperKind, Vec<u8>)>,
+}
+
+/// Assemble the source file at `src_path`, the diagnostics are printed if it doesn't assemble
+fn compile(src_path: &str, should_write: bool) -> Result<Program, String>
+{
+    let mut lex = lexer::Lexer { file: src_path.to_string(), ..Default::default() };
+
+    let source = fs::read_to_string(src_path).map_err(|e| format!("Unable to read {} : {}", src_path, e))?;
 
     lex.tokenize(source);
-    let out = lex.parse();
+    let out = match lex.parse()
+    {
+        Ok(out) => out,
+        Err(diagnostics) =>
+        {
+            for diagnostic in &diagnostics
+            {
+                eprintln!("{}", diagnostic.render(&lex.source));
+            }
+            return Err(format!("{} could not be assembled, {} error(s)", src_path, diagnostics.len()));
+        },
+    };
+    let cartridge = if lex.banks.is_empty() { None } else { Some((lex.mapper, lex.bank_image())) };
 
     if should_write
     {
         fs::write("out.bin", out.as_slice()).expect("Unknown error while writinng to bin");
+        if let Some((_, image)) = &cartridge
+        {
+            fs::write("out.rom", image).expect("Unknown error while writing the bank image");
+        }
+        fs::write("out.sym", lex.symbol_file()).expect("Unknown error while writing the symbol file");
+    }
+
+    Ok(Program { origin: lex.origin, code: out, variant: lex.cpu, cartridge })
+}
+
+impl Program
+{
+    /// Address execution starts at, where the reset vector points when the program sets it,
+    /// its first byte otherwise
+    fn entry(&self) -> u16
+    {
+        let vector = usize::from(cpu::Cpu::RESET_VECTOR);
+        let origin = usize::from(self.origin);
+
+        if origin <= vector && vector + 2 <= origin + self.code.len()
+        {
+            return u16::from_le_bytes([self.code[vector - origin], self.code[vector + 1 - origin]]);
+        }
+
+        self.origin
+    }
+}
+
+/// Restore the save state at `path`
+fn load_state<B: Bus>(cpu: &mut cpu::Cpu<B>, path: &str) -> Result<(), String>
+{
+    let data = fs::read(path).map_err(|e| format!("Unable to read {} : {}", path, e))?;
+    cpu.load_state(&data).map_err(|e| format!("Unable to load {} : {}", path, e))
+}
+
+fn run(program: &Program, options: &Options) -> Result<(), String>
+{
+    let instructions = program.code.as_slice();
+
+    // The program is loaded at its origin over the memory of the machine, so programs
+    // assembled above $8000 or setting the vectors still run. Programs keep their variables
+    // next to the code, so it is only ROM with --rom, stray writes to it are then reported
+    let mut map = bus::MemoryMap::machine(program.entry());
+    if options.rom && !instructions.is_empty()
+    {
+        map.rom(program.origin, instructions);
+    }
+    else if !instructions.is_empty()
+    {
+        map.ram(program.origin, (usize::from(program.origin) + instructions.len() - 1) as u16);
+        map.load(program.origin, instructions);
+    }
+    map.on_warning(|message| println!("Warning : {}", message));
+
+    let machine = Machine { map, rng: rand::thread_rng() };
+
+    match &program.cartridge
+    {
+        Some((kind, image)) => run_cpu(cpu::Cpu::new(kind.build(machine, image.clone())), program, options),
+        None => run_cpu(cpu::Cpu::new(machine), program, options),
     }
+}
 
-    out
+/// Set the window title to `message`
+fn set_title(canvas: &mut Canvas<Window>, message: &str) -> Result<(), String>
+{
+    let title = format!("6502 Emulator by JNjenga ({})", message);
+    canvas.window_mut().set_title(&title).map_err(|e| e.to_string())
 }
 
-fn run(instructions: &[u8]) -> Result<(), String>
+/// Execute one instruction, returns why execution should stop
+fn step<B: Bus>(cpu: &mut cpu::Cpu<B>, breakpoints: &[u16]) -> Option<String>
 {
-    // Initialize cpu
-    let mut cpu = cpu::Cpu
+    let result = cpu.step();
+    stop_reason(cpu, result, breakpoints)
+}
+
+/// Why execution should stop after an instruction that gave `result`, None to go on
+fn stop_reason<B: Bus>(cpu: &cpu::Cpu<B>, result: Result<cpu::StepResult, cpu::CpuError>, breakpoints: &[u16]) -> Option<String>
+{
+    // Nothing raises interrupts yet, so WAI never resumes
+    let status = match result
     {
-        a: 0,
-        x: 0,
-        y: 0,
-        sp: 0xff,
-        pc: 0x600,
-        sr: 0b00110000,
-        mem: [0;1<<16],
+        Ok(cpu::StepResult::Executed { .. }) => None,
+        Ok(cpu::StepResult::Break { pc, .. }) => Some(format!("BRK at {:#06x}", pc)),
+        Ok(cpu::StepResult::Waiting { .. }) => Some("Waiting for an interrupt".to_string()),
+        Ok(cpu::StepResult::Stopped { .. }) => Some("Stopped".to_string()),
+        Err(e) =>
+        {
+            println!("{}", e);
+            cpu.print_regs();
+            Some(e.to_string())
+        },
     };
+    // cpu.print_regs();
+
+    if status.is_none() && breakpoints.contains(&cpu.pc)
+    {
+        return Some(format!("Breakpoint at {:#06x}", cpu.pc));
+    }
+
+    status
+}
 
-    for i in 0..instructions.len()
+/// Step back to the previous breakpoint, or as far as the history goes
+fn reverse_continue<B: Bus>(cpu: &mut cpu::Cpu<B>, breakpoints: &[u16]) -> String
+{
+    loop
     {
-        cpu.mem[usize::from(cpu.pc + i as u16)] = instructions[i];
+        if !cpu.step_back()
+        {
+            return format!("Start of history at {:#06x}", cpu.pc);
+        }
+
+        if breakpoints.contains(&cpu.pc)
+        {
+            return format!("Breakpoint at {:#06x}", cpu.pc);
+        }
     }
+}
+
+fn run_cpu<B: Bus>(mut cpu: cpu::Cpu<B>, program: &Program, options: &Options) -> Result<(), String>
+{
+    cpu.variant = program.variant;
+    // The assembler accepts the stable undocumented opcodes, so run them too
+    cpu.undocumented = true;
+    cpu.reset();
+    // The cartridge may cover the reset vector, programs always start at their entry
+    cpu.pc = program.entry();
+
+    if let Some(path) = &options.load_state
+    {
+        load_state(&mut cpu, path)?;
+    }
+    let state_path = options.load_state.as_deref().unwrap_or(STATE_FILE);
+    // Recording the history takes every instruction through Cpu::step, it is off unless asked for
+    cpu.set_history(options.history);
 
     const SCREEN_HEIGHT: u32 = 640;
     const SCREEN_WIDTH: u32 = 640;
@@ -118,37 +450,55 @@ fn run(instructions: &[u8]) -> Result<(), String>
         .build()
         .map_err(|e| e.to_string())?;
 
-    canvas.set_draw_color(Color::RGB(255, 0, 0));
-    canvas.clear();
+    if options.fullscreen
+    {
+        canvas.window_mut().set_fullscreen(FullscreenType::Desktop)?;
+    }
+
+    let texture_creator = canvas.texture_creator();
+    let mut display = Display::new(&texture_creator, options.scaling)?;
     let mut event_pump = sdl_context.event_pump()?;
 
-    let pc_max = instructions.len() as u16 + 0x600 + 0x01;
-    let mut rng = rand::thread_rng();
+    let pc_min = u32::from(program.origin);
+    let pc_max = pc_min + program.code.len() as u32 + 0x01;
+    // Bank code lives outside the program, only stop at the end of programs without banks
+    let banked = program.cartridge.is_some();
+    let outside = |pc: u16| !banked && (u32::from(pc) >= pc_max || u32::from(pc) < pc_min);
+    // Why execution stopped, the window stays open so the screen can still be inspected
+    let mut status: Option<String> = None;
+
+    let mut scheduler = Scheduler::new(options.speed);
 
     'running: loop {
-        if cpu.pc < pc_max && cpu.pc >= 0x600
+        if status.is_none()
         {
-            cpu.mem[0xfe] = rng.gen();
-
-            cpu.step();
-            // cpu.print_regs();
-
-            canvas.set_draw_color(Color::RGB(255, 0, 0));
-
-            // Draw monitor
-            for row in 0..32
+            // Run one frame worth of cycles then draw the screen once
+            status = scheduler.run_frame(&mut cpu, |cpu, cycles|
             {
-                for col in 0..32
+                if outside(cpu.pc)
                 {
-                    let color = get_pixel_color(cpu.mem[0x200 + (32 * row + col)]);
-                    canvas.set_draw_color(color);
-                    canvas.fill_rect(Rect::new(col as i32 * 20_i32, row as i32 * 20_i32, 20, 20))?;
+                    return Some(format!("End of program at {:#06x}", cpu.pc));
                 }
+
+                let result = cpu.run_until(cycles, |cpu| outside(cpu.pc) || options.breakpoints.contains(&cpu.pc));
+                stop_reason(cpu, result, &options.breakpoints)
+                    .or_else(|| if outside(cpu.pc) { Some(format!("End of program at {:#06x}", cpu.pc)) } else { None })
+            });
+
+            if let Some(status) = &status
+            {
+                set_title(&mut canvas, status)?;
+            }
+            else if let Some(hz) = scheduler.measured_hz()
+            {
+                set_title(&mut canvas, &format!("Running at {:.3} MHz", hz as f64 / 1_000_000.0))?;
             }
 
-            canvas.present();
         }
 
+        // Drawn every frame, also while paused, so resizing the window redraws the screen
+        display.draw(&mut canvas, |address| cpu.bus.peek(address))?;
+
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
@@ -156,11 +506,97 @@ fn run(instructions: &[u8]) -> Result<(), String>
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::KeyDown { keycode: Some(Keycode::Space), .. } =>
+                {
+                    // Continuing after BRK or an error stops again right away
+                    status = match status
+                    {
+                        None => Some(format!("Paused at {:#06x} : {}", cpu.pc, next_instruction(&cpu))),
+                        Some(_) => None,
+                    };
+                    set_title(&mut canvas, status.as_deref().unwrap_or("Running"))?;
+                },
+                Event::KeyDown { keycode: Some(Keycode::Right), .. } if status.is_some() =>
+                {
+                    let message = step(&mut cpu, &options.breakpoints)
+                        .unwrap_or_else(|| format!("Paused at {:#06x} : {}", cpu.pc, next_instruction(&cpu)));
+                    set_title(&mut canvas, &message)?;
+                    status = Some(message);
+                },
+                Event::KeyDown { keycode: Some(Keycode::Left | Keycode::R), .. } if options.history == 0 =>
+                {
+                    let message = "Stepping back needs the history, start with --history <instructions>".to_string();
+                    set_title(&mut canvas, &message)?;
+                    status = Some(message);
+                },
+                Event::KeyDown { keycode: Some(Keycode::Left), .. } =>
+                {
+                    let message = if cpu.step_back()
+                    {
+                        format!("Stepped back to {:#06x} : {}", cpu.pc, next_instruction(&cpu))
+                    }
+                    else
+                    {
+                        format!("Start of history at {:#06x}", cpu.pc)
+                    };
+                    set_title(&mut canvas, &message)?;
+                    status = Some(message);
+                },
+                Event::KeyDown { keycode: Some(Keycode::R), .. } =>
+                {
+                    let message = reverse_continue(&mut cpu, &options.breakpoints);
+                    set_title(&mut canvas, &message)?;
+                    status = Some(message);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } =>
+                {
+                    let message = match fs::write(state_path, cpu.save_state())
+                    {
+                        Ok(()) => format!("State saved to {}", state_path),
+                        Err(e) => format!("Unable to write {} : {}", state_path, e),
+                    };
+                    println!("{}", message);
+                    set_title(&mut canvas, &message)?;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F11), .. } =>
+                {
+                    let fullscreen = match canvas.window().fullscreen_state()
+                    {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off,
+                    };
+                    canvas.window_mut().set_fullscreen(fullscreen)?;
+                },
+                Event::KeyDown { keycode: Some(Keycode::S), .. } =>
+                {
+                    let scaling = match display.scaling()
+                    {
+                        Scaling::Integer => Scaling::Smooth,
+                        Scaling::Smooth => Scaling::Integer,
+                    };
+                    display.set_scaling(scaling)?;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } =>
+                {
+                    let message = match load_state(&mut cpu, state_path)
+                    {
+                        Ok(()) =>
+                        {
+                            // Resume from the restored state
+                            status = None;
+                            format!("State loaded from {}", state_path)
+                        },
+                        Err(e) => e,
+                    };
+                    println!("{}", message);
+                    set_title(&mut canvas, &message)?;
+                },
                 _ => {}
             }
         }
 
-        // ::std::thread::sleep(std::time::Duration::new(0, 1_000_000_000u32 / 3));
+        // Stopped or paused frames only handle events
+        scheduler.wait_for_next_frame();
     }
 
     Ok(())
@@ -168,29 +604,153 @@ fn run(instructions: &[u8]) -> Result<(), String>
 
 fn main() -> Result<(), String>
 {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    // Options can follow the command, e.g e6502 cr hello.asm --load-state hello.state
+    let mut options = Options { load_state: take_option(&mut args, "--load-state"), ..Default::default() };
+    while let Some(arg) = take_option(&mut args, "--break")
+    {
+        match parse_address(&arg)
+        {
+            Some(address) => options.breakpoints.push(address),
+            None =>
+            {
+                println!("Invalid breakpoint address {:?}", arg);
+                usage();
+                std::process::exit(0);
+            },
+        }
+    }
+
+    let variant = match take_option(&mut args, "--cpu")
+    {
+        Some(name) => match parse_variant(&name)
+        {
+            Some(variant) => variant,
+            None =>
+            {
+                println!("Unknown cpu {:?}", name);
+                usage();
+                std::process::exit(0);
+            },
+        },
+        None => isa::Variant::Nmos,
+    };
+
+    let mapper = match take_option(&mut args, "--mapper")
+    {
+        Some(name) => match parse_mapper(&name)
+        {
+            Some(mapper) => Some(mapper),
+            None =>
+            {
+                println!("Unknown mapper {:?}", name);
+                usage();
+                std::process::exit(0);
+            },
+        },
+        None => None,
+    };
+
+    let origin = match take_option(&mut args, "--origin")
+    {
+        Some(arg) => match parse_address(&arg)
+        {
+            Some(address) => address,
+            None =>
+            {
+                println!("Invalid origin {:?}", arg);
+                usage();
+                std::process::exit(0);
+            },
+        },
+        None => 0x600,
+    };
+
+    if let Some(arg) = take_option(&mut args, "--history")
+    {
+        options.history = match arg.parse()
+        {
+            Ok(length) => length,
+            Err(_) =>
+            {
+                println!("Invalid history length {:?}", arg);
+                usage();
+                std::process::exit(0);
+            },
+        };
+    }
+
+    if let Some(arg) = take_option(&mut args, "--speed")
+    {
+        options.speed = match parse_speed(&arg)
+        {
+            Some(speed) => speed,
+            None =>
+            {
+                println!("Invalid speed {:?}", arg);
+                usage();
+                std::process::exit(0);
+            },
+        };
+    }
 
-    if args.len() > 2
+    if let Some(arg) = take_option(&mut args, "--scale")
+    {
+        options.scaling = match arg.to_lowercase().as_str()
+        {
+            "integer" => Scaling::Integer,
+            "smooth" => Scaling::Smooth,
+            _ =>
+            {
+                println!("Invalid scaling {:?}", arg);
+                usage();
+                std::process::exit(0);
+            },
+        };
+    }
+    options.fullscreen = take_flag(&mut args, "--fullscreen");
+    options.rom = take_flag(&mut args, "--rom");
+
+    if args.len() == 2 && args[1] == "selftest"
+    {
+        selftest::run()?;
+    }
+    else if args.len() > 2
     {
         if args[1] == "compile"
         {
             println!("Compiling..");
-            let _ = compile(&args[2], true);
+            compile(&args[2], true)?;
             println!("Done");
         }
-        else if args[2] == "run"
+        else if args[1] == "run"
         {
-            let instructions = fs::read(&args[1]).expect("Unable to read file");
-            run(instructions.as_slice())?;
+            let code = fs::read(&args[2]).expect("Unable to read file");
+            let cartridge = read_cartridge(&args[2], mapper.unwrap_or_default())?;
+            if mapper.is_some() && cartridge.is_none()
+            {
+                return Err(format!("--mapper needs a cartridge image, {} not found",
+                    Path::new(&args[2]).with_extension("rom").display()));
+            }
+            run(&Program { origin, code, variant, cartridge }, &options)?;
+        }
+        else if args[1] == "disasm"
+        {
+            let code = fs::read(&args[2]).map_err(|e| format!("Unable to read {} : {}", args[2], e))?;
+            for line in disasm::listing(variant, &code, origin)
+            {
+                println!("{}", line);
+            }
         }
         else if args[1] == "cr"
         {
             println!("Compiling..");
-            let instructions = compile(&args[2], false);
+            let program = compile(&args[2], false)?;
             println!("Done");
 
             println!("Running..");
-            run(instructions.as_slice())?;
+            run(&program, &options)?;
             println!("Done");
         }
         else
@@ -0,0 +1,448 @@
+use crate::bus::{Bus, MemoryMap};
+use crate::state::{Reader, StateError};
+use std::ops::RangeInclusive;
+
+/// Register selecting the bank of the Window mapper, the last byte of its window
+/// so it takes nothing away from the RAM around the window
+pub const BANK_PORT: u16 = 0xbfff;
+
+/// Banks a mapper can select, the bank number is written as one byte
+pub const MAX_BANKS: usize = 256;
+
+/// Stock mappers, selected in the assembler with `.mapper latch`, `.mapper fixed` and `.mapper window`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum MapperKind
+{
+    /// 8 KiB banks at $8000, a write anywhere in the window latches the bank number
+    Latch,
+    /// 16 KiB switchable bank at $8000 and the last bank fixed at $c000 (like NROM/UxROM),
+    /// a write anywhere in $8000-$ffff selects the switchable bank
+    #[default]
+    Fixed,
+    /// 16 KiB window at $8000 selected by writes to BANK_PORT ($bfff), the rest of memory is untouched
+    Window,
+}
+
+#[allow(dead_code)]
+impl MapperKind
+{
+    /// Size of the banks of the cartridge image
+    pub fn bank_size(self) -> usize
+    {
+        match self
+        {
+            MapperKind::Latch => 0x2000,
+            MapperKind::Fixed | MapperKind::Window => 0x4000,
+        }
+    }
+
+    /// Address the switchable bank is mapped at
+    pub fn window(self) -> u16
+    {
+        0x8000
+    }
+
+    /// First address of every slot, code of a bank is assembled for one of them
+    pub fn slots(self) -> &'static [u16]
+    {
+        match self
+        {
+            MapperKind::Latch | MapperKind::Window => &[0x8000],
+            MapperKind::Fixed => &[0x8000, 0xc000],
+        }
+    }
+
+    /// Slot that always shows the last bank
+    pub fn fixed_slot(self) -> Option<u16>
+    {
+        match self
+        {
+            MapperKind::Fixed => Some(0xc000),
+            MapperKind::Latch | MapperKind::Window => None,
+        }
+    }
+
+    /// Put `image` behind a mapper of this kind, memory outside the windows is `inner`
+    pub fn build<B: Bus>(self, inner: B, image: Vec<u8>) -> Mapper<B>
+    {
+        match self
+        {
+            MapperKind::Latch => Mapper::latch(inner, image, self.bank_size(), self.window()),
+            MapperKind::Fixed => Mapper::fixed_switchable(inner, image, self.bank_size(), self.window(), 0x8000..=0xffff),
+            MapperKind::Window => Mapper::window16k(inner, image, self.window(), BANK_PORT..=BANK_PORT),
+        }
+    }
+}
+
+/// A window of the address space showing one bank of the image
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Slot
+{
+    /// First address of the window, the window is one bank long
+    pub start: u16,
+    /// Bank currently visible in the window
+    pub bank: usize,
+}
+
+/// Bank switching on top of another bus
+///
+/// The image is split in banks of `bank_size` bytes, each slot shows one of them
+/// and writes to a register select the bank of its slot. Banks are read only,
+/// accesses outside the slots go to `inner`
+pub struct Mapper<B: Bus = MemoryMap>
+{
+    pub inner: B,
+    image: Vec<u8>,
+    bank_size: usize,
+    slots: Vec<Slot>,
+    /// Writes to an address in the range select the bank of the slot
+    registers: Vec<(RangeInclusive<u16>, usize)>,
+}
+
+#[allow(dead_code)]
+impl<B: Bus> Mapper<B>
+{
+    /// Mapper without slots, `image` must hold a whole number of banks
+    pub fn new(inner: B, image: Vec<u8>, bank_size: usize) -> Mapper<B>
+    {
+        assert!(bank_size > 0 && bank_size <= 0x8000, "Unsupported bank size {:#x}", bank_size);
+        assert!(!image.is_empty() && image.len().is_multiple_of(bank_size),
+            "Image of {:#x} bytes is not a whole number of {:#x} byte banks", image.len(), bank_size);
+
+        Mapper
+        {
+            inner,
+            image,
+            bank_size,
+            slots: Vec::new(),
+            registers: Vec::new(),
+        }
+    }
+
+    /// One switchable slot at `window`, writes anywhere in the window select its bank
+    pub fn latch(inner: B, image: Vec<u8>, bank_size: usize, window: u16) -> Mapper<B>
+    {
+        let mut mapper = Mapper::new(inner, image, bank_size);
+        let slot = mapper.add_slot(window, 0);
+        let end = mapper.slot_end(slot);
+        mapper.add_register(window..=end, slot);
+
+        mapper
+    }
+
+    /// A switchable slot at `window` followed by a slot fixed to the last bank,
+    /// writes to `register` select the bank of the switchable slot
+    pub fn fixed_switchable(inner: B, image: Vec<u8>, bank_size: usize, window: u16, register: RangeInclusive<u16>) -> Mapper<B>
+    {
+        let mut mapper = Mapper::new(inner, image, bank_size);
+        let slot = mapper.add_slot(window, 0);
+        let last = mapper.bank_count() - 1;
+        mapper.add_slot(window + bank_size as u16, last);
+        mapper.add_register(register, slot);
+
+        mapper
+    }
+
+    /// A single 16 KiB slot at `window`, writes to `register` select its bank
+    pub fn window16k(inner: B, image: Vec<u8>, window: u16, register: RangeInclusive<u16>) -> Mapper<B>
+    {
+        let mut mapper = Mapper::new(inner, image, 0x4000);
+        let slot = mapper.add_slot(window, 0);
+        mapper.add_register(register, slot);
+
+        mapper
+    }
+
+    /// Show `bank` at `start`, returns the index of the slot
+    pub fn add_slot(&mut self, start: u16, bank: usize) -> usize
+    {
+        assert!(usize::from(start) + self.bank_size <= 0x10000, "Slot at ${:04x} goes past the end of memory", start);
+
+        self.slots.push(Slot { start, bank: bank % self.bank_count() });
+        self.slots.len() - 1
+    }
+
+    /// Make writes to `range` select the bank of `slot`
+    pub fn add_register(&mut self, range: RangeInclusive<u16>, slot: usize)
+    {
+        assert!(slot < self.slots.len(), "No slot {}", slot);
+
+        self.registers.push((range, slot));
+    }
+
+    /// Show `bank` in `slot`, bank numbers wrap around the number of banks
+    pub fn select(&mut self, slot: usize, bank: usize)
+    {
+        self.slots[slot].bank = bank % self.bank_count();
+    }
+
+    pub fn slots(&self) -> &[Slot]
+    {
+        &self.slots
+    }
+
+    pub fn bank_count(&self) -> usize
+    {
+        self.image.len() / self.bank_size
+    }
+
+    pub fn bank_size(&self) -> usize
+    {
+        self.bank_size
+    }
+
+    /// Last address of `slot`
+    fn slot_end(&self, slot: usize) -> u16
+    {
+        (usize::from(self.slots[slot].start) + self.bank_size - 1) as u16
+    }
+
+    /// Append the number of slots and the bank of each
+    fn save_banks(&self, out: &mut Vec<u8>)
+    {
+        out.push(self.slots.len() as u8);
+        for slot in &self.slots
+        {
+            out.extend_from_slice(&(slot.bank as u16).to_le_bytes());
+        }
+    }
+
+    /// Show `banks[i]` in slot i, the banks come from read_banks
+    fn set_banks(&mut self, banks: Vec<usize>)
+    {
+        for (slot, bank) in self.slots.iter_mut().zip(banks)
+        {
+            slot.bank = bank;
+        }
+    }
+
+    /// Bank of every slot saved by `save_banks`, checked against the cartridge
+    fn read_banks(&self, input: &mut Reader) -> Result<Vec<usize>, StateError>
+    {
+        let slots = usize::from(input.u8()?);
+        if slots != self.slots.len()
+        {
+            return Err(StateError::Mismatch(format!("{} mapper slots saved, the mapper has {}", slots, self.slots.len())));
+        }
+
+        (0..slots).map(|_|
+        {
+            let bank = usize::from(input.u16()?);
+            if bank >= self.bank_count()
+            {
+                return Err(StateError::Mismatch(format!("bank {} saved, the cartridge has {}", bank, self.bank_count())));
+            }
+
+            Ok(bank)
+        }).collect()
+    }
+
+    /// Offset in the image of the byte visible at `address`, None outside the slots
+    fn image_offset(&self, address: u16) -> Option<usize>
+    {
+        self.slots.iter()
+            .find(|s| s.start <= address && usize::from(address) < usize::from(s.start) + self.bank_size)
+            .map(|s| s.bank * self.bank_size + usize::from(address - s.start))
+    }
+}
+
+impl<B: Bus> Bus for Mapper<B>
+{
+    fn read(&mut self, address: u16) -> u8
+    {
+        match self.image_offset(address)
+        {
+            Some(offset) => self.image[offset],
+            None => self.inner.read(address),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8)
+    {
+        let register = self.registers.iter()
+            .find(|(range, _)| range.contains(&address))
+            .map(|&(_, slot)| slot);
+
+        if let Some(slot) = register
+        {
+            self.select(slot, usize::from(value));
+        }
+        else if self.image_offset(address).is_none()
+        {
+            self.inner.write(address, value);
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8
+    {
+        match self.image_offset(address)
+        {
+            Some(offset) => self.image[offset],
+            None => self.inner.peek(address),
+        }
+    }
+
+    /// Banks are not writable and registers are left alone, only `inner` can be poked
+    fn poke(&mut self, address: u16, value: u8)
+    {
+        if self.image_offset(address).is_none()
+        {
+            self.inner.poke(address, value);
+        }
+    }
+
+    /// Bank switching changes what the slots show, only `inner` can be cached
+    fn cacheable(&self, address: u16) -> bool
+    {
+        self.image_offset(address).is_none() && self.inner.cacheable(address)
+    }
+
+    fn is_device_register(&self, address: u16) -> bool
+    {
+        self.registers.iter().any(|(range, _)| range.contains(&address)) || self.inner.is_device_register(address)
+    }
+
+    fn save_devices(&self, out: &mut Vec<u8>)
+    {
+        self.inner.save_devices(out);
+        self.save_banks(out);
+    }
+
+    fn load_devices(&mut self, input: &mut Reader) -> Result<(), StateError>
+    {
+        self.inner.load_devices(input)?;
+        let banks = self.read_banks(input)?;
+        self.set_banks(banks);
+
+        Ok(())
+    }
+
+    /// The image is part of the cartridge, only the selected banks are saved after the inner bus
+    fn save_state(&self, out: &mut Vec<u8>)
+    {
+        self.inner.save_state(out);
+        self.save_banks(out);
+    }
+
+    fn check_state(&self, input: &mut Reader) -> Result<(), StateError>
+    {
+        self.inner.check_state(input)?;
+        self.read_banks(input)?;
+
+        Ok(())
+    }
+
+    /// The inner bus is only restored once the banks have been checked too
+    fn load_state(&mut self, input: &mut Reader) -> Result<(), StateError>
+    {
+        self.check_state(&mut input.clone())?;
+
+        self.inner.load_state(input)?;
+        let banks = self.read_banks(input)?;
+        self.set_banks(banks);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bus::Ram;
+
+    /// Image of `banks` banks of `bank_size` bytes, every byte of a bank holds its number
+    fn image(banks: usize, bank_size: usize) -> Vec<u8>
+    {
+        (0..banks).flat_map(|bank| vec![bank as u8; bank_size]).collect()
+    }
+
+    #[test]
+    fn latch_selects_the_bank_written_in_the_window()
+    {
+        let mut mapper = MapperKind::Latch.build(Ram::default(), image(4, 0x2000));
+
+        assert_eq!(mapper.read(0x8000), 0);
+        mapper.write(0x9abc, 2);
+        assert_eq!((mapper.read(0x8000), mapper.read(0x9fff)), (2, 2));
+
+        // Outside the 8 KiB window is the inner bus
+        mapper.write(0xa000, 0x55);
+        assert_eq!(mapper.read(0xa000), 0x55);
+        assert_eq!(mapper.slots()[0].bank, 2);
+    }
+
+    #[test]
+    fn fixed_keeps_the_last_bank_at_c000()
+    {
+        let mut mapper = MapperKind::Fixed.build(Ram::default(), image(4, 0x4000));
+
+        assert_eq!((mapper.read(0x8000), mapper.read(0xc000)), (0, 3));
+        mapper.write(0xffff, 1);
+        assert_eq!((mapper.read(0xbfff), mapper.read(0xffff)), (1, 3));
+    }
+
+    #[test]
+    fn window_is_selected_through_the_bank_port()
+    {
+        let mut mapper = MapperKind::Window.build(Ram::default(), image(2, 0x4000));
+
+        // Other writes to the window are ignored
+        mapper.write(0x8000, 1);
+        assert_eq!(mapper.read(0x8000), 0);
+
+        mapper.write(BANK_PORT, 1);
+        assert_eq!(mapper.read(0x8000), 1);
+
+        // Zero page and the memory after the window belong to the inner bus
+        mapper.write(0x00ff, 0x12);
+        mapper.write(0xc000, 0x34);
+        assert_eq!((mapper.read(0x00ff), mapper.read(0xc000)), (0x12, 0x34));
+        assert_eq!(mapper.read(0x8000), 1);
+    }
+
+    #[test]
+    fn bank_numbers_wrap_around_the_bank_count()
+    {
+        let mut mapper = MapperKind::Latch.build(Ram::default(), image(3, 0x2000));
+
+        mapper.write(0x8000, 4);
+        assert_eq!(mapper.read(0x8000), 1);
+    }
+
+    #[test]
+    fn banks_are_read_only_and_not_cacheable()
+    {
+        let mut mapper = MapperKind::Window.build(Ram::default(), image(1, 0x4000));
+
+        mapper.poke(0x8000, 0x99);
+        assert_eq!(mapper.peek(0x8000), 0);
+        assert!(!mapper.cacheable(0x8000));
+        assert!(mapper.cacheable(0x0600));
+    }
+
+    #[test]
+    fn cpu_switches_banks()
+    {
+        // LDA #1, STA $8000, LDA $8010 on the latch mapper
+        let mut ram = Ram::default();
+        ram.mem[0x0600..0x0607].copy_from_slice(&[0xa9, 0x01, 0x8d, 0x00, 0x80, 0xad, 0x10]);
+        ram.mem[0x0607] = 0x80;
+
+        let mut cpu = crate::cpu::Cpu::new(MapperKind::Latch.build(ram, image(2, 0x2000)));
+        cpu.pc = 0x0600;
+        for _ in 0..3
+        {
+            cpu.step().unwrap();
+        }
+
+        assert_eq!(cpu.a, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a whole number")]
+    fn image_must_hold_whole_banks()
+    {
+        MapperKind::Fixed.build(Ram::default(), vec![0; 0x5000]);
+    }
+}
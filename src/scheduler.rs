@@ -0,0 +1,135 @@
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use std::time::{Duration, Instant};
+
+/// Frames drawn per second
+pub const FRAME_RATE: u64 = 60;
+
+/// Cycles run between two looks at the clock at Speed::Max
+const MAX_SPEED_SLICE: u64 = 10_000;
+
+/// How fast the emulated cpu runs
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed
+{
+    /// Cycles per second of the emulated clock, e.g 1_000_000 for a 1 MHz 6502
+    Clock(u64),
+    /// As many cycles as the host can run, the screen is still drawn once per frame
+    Max,
+}
+
+impl Default for Speed
+{
+    fn default() -> Speed
+    {
+        Speed::Clock(1_000_000)
+    }
+}
+
+/// Splits emulation into frames, each runs the cycles of one frame of the emulated
+/// clock and then waits for the next frame, so rendering happens once per frame
+pub struct Scheduler
+{
+    pub speed: Speed,
+    frame: Duration,
+    next_frame: Instant,
+    /// Cycles of the clock not yet handed out, a clock that is not a multiple of
+    /// the frame rate gets one more cycle in some frames
+    remainder: u64,
+    /// Cycles the last instruction of a frame ran past the budget, taken from the next frame
+    overshoot: u64,
+    /// Start and cycle count of the current one second measuring window
+    window_start: Instant,
+    window_cycles: u64,
+}
+
+impl Scheduler
+{
+    pub fn new(speed: Speed) -> Scheduler
+    {
+        let now = Instant::now();
+
+        Scheduler
+        {
+            speed,
+            frame: Duration::from_nanos(1_000_000_000 / FRAME_RATE),
+            next_frame: now,
+            remainder: 0,
+            overshoot: 0,
+            window_start: now,
+            window_cycles: 0,
+        }
+    }
+
+    /// Run the cpu for one frame, `run` executes at least the given number of cycles, like
+    /// Cpu::run, and returns why execution should stop if it stopped early
+    ///
+    /// Returns the reason execution stopped, if it stopped before the end of the frame
+    pub fn run_frame<B: Bus>(&mut self, cpu: &mut Cpu<B>, mut run: impl FnMut(&mut Cpu<B>, u64) -> Option<String>) -> Option<String>
+    {
+        let start = cpu.cycles;
+        let mut status = None;
+
+        match self.speed
+        {
+            Speed::Clock(hz) =>
+            {
+                self.remainder += hz;
+                let budget = (self.remainder / FRAME_RATE).saturating_sub(self.overshoot);
+                self.remainder %= FRAME_RATE;
+
+                if budget > 0
+                {
+                    status = run(cpu, budget);
+                }
+
+                self.overshoot = if status.is_none() { cpu.cycles.saturating_sub(start + budget) } else { 0 };
+            },
+            Speed::Max =>
+            {
+                // Checking the time after every instruction would cost more than the instruction
+                let deadline = Instant::now() + self.frame;
+                while status.is_none() && Instant::now() < deadline
+                {
+                    status = run(cpu, MAX_SPEED_SLICE);
+                }
+            },
+        }
+
+        // Loading a state or stepping back can move the cycle counter backwards
+        self.window_cycles += cpu.cycles.saturating_sub(start);
+        status
+    }
+
+    /// Sleep until the next frame is due, a host that fell behind skips the missed frames
+    pub fn wait_for_next_frame(&mut self)
+    {
+        let now = Instant::now();
+
+        self.next_frame += self.frame;
+        if self.next_frame > now
+        {
+            std::thread::sleep(self.next_frame - now);
+        }
+        else if now - self.next_frame > self.frame * 4
+        {
+            self.next_frame = now;
+        }
+    }
+
+    /// Emulated cycles per second, measured about once a second
+    pub fn measured_hz(&mut self) -> Option<u64>
+    {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < Duration::from_secs(1)
+        {
+            return None;
+        }
+
+        let hz = (self.window_cycles as f64 / elapsed.as_secs_f64()) as u64;
+        self.window_start = Instant::now();
+        self.window_cycles = 0;
+
+        Some(hz)
+    }
+}
@@ -0,0 +1,358 @@
+use crate::cpu::Cpu;
+use crate::flags::StatusFlags;
+use crate::isa::Instruction;
+
+/// Where the instruction reads its operand
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand
+{
+    /// The register only, e.g ASL A
+    None,
+    Imm,
+    /// Zero page address $10
+    Zp,
+}
+
+/// Register compared or modified by the instruction
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Register
+{
+    A,
+    X,
+    Y,
+}
+
+/// Where the result ends up
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output
+{
+    Register,
+    Memory,
+    /// Only the flags change, e.g CMP and BIT
+    Flags,
+}
+
+/// Expected result and status for a register value, an operand and the incoming status
+type Reference = fn(u8, u8, StatusFlags) -> (u8, StatusFlags);
+
+struct Case
+{
+    name: &'static str,
+    opcode: u8,
+    operand: Operand,
+    register: Register,
+    output: Output,
+    reference: Reference,
+}
+
+const ZP_ADDRESS: u8 = 0x10;
+
+/// Flags set by a result, the other flags of `sr` are kept
+fn nz(sr: StatusFlags, value: u8) -> StatusFlags
+{
+    let mut sr = sr;
+    sr.set(StatusFlags::NEGATIVE, value >= 0x80);
+    sr.set(StatusFlags::ZERO, value == 0);
+    sr
+}
+
+// The references use plain signed and unsigned arithmetic on wider integers
+// instead of the bit tricks of the cpu
+
+fn reference_adc(a: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let carry = i16::from(sr.contains(StatusFlags::CARRY));
+    let unsigned = i16::from(a) + i16::from(m) + carry;
+    let signed = i16::from(a as i8) + i16::from(m as i8) + carry;
+
+    let mut sr = nz(sr, unsigned as u8);
+    sr.set(StatusFlags::CARRY, unsigned > 255);
+    sr.set(StatusFlags::OVERFLOW, !(-128..=127).contains(&signed));
+    (unsigned as u8, sr)
+}
+
+fn reference_sbc(a: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let borrow = 1 - i16::from(sr.contains(StatusFlags::CARRY));
+    let unsigned = i16::from(a) - i16::from(m) - borrow;
+    let signed = i16::from(a as i8) - i16::from(m as i8) - borrow;
+
+    let mut sr = nz(sr, unsigned as u8);
+    sr.set(StatusFlags::CARRY, unsigned >= 0);
+    sr.set(StatusFlags::OVERFLOW, !(-128..=127).contains(&signed));
+    (unsigned as u8, sr)
+}
+
+fn reference_cmp(r: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let difference = i16::from(r) - i16::from(m);
+
+    let mut sr = nz(sr, difference as u8);
+    sr.set(StatusFlags::CARRY, difference >= 0);
+    (r, sr)
+}
+
+fn reference_bit(a: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let mut sr = sr;
+    sr.set(StatusFlags::NEGATIVE, m & 0b10000000 != 0);
+    sr.set(StatusFlags::OVERFLOW, m & 0b01000000 != 0);
+    sr.set(StatusFlags::ZERO, a & m == 0);
+    (a, sr)
+}
+
+fn reference_and(a: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    (a & m, nz(sr, a & m))
+}
+
+fn reference_ora(a: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    (a | m, nz(sr, a | m))
+}
+
+fn reference_eor(a: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    (a ^ m, nz(sr, a ^ m))
+}
+
+fn reference_asl(_: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let shifted = u16::from(m) * 2;
+
+    let mut sr = nz(sr, shifted as u8);
+    sr.set(StatusFlags::CARRY, shifted > 255);
+    (shifted as u8, sr)
+}
+
+fn reference_lsr(_: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let mut sr = nz(sr, m / 2);
+    sr.set(StatusFlags::CARRY, m % 2 == 1);
+    (m / 2, sr)
+}
+
+fn reference_rol(_: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let rotated = u16::from(m) * 2 + u16::from(sr.contains(StatusFlags::CARRY));
+
+    let mut sr = nz(sr, rotated as u8);
+    sr.set(StatusFlags::CARRY, rotated > 255);
+    (rotated as u8, sr)
+}
+
+fn reference_ror(_: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let rotated = m / 2 + if sr.contains(StatusFlags::CARRY) { 128 } else { 0 };
+
+    let mut sr = nz(sr, rotated);
+    sr.set(StatusFlags::CARRY, m % 2 == 1);
+    (rotated, sr)
+}
+
+fn reference_inc(_: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let value = ((u16::from(m) + 1) % 256) as u8;
+    (value, nz(sr, value))
+}
+
+fn reference_dec(_: u8, m: u8, sr: StatusFlags) -> (u8, StatusFlags)
+{
+    let value = ((u16::from(m) + 255) % 256) as u8;
+    (value, nz(sr, value))
+}
+
+fn cases() -> Vec<Case>
+{
+    use Operand::{Imm, Zp};
+    use Output::{Flags, Memory};
+    use Register::{A, X, Y};
+
+    let case = |name, opcode, operand, register, output, reference| Case { name, opcode, operand, register, output, reference };
+
+    vec![
+        case("ADC #imm", Instruction::ADC_IMM, Imm, A, Output::Register, reference_adc as Reference),
+        case("SBC #imm", Instruction::SBC_IMM, Imm, A, Output::Register, reference_sbc),
+        case("AND #imm", Instruction::AND_IMM, Imm, A, Output::Register, reference_and),
+        case("ORA #imm", Instruction::ORA_IMM, Imm, A, Output::Register, reference_ora),
+        case("EOR #imm", Instruction::EOR_IMM, Imm, A, Output::Register, reference_eor),
+        case("CMP #imm", Instruction::CMP_IMM, Imm, A, Flags, reference_cmp),
+        case("CPX #imm", Instruction::CPX_IMM, Imm, X, Flags, reference_cmp),
+        case("CPY #imm", Instruction::CPY_IMM, Imm, Y, Flags, reference_cmp),
+        case("BIT zp", Instruction::BIT_ZP, Zp, A, Flags, reference_bit),
+        case("ASL A", Instruction::ASL_ACC, Operand::None, A, Output::Register, reference_asl),
+        case("LSR A", Instruction::LSR_ACC, Operand::None, A, Output::Register, reference_lsr),
+        case("ROL A", Instruction::ROL_ACC, Operand::None, A, Output::Register, reference_rol),
+        case("ROR A", Instruction::ROR_ACC, Operand::None, A, Output::Register, reference_ror),
+        case("ASL zp", Instruction::ASL_ZP, Zp, A, Memory, reference_asl),
+        case("LSR zp", Instruction::LSR_ZP, Zp, A, Memory, reference_lsr),
+        case("ROL zp", Instruction::ROL_ZP, Zp, A, Memory, reference_rol),
+        case("ROR zp", Instruction::ROR_ZP, Zp, A, Memory, reference_ror),
+        case("INC zp", Instruction::INC_ZP, Zp, A, Memory, reference_inc),
+        case("DEC zp", Instruction::DEC_ZP, Zp, A, Memory, reference_dec),
+    ]
+}
+
+/// Run the instruction of `case` on every register value, operand and carry with the
+/// other flags both set and clear, returns a description of the first mismatch
+fn check(case: &Case) -> Result<u32, String>
+{
+    let mut cpu = Cpu::default();
+    let mut checked = 0;
+
+    // Registers only instructions use the register as the operand
+    let operands = if case.operand == Operand::None { 0..=0 } else { 0..=255 };
+    let others = StatusFlags::NEGATIVE | StatusFlags::OVERFLOW | StatusFlags::ZERO | StatusFlags::INTERRUPT;
+
+    for register in 0..=255u8
+    {
+        for m in operands.clone()
+        {
+            for &carry in &[false, true]
+            {
+                for &set_others in &[false, true]
+                {
+                    let mut sr = StatusFlags::UNUSED;
+                    sr.set(StatusFlags::CARRY, carry);
+                    sr.set(others, set_others);
+
+                    cpu.pc = 0x600;
+                    cpu.a = 0;
+                    cpu.x = 0;
+                    cpu.y = 0;
+                    cpu.sr = sr;
+                    cpu.bus.mem[0x600] = case.opcode;
+                    match case.register
+                    {
+                        Register::A => cpu.a = register,
+                        Register::X => cpu.x = register,
+                        Register::Y => cpu.y = register,
+                    }
+                    match case.operand
+                    {
+                        Operand::None => {},
+                        Operand::Imm => cpu.bus.mem[0x601] = m,
+                        Operand::Zp =>
+                        {
+                            cpu.bus.mem[0x601] = ZP_ADDRESS;
+                            cpu.bus.mem[usize::from(ZP_ADDRESS)] = m;
+                        },
+                    }
+
+                    let operand = if case.operand == Operand::None { register } else { m };
+                    let (expected, expected_sr) = (case.reference)(register, operand, sr);
+
+                    if let Err(e) = cpu.step()
+                    {
+                        return Err(format!("{} : {}", case.name, e));
+                    }
+
+                    let value = match (case.output, case.register)
+                    {
+                        (Output::Memory, _) => cpu.bus.mem[usize::from(ZP_ADDRESS)],
+                        (_, Register::A) => cpu.a,
+                        (_, Register::X) => cpu.x,
+                        (_, Register::Y) => cpu.y,
+                    };
+
+                    if value != expected || cpu.sr != expected_sr
+                    {
+                        return Err(format!(
+                            "{} : register ${:02x} operand ${:02x} status {:?}, expected ${:02x} {:?} got ${:02x} {:?}",
+                            case.name, register, operand, sr, expected, expected_sr, value, cpu.sr));
+                    }
+
+                    checked += 1;
+                }
+            }
+        }
+    }
+
+    Ok(checked)
+}
+
+/// Check the ALU instructions against the reference implementations for every input,
+/// prints one line per instruction
+pub fn run() -> Result<(), String>
+{
+    let mut failed = 0;
+
+    for case in cases()
+    {
+        match check(&case)
+        {
+            Ok(checked) => println!("{:<10} ok ({} inputs)", case.name, checked),
+            Err(e) =>
+            {
+                println!("{:<10} FAILED {}", case.name, e);
+                failed += 1;
+            },
+        }
+    }
+
+    if failed > 0
+    {
+        return Err(format!("{} instructions do not match the reference", failed));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bus::{Bus, Ram};
+    use crate::cpu::StepResult;
+    use crate::lexer::Lexer;
+
+    /// Assemble asm-examples/`name`, run it until BRK and return the result it leaves in $00
+    fn run_example(name: &str) -> u8
+    {
+        let path = format!("{}/asm-examples/{}", env!("CARGO_MANIFEST_DIR"), name);
+        let source = std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("Unable to read {} : {}", path, e));
+
+        let mut lexer = Lexer { file: name.to_string(), ..Default::default() };
+        lexer.tokenize(source);
+        let code = lexer.parse().unwrap_or_else(|diagnostics| panic!("{} does not assemble, {}", name, diagnostics[0]));
+
+        let mut ram = Ram::default();
+        let start = usize::from(lexer.origin);
+        ram.mem[start..start + code.len()].copy_from_slice(&code);
+
+        let mut cpu = Cpu::new(ram);
+        cpu.variant = lexer.cpu;
+        cpu.pc = lexer.origin;
+        cpu.sp = 0xff;
+
+        match cpu.run(u64::MAX)
+        {
+            Ok(StepResult::Break { .. }) => cpu.bus.peek(0x00),
+            result => panic!("{} stopped at {:#06x} without BRK : {:?}", name, cpu.pc, result),
+        }
+    }
+
+    #[test]
+    fn decimal_mode_matches_bcd_test()
+    {
+        assert_eq!(run_example("bcd.asm"), 0, "bcd.asm failed");
+    }
+
+    #[test]
+    fn decimal_mode_matches_bcd_test_65c02()
+    {
+        assert_eq!(run_example("bcd65c02.asm"), 0, "bcd65c02.asm failed");
+    }
+
+    #[test]
+    fn alu_matches_reference()
+    {
+        for case in cases()
+        {
+            if let Err(e) = check(&case)
+            {
+                panic!("{}", e);
+            }
+        }
+    }
+}
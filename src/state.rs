@@ -0,0 +1,232 @@
+use std::fmt;
+
+/// First bytes of a save state file
+pub const MAGIC: &[u8; 6] = b"E6502S";
+
+/// Version of the save state layout, bump it whenever the layout changes
+///
+/// Layout of version 1, numbers are little endian
+///   magic, version (u8)
+///   cpu : a, x, y, sp (u8), pc (u16), sr (u8), cycles (u64), state, variant (u8),
+///         undocumented, irq line, nmi line, nmi pending (u8, 0 or 1)
+///   bus : written by the `Bus::save_state` of the machine, 64 KiB of memory
+///         followed by the state of the devices
+pub const VERSION: u8 = 1;
+
+/// Why a save state could not be loaded
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum StateError
+{
+    /// The file does not start with MAGIC
+    BadMagic,
+    /// The file was written by another version of the emulator
+    UnsupportedVersion(u8),
+    /// The file ended before the whole state was read
+    Truncated,
+    /// The state does not fit the machine it is loaded into
+    Mismatch(String),
+}
+
+impl fmt::Display for StateError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match self
+        {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported save state version {}, expected {}", version, VERSION),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Mismatch(reason) => write!(f, "Save state does not match the machine : {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Reads the fields of a save state in order
+///
+/// Cloning it gives a second reader at the same position, to check a state before loading it
+#[derive(Clone)]
+pub struct Reader<'a>
+{
+    data: &'a [u8],
+}
+
+#[allow(dead_code)]
+impl<'a> Reader<'a>
+{
+    pub fn new(data: &'a [u8]) -> Reader<'a>
+    {
+        Reader { data }
+    }
+
+    /// Next `len` bytes
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError>
+    {
+        if self.data.len() < len
+        {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError>
+    {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError>
+    {
+        match self.u8()?
+        {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(StateError::Mismatch(format!("{} is not a boolean", value))),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError>
+    {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError>
+    {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// True once every byte has been read
+    pub fn is_empty(&self) -> bool
+    {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::bus::{Bus, MemoryMap, Ram};
+    use crate::cpu::{Cpu, State};
+    use crate::isa::Variant;
+    use crate::mapper::{Mapper, MapperKind};
+
+    /// Cpu with registers and memory that differ from a new one
+    fn busy_cpu() -> Cpu
+    {
+        let mut cpu = Cpu::default();
+        cpu.a = 0x12;
+        cpu.x = 0x34;
+        cpu.y = 0x56;
+        cpu.sp = 0x78;
+        cpu.pc = 0x9abc;
+        cpu.cycles = 0x1_0000_0001;
+        cpu.variant = Variant::Wdc;
+        cpu.state = State::Waiting;
+        cpu.nmi(true);
+        cpu.bus.mem[0x0000] = 0xaa;
+        cpu.bus.mem[0xffff] = 0xbb;
+        cpu
+    }
+
+    #[test]
+    fn cpu_round_trip()
+    {
+        let saved = busy_cpu().save_state();
+        assert_eq!(&saved[..MAGIC.len()], MAGIC);
+        assert_eq!(saved[MAGIC.len()], VERSION);
+
+        let mut cpu = Cpu::default();
+        cpu.load_state(&saved).unwrap();
+
+        assert_eq!((cpu.a, cpu.x, cpu.y, cpu.sp, cpu.pc), (0x12, 0x34, 0x56, 0x78, 0x9abc));
+        assert_eq!(cpu.cycles, 0x1_0000_0001);
+        assert_eq!((cpu.variant, cpu.state), (Variant::Wdc, State::Waiting));
+        assert_eq!((cpu.bus.mem[0x0000], cpu.bus.mem[0xffff]), (0xaa, 0xbb));
+        assert_eq!(cpu.save_state(), saved);
+    }
+
+    #[test]
+    fn mapper_round_trip()
+    {
+        let build = || MapperKind::Fixed.build(MemoryMap::machine(0x0600), vec![0; 4 * 0x4000]);
+
+        let mut cpu = Cpu::new(build());
+        cpu.bus.write(0x8000, 2);
+        cpu.bus.write(0x0010, 0x42);
+        let saved = cpu.save_state();
+
+        let mut other = Cpu::new(build());
+        other.load_state(&saved).unwrap();
+        assert_eq!(other.bus.slots()[0].bank, 2);
+        assert_eq!(other.bus.peek(0x0010), 0x42);
+    }
+
+    #[test]
+    fn foreign_files_are_rejected()
+    {
+        let mut cpu = Cpu::default();
+        let mut saved = busy_cpu().save_state();
+
+        assert_eq!(cpu.load_state(b"PNG"), Err(StateError::BadMagic));
+
+        saved[MAGIC.len()] = VERSION + 1;
+        assert_eq!(cpu.load_state(&saved), Err(StateError::UnsupportedVersion(VERSION + 1)));
+    }
+
+    #[test]
+    fn truncated_and_oversized_states_are_rejected()
+    {
+        let mut cpu = Cpu::default();
+        let saved = busy_cpu().save_state();
+
+        assert_eq!(cpu.load_state(&saved[..saved.len() - 1]), Err(StateError::Truncated));
+
+        let mut longer = saved.clone();
+        longer.push(0);
+        assert!(matches!(cpu.load_state(&longer), Err(StateError::Mismatch(_))));
+    }
+
+    #[test]
+    fn failed_loads_change_nothing()
+    {
+        let mut saved = busy_cpu().save_state();
+        saved.push(0);
+
+        let mut cpu = Cpu::default();
+        assert!(cpu.load_state(&saved).is_err());
+        assert_eq!((cpu.a, cpu.pc, cpu.bus.mem[0x0000]), (0, 0, 0));
+
+        // The bank of the saved mapper does not exist on a smaller cartridge
+        let mut big = Mapper::latch(Ram::default(), vec![0; 4 * 0x2000], 0x2000, 0x8000);
+        big.write(0x8000, 3);
+        big.poke(0x0010, 0x42);
+        let mut banks = Vec::new();
+        big.save_state(&mut banks);
+
+        let mut small = Mapper::latch(Ram::default(), vec![0; 2 * 0x2000], 0x2000, 0x8000);
+        let result = small.load_state(&mut Reader::new(&banks));
+        assert_eq!(result, Err(StateError::Mismatch("bank 3 saved, the cartridge has 2".to_string())));
+        assert_eq!(small.peek(0x0010), 0);
+    }
+
+    #[test]
+    fn reader_reads_little_endian_fields()
+    {
+        let mut input = Reader::new(&[0x01, 0x34, 0x12, 0x02]);
+
+        assert_eq!(input.bool(), Ok(true));
+        assert_eq!(input.u16(), Ok(0x1234));
+        assert!(matches!(input.bool(), Err(StateError::Mismatch(_))));
+        assert!(input.is_empty());
+        assert_eq!(input.u8(), Err(StateError::Truncated));
+    }
+}